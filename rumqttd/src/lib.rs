@@ -27,9 +27,9 @@ pub type TopicId = usize;
 pub type Offset = (u64, u64);
 pub type Cursor = (u64, u64);
 
-pub use link::local::{Link, LinkError, LinkRx, LinkTx};
+pub use link::local::{Link, LinkError, LinkRx, LinkSettings, LinkTx};
 pub use router::Notification;
-pub use server::Broker;
+pub use server::{AuthError, AuthHandler, Broker, BrokerHandle, Credentials};
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -40,7 +40,11 @@ pub struct Config {
     pub ws: HashMap<String, ServerSettings>,
     pub cluster: Option<ClusterSettings>,
     pub console: ConsoleSettings,
-    pub bridge: Option<BridgeConfig>,
+    #[serde(default)]
+    pub bridge: HashMap<String, BridgeConfig>,
+    /// File with static `username:password` credentials to authenticate
+    /// connections with. Ignored when an `AuthHandler` is set on `Broker`
+    pub credentials: Option<PathBuf>,
 }
 
 // TODO: Change names without _ until config-rs issue is resolved
@@ -77,6 +81,30 @@ pub struct ConnectionSettings {
     pub max_inflight_size: usize,
     #[serde(default)]
     pub dynamic_filters: bool,
+    /// Maximum topic alias accepted from MQTT 5 clients. Topic aliases are
+    /// disabled when 0
+    #[serde(default)]
+    pub topic_alias_max: u16,
+    /// Keep alive in seconds which MQTT 5 clients are asked to use instead of
+    /// their own. Clients of v4 listeners can't be told about it and keep
+    /// their own keep alive
+    #[serde(default)]
+    pub server_keep_alive: Option<u16>,
+    /// Publishes each client of this listener may send. Publishes over the
+    /// limit are rejected with `QuotaExceeded` in v5 and delay further reads
+    /// in v4
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// Publish rate limit. Unlimited when neither is set
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub messages_per_sec: Option<u32>,
+    /// Topic and payload bytes per second
+    #[serde(default)]
+    pub bytes_per_sec: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -97,24 +125,118 @@ pub struct RouterConfig {
     pub max_read_len: u64,
     pub max_connections: usize,
     pub initialized_filters: Option<Vec<Filter>>,
+    /// Directory to move segments which don't fit in memory. Each filter
+    /// gets its own sub directory
+    #[serde(default)]
+    pub log_dir: Option<PathBuf>,
+    /// Maximum number of segments per filter on disk. Disk persistence is
+    /// disabled when this is 0
+    #[serde(default)]
+    pub max_disk_segments: usize,
+    /// Directory to save state of persistent sessions in, so that they
+    /// survive restarts
+    #[serde(default)]
+    pub session_dir: Option<PathBuf>,
+    /// Seconds between saves of connected persistent sessions to `session_dir`,
+    /// so that they survive a crash of the broker as well. Sessions are only
+    /// saved on disconnection when this is 0
+    #[serde(default = "default_session_sync_secs")]
+    pub session_sync_secs: u64,
+    /// Topic level access control. Everything but `$SYS` subscriptions is
+    /// allowed when this isn't set
+    #[serde(default)]
+    pub acl: Option<Vec<AclRule>>,
+    /// How publishes of a shared subscription are spread across its group
+    #[serde(default)]
+    pub shared_strategy: SharedStrategy,
+    /// Seconds between publishes of broker statistics under `$SYS/broker`.
+    /// Disabled when this is 0
+    #[serde(default)]
+    pub sys_interval_secs: u64,
+    /// Publishes all the clients of a tenant may send together
+    #[serde(default)]
+    pub tenant_rate_limit: Option<RateLimit>,
+}
+
+fn default_session_sync_secs() -> u64 {
+    5
+}
+
+/// Picks the member of a shared subscription group which gets a publish
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedStrategy {
+    /// Members take turns
+    #[default]
+    RoundRobin,
+    /// Member with the fewest buffered and inflight publishes
+    LeastLoaded,
+}
+
+/// Allows or denies publishes/subscriptions on a filter. `%c` and `%u` in the
+/// filter are substituted with client id and username of the connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclRule {
+    /// Rule applies only to this client when set
+    pub client_id: Option<String>,
+    /// Rule applies only to this username when set
+    pub username: Option<String>,
+    pub filter: Filter,
+    pub action: AclAction,
+    pub permission: AclPermission,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    Publish,
+    Subscribe,
+    All,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclPermission {
+    Allow,
+    Deny,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ConsoleSettings {
     pub listen: String,
+    /// Bearer token required by admin endpoints. They are disabled without it
+    #[serde(default, skip_serializing)]
+    pub admin_token: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BridgeConfig {
+    /// Client id of the bridge on upstream and local broker
+    pub name: String,
     pub url: String,
     pub port: u16,
     pub qos: u8,
     pub sub_path: String,
+    /// Local filters which are forwarded to upstream with QoS 1
+    #[serde(default)]
+    pub pub_paths: Vec<String>,
     pub reconnection_delay: u64,
     pub ping_delay: u64,
     pub timeout_delay: u64,
     #[serde(default)]
     pub transport: Transport,
+    /// Topic prefixes rewritten between upstream and local broker
+    #[serde(default)]
+    pub topics: Vec<TopicMapping>,
+}
+
+/// Publishes from upstream with topics starting with `remote` are republished
+/// locally with `local` in place of the prefix. Publishes forwarded to upstream
+/// are rewritten the other way around
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TopicMapping {
+    pub remote: String,
+    pub local: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
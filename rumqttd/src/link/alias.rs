@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::mem;
+
+use bytes::Bytes;
+
+use crate::protocol::{Publish, PublishProperties};
+use crate::Notification;
+
+/// Topic aliases of an MQTT 5 connection. Aliases of incoming publishes are
+/// chosen by the client, up to the maximum the broker advertises in ConnAck.
+/// Aliases of outgoing publishes are chosen by the broker, up to the maximum
+/// the client sets in Connect. Both are scoped to the network connection
+#[derive(Debug)]
+pub struct TopicAliases {
+    incoming_max: u16,
+    incoming: HashMap<u16, Bytes>,
+    outgoing_max: u16,
+    outgoing: HashMap<Bytes, u16>,
+}
+
+impl TopicAliases {
+    pub fn new(incoming_max: u16, outgoing_max: u16) -> TopicAliases {
+        TopicAliases {
+            incoming_max,
+            incoming: HashMap::new(),
+            outgoing_max,
+            outgoing: HashMap::new(),
+        }
+    }
+
+    /// Resolves alias of an incoming publish. A publish with a topic maps the
+    /// alias to it and a publish with an empty topic uses the mapped topic.
+    /// Alias is removed from properties as it only has meaning on this connection
+    pub fn incoming(
+        &mut self,
+        publish: &mut Publish,
+        properties: &mut Option<PublishProperties>,
+    ) -> Result<(), u16> {
+        let alias = match properties.as_mut().and_then(|p| p.topic_alias.take()) {
+            Some(alias) => alias,
+            None => return Ok(()),
+        };
+
+        if alias == 0 || alias > self.incoming_max {
+            return Err(alias);
+        }
+
+        if publish.topic.is_empty() {
+            publish.topic = self.incoming.get(&alias).ok_or(alias)?.clone();
+        } else {
+            self.incoming.insert(alias, publish.topic.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Assigns aliases to outgoing publishes. First publish on a topic carries
+    /// both the topic and a new alias, later ones only carry the alias. Topics
+    /// seen after all the aliases are used up are sent as is
+    pub fn outgoing(&mut self, notification: &mut Notification) {
+        if self.outgoing_max == 0 {
+            return;
+        }
+
+        let (mut forward, mut properties) =
+            match mem::replace(notification, Notification::Unschedule) {
+                Notification::Forward(forward) => (forward, PublishProperties::default()),
+                Notification::ForwardWithProperties(forward, properties) => (forward, properties),
+                v => {
+                    *notification = v;
+                    return;
+                }
+            };
+
+        let topic = &mut forward.publish.topic;
+        match self.outgoing.get(topic) {
+            Some(alias) => {
+                properties.topic_alias = Some(*alias);
+                topic.clear();
+            }
+            None if self.outgoing.len() < self.outgoing_max as usize => {
+                let alias = self.outgoing.len() as u16 + 1;
+                self.outgoing.insert(topic.clone(), alias);
+                properties.topic_alias = Some(alias);
+            }
+            None => (),
+        }
+
+        *notification = match properties == PublishProperties::default() {
+            true => Notification::Forward(forward),
+            false => Notification::ForwardWithProperties(forward, properties),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicAliases;
+    use crate::protocol::{Publish, PublishProperties, QoS};
+    use crate::router::Forward;
+    use crate::Notification;
+
+    fn publish(topic: &'static str) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: topic.into(),
+            pkid: 0,
+            payload: vec![1, 2, 3].into(),
+        }
+    }
+
+    fn alias(alias: u16) -> Option<PublishProperties> {
+        Some(PublishProperties {
+            topic_alias: Some(alias),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn incoming_aliases_are_resolved() {
+        let mut aliases = TopicAliases::new(2, 0);
+
+        let mut p = publish("hello/world");
+        let mut properties = alias(1);
+        assert_eq!(aliases.incoming(&mut p, &mut properties), Ok(()));
+        assert_eq!(properties.unwrap().topic_alias, None);
+
+        let mut p = publish("");
+        assert_eq!(aliases.incoming(&mut p, &mut alias(1)), Ok(()));
+        assert_eq!(p.topic, "hello/world");
+
+        assert_eq!(aliases.incoming(&mut publish(""), &mut alias(2)), Err(2));
+        assert_eq!(aliases.incoming(&mut publish("a"), &mut alias(3)), Err(3));
+        assert_eq!(aliases.incoming(&mut publish("a"), &mut alias(0)), Err(0));
+        assert_eq!(aliases.incoming(&mut publish("a"), &mut None), Ok(()));
+    }
+
+    #[test]
+    fn outgoing_aliases_are_assigned_until_maximum() {
+        let mut aliases = TopicAliases::new(0, 1);
+        let mut forward = |topic| {
+            let forward = Forward {
+                cursor: (0, 0),
+                size: 0,
+                publish: publish(topic),
+            };
+
+            let mut notification = Notification::Forward(forward);
+            aliases.outgoing(&mut notification);
+            match notification {
+                Notification::Forward(f) => (f.publish.topic, None),
+                Notification::ForwardWithProperties(f, p) => (f.publish.topic, p.topic_alias),
+                v => unreachable!("{:?}", v),
+            }
+        };
+
+        assert_eq!(forward("hello/1"), ("hello/1".into(), Some(1)));
+        assert_eq!(forward("hello/1"), ("".into(), Some(1)));
+        assert_eq!(forward("hello/2"), ("hello/2".into(), None));
+    }
+}
@@ -1,253 +1,629 @@
-use std::{
-    fs,
-    io::{self, BufReader, Cursor},
-    net::{AddrParseError, SocketAddr, ToSocketAddrs},
-    path::Path,
-    sync::Arc,
-    time::Duration,
-};
-
-use bytes::BytesMut;
-use flume::Sender;
+use bytes::Bytes;
+use flume::{Receiver, Sender};
 use log::*;
-use tokio::{
-    net::TcpStream,
-    time::{sleep, sleep_until, Instant},
-};
-use tokio_rustls::{
-    client::TlsStream,
-    rustls::{internal::pemfile::*, ClientConfig, TLSError},
-    webpki::{self, DNSNameRef, InvalidDNSNameError},
-    TlsConnector,
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::time::error::Elapsed;
+use tokio::time::{self, Instant};
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+#[cfg(feature = "use-rustls")]
+use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+#[cfg(feature = "use-rustls")]
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, Error as RustlsError, PrivateKey, RootCertStore, ServerName,
 };
+#[cfg(feature = "use-rustls")]
+use tokio_rustls::TlsConnector;
 
-use crate::{
-    link::{
-        local::{Link, LinkError},
-        network::Network,
-    },
-    router::Event,
-    BridgeConfig, ClientAuth, ConnectionId, Transport, protocol::{v4::pingreq, Packet},
+use crate::link::local::{Link, LinkError, LinkRx, LinkSettings, LinkTx};
+use crate::link::network::{self, Network, N};
+use crate::protocol::v4::V4;
+use crate::protocol::{
+    self, ConnAck, Connect, ConnectReturnCode, DisconnectReasonCode, Filter, Packet, PingReq,
+    PubAck, PubAckReason, PubComp, PubCompReason, PubRec, PubRecReason, QoS, RetainForwardRule,
+    Subscribe, SubscribeReasonCode,
 };
+use crate::router::{Disconnection, Event};
+#[cfg(feature = "use-rustls")]
+use crate::ClientAuth;
+use crate::{BridgeConfig, ConnectionId, Notification, TopicMapping, Transport};
 
-pub async fn bridge_launch(
+/// Maximum size of packets received from upstream broker
+const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BridgeError {
+    #[error("I/O {0}")]
+    Io(#[from] io::Error),
+    #[error("Network {0}")]
+    Network(#[from] network::Error),
+    #[error("Timeout")]
+    Timeout(#[from] Elapsed),
+    #[error("Local link error = {0}")]
+    Link(Box<LinkError>),
+    #[error("Invalid qos {0}")]
+    InvalidQos(u8),
+    #[error("Connection refused with {0:?}")]
+    ConnectionRefused(ConnectReturnCode),
+    #[error("Subscription to {0} rejected")]
+    SubscriptionRejected(String),
+    #[error("Unexpected packet {0:?}")]
+    UnexpectedPacket(Box<Packet>),
+    #[error("No ping response")]
+    PingTimeout,
+    #[error("Local link disconnected by router with {0:?}")]
+    Disconnected(DisconnectReasonCode),
+    #[cfg(feature = "use-rustls")]
+    #[error("Rustls error {0}")]
+    Rustls(#[from] RustlsError),
+    #[cfg(feature = "use-rustls")]
+    #[error("Invalid CA cert file {0}")]
+    InvalidCACert(String),
+    #[cfg(feature = "use-rustls")]
+    #[error("Invalid client key file {0}")]
+    InvalidClientKey(String),
+    #[cfg(feature = "use-rustls")]
+    #[error("Invalid domain name {0}")]
+    InvalidDomain(String),
+    #[cfg(not(feature = "use-rustls"))]
+    #[error("TLS transport requires use-rustls feature")]
+    RustlsNotEnabled,
+}
+
+// Link errors carry router events, which are large
+impl From<LinkError> for BridgeError {
+    fn from(e: LinkError) -> Self {
+        BridgeError::Link(Box::new(e))
+    }
+}
+
+/// Connects to upstream broker, subscribes to `sub_path` and republishes received
+/// publishes to the local router. Publishes on local `pub_paths` are forwarded to
+/// upstream with QoS 1. Reconnects after `reconnection_delay` on failures
+/// Runs the bridge until the router is gone or senders of `shutdown` are dropped
+pub async fn start(
     config: BridgeConfig,
     router_tx: Sender<(ConnectionId, Event)>,
+    shutdown: Receiver<()>,
 ) -> Result<(), BridgeError> {
-    // connect to local router
-    let (mut tx, _rx) = Link::new("bridge", router_tx, true)?;
-
-    // connecting to other router
-    let qos = match u8_to_qos(config.qos) {
-        Ok(v) => v,
-        Err(_e) => {
-            return Err(BridgeError::InvalidQos);
+    let qos = protocol::qos(config.qos).ok_or(BridgeError::InvalidQos(config.qos))?;
+    let delay = Duration::from_secs(config.reconnection_delay);
+
+    loop {
+        let o = select! {
+            o = run(&config, qos, &router_tx) => o,
+            _ = shutdown.recv_async() => return Ok(()),
+        };
+
+        match o {
+            // Router is gone, nothing left to bridge to
+            Err(e @ BridgeError::Link(_)) => return Err(e),
+            Err(e) => warn!(
+                "{:15.15}[E] {:20} url = {}:{}, error = {}",
+                config.name, "bridge-disconnected", config.url, config.port, e
+            ),
+            Ok(()) => (),
         }
+
+        select! {
+            _ = time::sleep(delay) => (),
+            _ = shutdown.recv_async() => return Ok(()),
+        }
+    }
+}
+
+async fn run(
+    config: &BridgeConfig,
+    qos: QoS,
+    router_tx: &Sender<(ConnectionId, Event)>,
+) -> Result<(), BridgeError> {
+    let timeout = Duration::from_secs(config.timeout_delay);
+    let socket = time::timeout(timeout, connect(config)).await??;
+    let mut network = Network::new(socket, MAX_PACKET_SIZE, 100, V4);
+
+    let connect = Connect {
+        keep_alive: config.ping_delay.min(u16::MAX as u64) as u16,
+        client_id: config.name.clone(),
+        clean_session: true,
     };
-    let addr = match (config.url.clone(), config.port).to_socket_addrs()?.next() {
-        Some(addr) => addr,
-        None => return Err(BridgeError::InvalidUrl),
+
+    network
+        .write_packet(Packet::Connect(connect, None, None, None, None))
+        .await?;
+
+    match time::timeout(timeout, network.read()).await?? {
+        Packet::ConnAck(ConnAck {
+            code: ConnectReturnCode::Success,
+            ..
+        }) => (),
+        Packet::ConnAck(ack) => return Err(BridgeError::ConnectionRefused(ack.code)),
+        packet => return Err(BridgeError::UnexpectedPacket(Box::new(packet))),
+    }
+
+    let filter = Filter {
+        path: config.sub_path.clone(),
+        qos,
+        nolocal: false,
+        preserve_retain: false,
+        retain_forward_rule: RetainForwardRule::OnEverySubscribe,
     };
 
-    'outer: loop {
-        let mut network = match netowrk_connect(&config, &addr).await {
-            Ok(v) => v,
-            Err((e, s)) => {
-                warn!("bridge: {}, retrying - {}", s, e);
-                sleep(Duration::from_secs(config.reconnection_delay)).await;
-                continue;
-            }
-        };
-        info!("bridge: connected to {}", config.url);
+    let subscribe = Subscribe {
+        pkid: 1,
+        filters: vec![filter],
+    };
 
-        if let Err(e) = network_init(&mut network, &config.sub_path, qos).await {
-            warn!("bridge: unable to init connection, reconnecting - {}", e);
-            sleep(Duration::from_secs(config.reconnection_delay)).await;
-            continue;
+    network
+        .write_packet(Packet::Subscribe(subscribe, None))
+        .await?;
+
+    match time::timeout(timeout, network.read()).await?? {
+        Packet::SubAck(suback, _) => {
+            if suback
+                .return_codes
+                .iter()
+                .any(|code| !matches!(code, SubscribeReasonCode::Success(_)))
+            {
+                return Err(BridgeError::SubscriptionRejected(config.sub_path.clone()));
+            }
         }
+        packet => return Err(BridgeError::UnexpectedPacket(Box::new(packet))),
+    }
+
+    info!(
+        "{:15.15}[I] {:20} url = {}:{}, filter = {}",
+        config.name, "bridge-connected", config.url, config.port, config.sub_path
+    );
+
+    // Local link lives only as long as the upstream connection. Its session is
+    // persistent and the router resumes `pub_paths` from the last publish which
+    // upstream acked, so nothing is lost while upstream is unreachable. Publishes
+    // from upstream are acked before the router sees them, so topics without
+    // local subscribers get a filter instead of failing
+    let settings = LinkSettings {
+        clean: false,
+        dynamic_filters: true,
+        local: true,
+        ..Default::default()
+    };
+
+    let (mut link_tx, mut link_rx, _ack) =
+        Link::init(&config.name, router_tx.clone(), settings).await?;
+
+    let o = bridge(config, &mut network, &mut link_tx, &mut link_rx).await;
+
+    // Router has already removed the connection
+    if let Err(BridgeError::Disconnected(_)) = o {
+        return o;
+    }
+
+    let disconnect = Disconnection {
+        id: config.name.clone(),
+        execute_will: false,
+        pending: vec![],
+    };
+
+    let message = (link_rx.id(), Event::Disconnect(disconnect));
+    router_tx.send_async(message).await.ok();
+    o
+}
+
+async fn bridge(
+    config: &BridgeConfig,
+    network: &mut Network<V4>,
+    link_tx: &mut LinkTx,
+    link_rx: &mut LinkRx,
+) -> Result<(), BridgeError> {
+    // Subscriptions of the saved session are kept as they are
+    let filters: Vec<Filter> = config
+        .pub_paths
+        .iter()
+        .map(|path| Filter {
+            path: path.clone(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        })
+        .collect();
 
-        let mut buf = BytesMut::with_capacity(2);
-        pingreq::write(&mut buf).unwrap();
-        debug!("bridge: recved suback from {}", config.url);
-
-        let mut ping_time = Instant::now();
-        let mut timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
-        let mut ping_unacked = false;
-
-        loop {
-            tokio::select! {
-                packet_res = network.read() => {
-                    // resetting timeout because tokio::select! consumes the old timeout future
-                    timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
-                    let packet = match packet_res {
-                        Ok(v) => v,
-                        Err(e) => {
-                            warn!("bridge: unable to read from network stream, reconnecting - {}", e);
-                            sleep(Duration::from_secs(config.reconnection_delay)).await;
-                            continue 'outer;
+    if !filters.is_empty() {
+        let subscribe = Subscribe { pkid: 0, filters };
+        link_tx
+            .buffer()
+            .push_back(Packet::Subscribe(subscribe, None));
+        link_tx.notify().await?;
+    }
+
+    let ping_delay = Duration::from_secs(config.ping_delay.max(1));
+    let mut ping = time::interval_at(Instant::now() + ping_delay, ping_delay);
+    let mut ping_pending = false;
+    let mut notifications = VecDeque::with_capacity(100);
+
+    loop {
+        select! {
+            o = network.read() => match o? {
+                Packet::Publish(mut publish, _) => {
+                    let (qos, pkid) = (publish.qos, publish.pkid);
+
+                    // Router doesn't ack qos 0 publishes of the bridge. Acks to
+                    // upstream are sent once the publish is handed to the router
+                    publish.topic = to_local(&config.topics, &publish.topic);
+                    publish.qos = QoS::AtMostOnce;
+                    publish.pkid = 0;
+                    publish.dup = false;
+
+                    link_tx.buffer().push_back(Packet::Publish(publish, None));
+                    link_tx.notify().await?;
+
+                    let ack = match qos {
+                        QoS::AtMostOnce => continue,
+                        QoS::AtLeastOnce => {
+                            let reason = PubAckReason::Success;
+                            Packet::PubAck(PubAck { pkid, reason }, None)
+                        }
+                        QoS::ExactlyOnce => {
+                            let reason = PubRecReason::Success;
+                            Packet::PubRec(PubRec { pkid, reason }, None)
                         }
                     };
 
-                    match packet {
-                        Packet::Publish(publish) => {
-                            tx.send(Packet::Publish(publish)).await?;
-                        }
-                        Packet::PingResp => ping_unacked = false,
-                        packet => warn!("bridge: expected publish, got {:?}", packet),
-                    }
+                    network.write_packet(ack).await?;
                 }
-                _ = timeout => {
-                    // retry connection if ping not acked till next timeout
-                    if ping_unacked {
-                        warn!("bridge: no response to ping, reconnecting");
-                        sleep(Duration::from_secs(config.reconnection_delay)).await;
-                        continue 'outer;
-                    }
-
-                    if let Err(e) = network.write_all(&buf).await {
-                        warn!("bridge: unable to write PINGRES to network stream, reconnecting - {}", e);
-                        sleep(Duration::from_secs(config.reconnection_delay)).await;
-                        continue 'outer;
+                Packet::PubRel(pubrel, _) => {
+                    let pubcomp = PubComp {
+                        pkid: pubrel.pkid,
+                        reason: PubCompReason::Success,
                     };
-                    ping_unacked = true;
 
-                    ping_time = Instant::now();
-                    // resetting timeout because tokio::select! consumes the old timeout future
-                    timeout = sleep_until(ping_time + Duration::from_secs(config.ping_delay));
+                    network.write_packet(Packet::PubComp(pubcomp, None)).await?;
+                }
+                // Publishes of `pub_paths` are acked in the router only once
+                // upstream has acked them
+                Packet::PubAck(puback, _) => {
+                    link_tx.buffer().push_back(Packet::PubAck(puback, None));
+                    link_tx.notify().await?;
+                }
+                Packet::PingResp(_) => ping_pending = false,
+                packet => {
+                    warn!("{:15.15}[E] {:20} packet = {:?}", config.name, "bridge-unexpected", packet);
+                }
+            },
+            o = link_rx.exchange(&mut notifications) => {
+                o?;
+
+                // Packet ids of the router are unique among inflight publishes of
+                // this link and are reused for upstream
+                let mut unscheduled = false;
+                for notification in notifications.drain(..) {
+                    let mut publish = match notification {
+                        Notification::Forward(forward) => forward.publish,
+                        Notification::ForwardWithProperties(forward, _) => forward.publish,
+                        Notification::Unschedule => {
+                            unscheduled = true;
+                            continue;
+                        }
+                        // Reconnects with a new local link
+                        Notification::Disconnect(reason)
+                        | Notification::DisconnectWithProperties(reason, _) => {
+                            return Err(BridgeError::Disconnected(reason));
+                        }
+                        _ => continue,
+                    };
+
+                    publish.topic = to_remote(&config.topics, &publish.topic);
+                    network.write_packet(Packet::Publish(publish, None)).await?;
+                }
+
+                if unscheduled {
+                    link_rx.wake().await?;
                 }
             }
+            _ = ping.tick() => {
+                // Upstream didn't respond to the previous ping in time
+                if ping_pending {
+                    return Err(BridgeError::PingTimeout);
+                }
+
+                network.write_packet(Packet::PingReq(PingReq)).await?;
+                ping_pending = true;
+            }
         }
     }
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum BridgeError {
-    #[error("Addr - {0}")]
-    Addr(#[from] AddrParseError),
-    #[error("I/O - {0}")]
-    Io(#[from] io::Error),
-    #[error("Web Pki - {0}")]
-    WebPki(#[from] tokio_rustls::webpki::Error),
-    #[error("DNS name - {0}")]
-    DNSName(#[from] InvalidDNSNameError),
-    #[error("TLS error - {0}")]
-    TLS(#[from] TLSError),
-    #[error("No valid cert in chain")]
-    NoValidCertInChain,
-    #[error("local link - {0}")]
-    Link(#[from] LinkError),
-    #[error("invalid qos")]
-    InvalidQos,
-    #[error("invalid key")]
-    InvalidKey,
-    #[error("invalid url")]
-    InvalidUrl,
-    #[error("invalid packet")]
-    InvalidPacket,
-}
+async fn connect(config: &BridgeConfig) -> Result<Box<dyn N>, BridgeError> {
+    let socket = TcpStream::connect((config.url.as_str(), config.port)).await?;
+    match &config.transport {
+        Transport::Tcp => Ok(Box::new(socket)),
+        #[cfg(feature = "use-rustls")]
+        Transport::Tls { ca, client_auth } => {
+            let connector = tls_connector(ca, client_auth)?;
+            let domain = ServerName::try_from(config.url.as_str())
+                .map_err(|_| BridgeError::InvalidDomain(config.url.clone()))?;
 
-// The cert handling functions return unit right now, this is a shortcut
-impl From<()> for BridgeError {
-    fn from(_: ()) -> Self {
-        BridgeError::NoValidCertInChain
+            let socket = connector.connect(domain, socket).await?;
+            Ok(Box::new(socket))
+        }
+        #[cfg(not(feature = "use-rustls"))]
+        Transport::Tls { .. } => Err(BridgeError::RustlsNotEnabled),
     }
 }
 
-async fn netowrk_connect(
-    config: &BridgeConfig,
-    addr: &SocketAddr,
-) -> Result<Network, (BridgeError, &'static str)> {
-    match &config.transport {
-        Transport::Tcp => {
-            let socket = match TcpStream::connect(addr).await {
-                Ok(v) => v,
-                Err(e) => return Err((BridgeError::Io(e), "unable to form TCP connection")),
-            };
-            Ok(Network::new(Box::new(socket), 5120))
-        }
-        Transport::Tls { ca, client_auth } => {
-            let socket = match tls_connect(config.url.clone(), config.port, &ca, client_auth).await
-            {
-                Ok(v) => v,
-                Err(e) => return Err((e, "unable to form TCP/TLS connection")),
+#[cfg(feature = "use-rustls")]
+fn tls_connector(ca: &Path, client_auth: &Option<ClientAuth>) -> Result<TlsConnector, BridgeError> {
+    let ca_path = ca.display().to_string();
+    let ca_certs = rustls_pemfile::certs(&mut BufReader::new(File::open(ca)?))?;
+    if ca_certs.is_empty() {
+        return Err(BridgeError::InvalidCACert(ca_path));
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in ca_certs {
+        roots
+            .add(&Certificate(cert))
+            .map_err(|_| BridgeError::InvalidCACert(ca_path.clone()))?;
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    let config = match client_auth {
+        Some(ClientAuth { certs, key }) => {
+            let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(certs)?))?;
+            let certs = certs.into_iter().map(Certificate).collect();
+
+            // Keys can either be in PKCS1 (RSA) or PKCS8 format
+            let mut keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(File::open(key)?))?;
+            if keys.is_empty() {
+                keys = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))?;
+            }
+
+            let key = match keys.into_iter().next() {
+                Some(key) => PrivateKey(key),
+                None => return Err(BridgeError::InvalidClientKey(key.display().to_string())),
             };
-            Ok(Network::new(Box::new(socket), 5120))
+
+            builder.with_single_cert(certs, key)?
         }
-    }
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
 }
 
-async fn send_and_recv<F: FnOnce(Packet) -> bool>(
-    network: &mut Network,
-    send_packet: &[u8],
-    accept_recv: F,
-) -> Result<(), BridgeError> {
-    network.write_all(send_packet).await?;
-    match accept_recv(network.read().await?) {
-        true => Ok(()),
-        false => Err(BridgeError::InvalidPacket),
-    }
+/// Rewrites topic of a publish received from upstream broker
+fn to_local(mappings: &[TopicMapping], topic: &Bytes) -> Bytes {
+    let mappings = mappings.iter().map(|m| (&m.remote, &m.local));
+    rewrite(mappings, topic)
 }
 
-async fn network_init(network: &mut Network, sub_path: &str, qos: QoS) -> Result<(), BridgeError> {
-    let mut buf = BytesMut::new();
-    Connect::new("bytebeam-bridge").write(&mut buf).unwrap();
-    send_and_recv(network, &buf, |packet| matches!(packet, Packet::ConnAck(_))).await?;
-
-    buf.clear();
-    let _len = subscribe::write(
-        vec![SubscribeFilter {
-            path: sub_path.to_owned(),
-            qos,
-        }],
-        1,
-        &mut buf,
-    );
-    send_and_recv(network, &buf, |packet| matches!(packet, Packet::SubAck(_))).await
+/// Rewrites topic of a local publish which is forwarded to upstream broker
+fn to_remote(mappings: &[TopicMapping], topic: &Bytes) -> Bytes {
+    let mappings = mappings.iter().map(|m| (&m.local, &m.remote));
+    rewrite(mappings, topic)
 }
 
-// TODO: Try to replicate rumqttc
-pub async fn tls_connect<P: AsRef<Path>>(
-    broker_addr: String,
-    port: u16,
-    ca_file: P,
-    client_auth_opt: &Option<ClientAuth>,
-) -> Result<TlsStream<TcpStream>, BridgeError> {
-    let mut config = ClientConfig::new();
-    config
-        .root_store
-        .add_pem_file(&mut BufReader::new(Cursor::new(fs::read(ca_file)?)))?;
-
-    if let Some(ClientAuth {
-        certs: certs_path,
-        key: key_path,
-    }) = client_auth_opt
-    {
-        let read_certs = certs(&mut BufReader::new(Cursor::new(fs::read(certs_path)?)))?;
-
-        let read_keys = match rustls_pemfile::read_one(&mut BufReader::new(Cursor::new(fs::read(
-            key_path,
-        )?)))? {
-            Some(rustls_pemfile::Item::RSAKey(_)) => {
-                rsa_private_keys(&mut BufReader::new(Cursor::new(fs::read(key_path)?)))?
-            }
-            Some(rustls_pemfile::Item::PKCS8Key(_)) => {
-                pkcs8_private_keys(&mut BufReader::new(Cursor::new(fs::read(key_path)?)))?
-            }
-            None | Some(_) => return Err(BridgeError::InvalidKey),
+/// Replaces the first matching `from` prefix of the topic with `to`. Topics
+/// without matching prefix are left as is
+fn rewrite<'a>(
+    mut mappings: impl Iterator<Item = (&'a String, &'a String)>,
+    topic: &Bytes,
+) -> Bytes {
+    mappings
+        .find_map(|(from, to)| {
+            let rest = topic.strip_prefix(from.as_bytes())?;
+            Some([to.as_bytes(), rest].concat().into())
+        })
+        .unwrap_or_else(|| topic.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{start, to_local, to_remote, MAX_PACKET_SIZE};
+    use crate::link::local::{Link, LinkSettings};
+    use crate::link::network::Network;
+    use crate::protocol::v4::V4;
+    use crate::protocol::{
+        ConnAck, ConnectReturnCode, Packet, PubAck, Publish, QoS, SubAck, SubscribeReasonCode,
+    };
+    use crate::router::{Ack, Event, Router};
+    use crate::{BridgeConfig, ConnectionId, Notification, RouterConfig, TopicMapping, Transport};
+    use bytes::Bytes;
+    use flume::Sender;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::time;
+
+    #[test]
+    fn upstream_topics_are_rewritten_with_first_matching_prefix() {
+        let mapping = |remote: &str, local: &str| TopicMapping {
+            remote: remote.to_owned(),
+            local: local.to_owned(),
+        };
+
+        let mappings = vec![
+            mapping("cloud/devices/", "devices/"),
+            mapping("cloud/", "upstream/"),
+        ];
+
+        let topic = |t: &'static str| Bytes::from_static(t.as_bytes());
+        assert_eq!(
+            to_local(&mappings, &topic("cloud/devices/1/status")),
+            topic("devices/1/status")
+        );
+        assert_eq!(
+            to_local(&mappings, &topic("cloud/alerts")),
+            topic("upstream/alerts")
+        );
+        assert_eq!(
+            to_local(&mappings, &topic("hello/world")),
+            topic("hello/world")
+        );
+        assert_eq!(to_local(&[], &topic("cloud/alerts")), topic("cloud/alerts"));
+    }
+
+    #[test]
+    fn local_topics_are_rewritten_for_upstream() {
+        let mappings = vec![TopicMapping {
+            remote: "cloud/edge-1/".to_owned(),
+            local: "".to_owned(),
+        }];
+
+        let topic = |t: &'static str| Bytes::from_static(t.as_bytes());
+        assert_eq!(
+            to_remote(&mappings, &topic("sensors/temperature")),
+            topic("cloud/edge-1/sensors/temperature")
+        );
+        assert_eq!(to_remote(&[], &topic("sensors/1")), topic("sensors/1"));
+    }
+
+    fn config(port: u16) -> BridgeConfig {
+        BridgeConfig {
+            name: "bridge".to_owned(),
+            url: "127.0.0.1".to_owned(),
+            port,
+            qos: 1,
+            sub_path: "cloud/#".to_owned(),
+            pub_paths: vec![],
+            reconnection_delay: 0,
+            ping_delay: 60,
+            timeout_delay: 5,
+            transport: Transport::Tcp,
+            topics: vec![],
+        }
+    }
+
+    fn router() -> Sender<(ConnectionId, Event)> {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            max_connections: 10,
+            ..Default::default()
+        };
+
+        let (_router, router_tx) = Router::new(0, config).spawn();
+        router_tx
+    }
+
+    /// Accepts the next connection of the bridge as upstream broker
+    async fn accept(listener: &TcpListener) -> Network<V4> {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut upstream = Network::new(Box::new(socket), MAX_PACKET_SIZE, 10, V4);
+        assert!(matches!(upstream.read().await, Ok(Packet::Connect(..))));
+        let code = ConnectReturnCode::Success;
+        let connack = ConnAck {
+            session_present: false,
+            code,
         };
+        upstream
+            .write_packet(Packet::ConnAck(connack))
+            .await
+            .unwrap();
+
+        assert!(matches!(upstream.read().await, Ok(Packet::Subscribe(..))));
+        let return_codes = vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)];
+        let suback = SubAck {
+            pkid: 1,
+            return_codes,
+        };
+        upstream
+            .write_packet(Packet::SubAck(suback, None))
+            .await
+            .unwrap();
+        upstream
+    }
 
-        let read_key = match read_keys.first() {
-            Some(v) => v.clone(),
-            None => return Err(BridgeError::InvalidKey),
+    fn publish(topic: &'static str, pkid: u16) -> Packet {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: Bytes::from_static(topic.as_bytes()),
+            pkid,
+            payload: Bytes::from_static(b"hello"),
         };
 
-        config.set_single_client_cert(read_certs, read_key)?;
+        Packet::Publish(publish, None)
     }
 
-    let connector = TlsConnector::from(Arc::new(config));
-    let addr = broker_addr.clone();
-    let domain = DNSNameRef::try_from_ascii_str(&broker_addr)?;
-    let tcp = TcpStream::connect((addr, port)).await?;
-    let tls = connector.connect(domain, tcp).await?;
-    Ok(tls)
+    #[tokio::test]
+    async fn upstream_topics_without_local_filters_are_bridged() {
+        let router_tx = router();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(listener.local_addr().unwrap().port());
+        let (_shutdown_tx, shutdown_rx) = flume::bounded(1);
+        tokio::spawn(start(config, router_tx.clone(), shutdown_rx));
+        let mut upstream = accept(&listener).await;
+
+        // Nobody subscribed to this topic locally
+        upstream
+            .write_packet(publish("cloud/nobody", 1))
+            .await
+            .unwrap();
+        match upstream.read().await {
+            Ok(Packet::PubAck(PubAck { pkid: 1, .. }, _)) => (),
+            v => panic!("{:?}", v),
+        }
+
+        let settings = LinkSettings::default();
+        let (mut tx, mut rx, _) = Link::new("subscriber", router_tx, settings).unwrap();
+        tx.subscribe("cloud/devices").unwrap();
+        match rx.next().await {
+            Ok(Some(Notification::DeviceAck(Ack::SubAck(..)))) => (),
+            v => panic!("{:?}", v),
+        }
+
+        // Later publishes still reach local subscribers
+        upstream
+            .write_packet(publish("cloud/devices", 2))
+            .await
+            .unwrap();
+        match rx.next().await {
+            Ok(Some(Notification::Forward(forward))) => {
+                assert_eq!(forward.publish.topic, "cloud/devices")
+            }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[tokio::test]
+    async fn bridge_reconnects_when_router_disconnects_its_link() {
+        let router_tx = router();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = config(listener.local_addr().unwrap().port());
+        let (_shutdown_tx, shutdown_rx) = flume::bounded(1);
+        tokio::spawn(start(config, router_tx.clone(), shutdown_rx));
+        let mut upstream = accept(&listener).await;
+
+        // Local link of the bridge is up once its publishes reach subscribers
+        let settings = LinkSettings::default();
+        let (mut tx, mut rx, _) = Link::new("subscriber", router_tx.clone(), settings).unwrap();
+        tx.subscribe("cloud/devices").unwrap();
+        assert!(matches!(
+            rx.next().await,
+            Ok(Some(Notification::DeviceAck(..)))
+        ));
+        upstream
+            .write_packet(publish("cloud/devices", 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.next().await,
+            Ok(Some(Notification::Forward(..)))
+        ));
+
+        // Taking over client id of the bridge disconnects its local link
+        let settings = LinkSettings::default();
+        let link = Link::init("bridge", router_tx, settings).await.unwrap();
+        let reconnect = time::timeout(Duration::from_secs(5), accept(&listener));
+        let _upstream = reconnect.await.unwrap();
+        drop(link);
+    }
 }
@@ -1,106 +1,434 @@
-use crate::link::local::{Link, LinkRx};
-use crate::router::{Event, MetricsRequest};
+use crate::link::local::{Link, LinkRx, LinkSettings};
+use crate::protocol::{qos, Publish};
+use crate::router::prometheus::Encoder;
+use crate::router::{AdminReply, AdminRequest, Event, MetricsReply, MetricsRequest};
+use crate::server::constant_time_eq;
 use crate::{ConnectionId, ConsoleSettings};
-use flume::Sender;
+use flume::{Receiver, Sender};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
+/// Connections accepted by listeners, by listener name
+pub type Accepts = Arc<Mutex<BTreeMap<String, usize>>>;
+
 pub struct ConsoleLink {
     config: ConsoleSettings,
     connection_id: ConnectionId,
     router_tx: Sender<(ConnectionId, Event)>,
     link_rx: LinkRx,
+    /// Http workers share the reply channel of the link. Held from sending a
+    /// request to the router until its reply is received
+    requests: Mutex<()>,
+    reload_tx: Sender<()>,
+    accepts: Accepts,
 }
 
 impl ConsoleLink {
     /// Requires the corresponding Router to be running to complete
-    pub fn new(config: ConsoleSettings, router_tx: Sender<(ConnectionId, Event)>) -> ConsoleLink {
+    pub fn new(
+        config: ConsoleSettings,
+        router_tx: Sender<(ConnectionId, Event)>,
+        reload_tx: Sender<()>,
+        accepts: Accepts,
+    ) -> ConsoleLink {
         let tx = router_tx.clone();
-        let (link_tx, link_rx, _ack) =
-            Link::new(None, "console", tx, true, None, true).unwrap();
+        let settings = LinkSettings {
+            dynamic_filters: true,
+            local: true,
+            ..Default::default()
+        };
+
+        let (link_tx, link_rx, _ack) = Link::new("console", tx, settings).unwrap();
         let connection_id = link_tx.connection_id;
         ConsoleLink {
             config,
             router_tx,
             link_rx,
             connection_id,
+            requests: Mutex::new(()),
+            reload_tx,
+            accepts,
         }
     }
+
+    /// Sends `event` to the router and waits for its reply. Fails when the
+    /// router is gone
+    fn request(&self, event: Event) -> Result<Option<MetricsReply>, ()> {
+        let _guard = self.requests.lock();
+
+        // Replies which came after their request timed out
+        self.link_rx.discard_metrics();
+        let message = (self.connection_id, event);
+        self.router_tx.send(message).map_err(|_| ())?;
+        Ok(self.link_rx.metrics())
+    }
 }
 
-pub fn start(console: Arc<ConsoleLink>) {
+/// Serves the console until `shutdown` is signalled by sending or dropping its sender
+pub fn start(console: Arc<ConsoleLink>, shutdown: Receiver<()>) {
     let address = console.config.listen.clone();
+    let server = rouille::Server::new(address, move |request| handle(&console, request));
+    let server = match server {
+        Ok(server) => server,
+        Err(e) => {
+            error!("{:15.15}[E] {:20} {:?}", "console", "start-error", e);
+            return;
+        }
+    };
+
+    let (handle, stop) = server.stoppable();
+    shutdown.recv().ok();
+    stop.send(()).ok();
+    handle.join().ok();
+}
+
+fn handle(console: &ConsoleLink, request: &rouille::Request) -> rouille::Response {
+    router!(request,
+        (GET) (/) => {
+            rouille::Response::redirect_302("/config")
+        },
+        (GET) (/config) => {
+            rouille::Response::json(&console.config.clone())
+        },
+        (GET) (/metrics) => {
+            let event = Event::Metrics(MetricsRequest::Prometheus);
+            let mut metrics = match console.request(event) {
+                Ok(Some(MetricsReply::Prometheus(v))) => v,
+                Ok(_) => return rouille::Response::text("").with_status_code(503),
+                Err(()) => return rouille::Response::empty_404(),
+            };
+
+            metrics.push_str(&listener_metrics(&console.accepts));
+            rouille::Response::text(metrics)
+                .with_unique_header("Content-Type", "text/plain; version=0.0.4")
+        },
+        (POST) (/reload) => {
+            // Pending reload picks up this request as well
+            console.reload_tx.try_send(()).ok();
+            rouille::Response::empty_204()
+        },
+        (GET) (/router) => {
+            metrics(console, MetricsRequest::Router)
+        },
+        (GET) (/device/{id: String}) => {
+            metrics(console, MetricsRequest::Connection(id))
+        },
+        (GET) (/subscriptions) => {
+            metrics(console, MetricsRequest::Subscriptions)
+        },
+        (GET) (/subscription/{filter: String}) => {
+            let filter = filter.replace('.', "/");
+            metrics(console, MetricsRequest::Subscription(filter))
+        },
+        (GET) (/waiters/{filter: String}) => {
+            let filter = filter.replace('.', "/");
+            metrics(console, MetricsRequest::Waiters(filter))
+        },
+        (GET) (/readyqueue) => {
+            metrics(console, MetricsRequest::ReadyQueue)
+        },
+        (POST) (/device/{id: String}/disconnect) => {
+            admin(console, request, AdminRequest::Disconnect(id))
+        },
+        (DELETE) (/session/{id: String}) => {
+            admin(console, request, AdminRequest::DeleteSession(id))
+        },
+        (GET) (/retained) => {
+            admin(console, request, AdminRequest::Retained("#".to_owned()))
+        },
+        (GET) (/retained/{topic: String}) => {
+            let topic = topic.replace('.', "/");
+            admin(console, request, AdminRequest::RetainedPublish(topic))
+        },
+        (DELETE) (/retained) => {
+            admin(console, request, AdminRequest::ClearRetained("#".to_owned()))
+        },
+        (DELETE) (/retained/{filter: String}) => {
+            let filter = filter.replace('.', "/");
+            admin(console, request, AdminRequest::ClearRetained(filter))
+        },
+        (POST) (/publish) => {
+            let publish: PublishRequest = match rouille::input::json_input(request) {
+                Ok(v) => v,
+                Err(_) => return rouille::Response::empty_400(),
+            };
+
+            let qos = match qos(publish.qos) {
+                Some(v) => v,
+                None => return rouille::Response::empty_400(),
+            };
+
+            let publish = Publish {
+                dup: false,
+                qos,
+                retain: publish.retain,
+                topic: publish.topic.into(),
+                pkid: 0,
+                payload: publish.payload.into(),
+            };
+
+            admin(console, request, AdminRequest::Publish(publish))
+        },
+        _ => rouille::Response::empty_404()
+    )
+}
+
+/// Replies with metrics of the router as json
+fn metrics(console: &ConsoleLink, request: MetricsRequest) -> rouille::Response {
+    match console.request(Event::Metrics(request)) {
+        Ok(v) => rouille::Response::json(&v),
+        Err(()) => rouille::Response::empty_404(),
+    }
+}
+
+/// Body of `POST /publish`
+#[derive(Debug, Deserialize)]
+struct PublishRequest {
+    topic: String,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+}
+
+/// Runs an admin action on the router after checking the bearer token of
+/// the request. Admin actions are forbidden when no token is configured
+fn admin(
+    console: &ConsoleLink,
+    request: &rouille::Request,
+    action: AdminRequest,
+) -> rouille::Response {
+    let token = match &console.config.admin_token {
+        Some(v) => v,
+        None => return rouille::Response::text("").with_status_code(403),
+    };
+
+    let authorization = request.header("Authorization").unwrap_or_default();
+    match authorization.strip_prefix("Bearer ") {
+        Some(given) if constant_time_eq(token, given) => (),
+        _ => {
+            return rouille::Response::text("")
+                .with_status_code(401)
+                .with_unique_header("WWW-Authenticate", "Bearer")
+        }
+    }
+
+    let reply = match console.request(Event::Admin(action)) {
+        Ok(Some(MetricsReply::Admin(v))) => v,
+        Ok(_) => return rouille::Response::text("").with_status_code(503),
+        Err(()) => return rouille::Response::empty_404(),
+    };
+
+    match reply {
+        AdminReply::Done => rouille::Response::empty_204(),
+        AdminReply::NotFound => rouille::Response::empty_404(),
+        AdminReply::Retained(v) => rouille::Response::json(&v),
+        AdminReply::RetainedPublish(v) => rouille::Response::json(&v),
+        AdminReply::Cleared(v) => rouille::Response::json(&v),
+        AdminReply::Failed(e) => rouille::Response::text(e).with_status_code(400),
+    }
+}
+
+fn listener_metrics(accepts: &Accepts) -> String {
+    let mut encoder = Encoder::new();
+    let name = "rumqttd_listener_accepts_total";
+    encoder.family(name, "counter", "Connections accepted by the listener");
+    for (listener, count) in accepts.lock().iter() {
+        encoder.sample(name, &[("listener", listener)], count);
+    }
+
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{handle, ConsoleLink};
+    use crate::link::local::{Link, LinkSettings};
+    use crate::protocol::DisconnectReasonCode;
+    use crate::router::{Event, Router};
+    use crate::{ConnectionId, ConsoleSettings, Notification, RouterConfig};
+    use flume::Sender;
+    use rouille::Request;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::thread;
 
-    rouille::start_server(address, move |request| {
-        router!(request,
-            (GET) (/) => {
-                rouille::Response::redirect_302("/config")
-            },
-            (GET) (/config) => {
-                rouille::Response::json(&console.config.clone())
-            },
-            (GET) (/router) => {
-                let event = Event::Metrics(MetricsRequest::Router);
-                let message = (console.connection_id, event);
-                if console.router_tx.send(message).is_err() {
-                    return rouille::Response::empty_404()
-                }
-
-                let v = console.link_rx.metrics();
-                rouille::Response::json(&v)
-            },
-            (GET) (/device/{id: String}) => {
-                let event = Event::Metrics(MetricsRequest::Connection(id));
-                let message = (console.connection_id, event);
-                if console.router_tx.send(message).is_err() {
-                    return rouille::Response::empty_404()
-                }
-
-                let v = console.link_rx.metrics();
-                rouille::Response::json(&v)
-            },
-            (GET) (/subscriptions) => {
-                let event = Event::Metrics(MetricsRequest::Subscriptions);
-                let message = (console.connection_id, event);
-                if console.router_tx.send(message).is_err() {
-                    return rouille::Response::empty_404()
-                }
-
-                let v = console.link_rx.metrics();
-                rouille::Response::json(&v)
-            },
-            (GET) (/subscription/{filter: String}) => {
-                let filter = filter.replace('.', "/");
-                let event = Event::Metrics(MetricsRequest::Subscription(filter));
-                let message = (console.connection_id, event);
-                if console.router_tx.send(message).is_err() {
-                    return rouille::Response::empty_404()
-                }
-
-                let v = console.link_rx.metrics();
-                rouille::Response::json(&v)
-            },
-            (GET) (/waiters/{filter: String}) => {
-                let filter = filter.replace('.', "/");
-                let event = Event::Metrics(MetricsRequest::Waiters(filter));
-                let message = (console.connection_id, event);
-                if console.router_tx.send(message).is_err() {
-                    return rouille::Response::empty_404()
-                }
-
-                let v = console.link_rx.metrics();
-                rouille::Response::json(&v)
-            },
-            (GET) (/readyqueue) => {
-                let event = Event::Metrics(MetricsRequest::ReadyQueue);
-                let message = (console.connection_id, event);
-                if console.router_tx.send(message).is_err() {
-                    return rouille::Response::empty_404()
-                }
-
-                let v = console.link_rx.metrics();
-                rouille::Response::json(&v)
-           },
-            _ => rouille::Response::empty_404()
-        )
-    });
+    fn start(admin_token: Option<&str>) -> (ConsoleLink, Sender<(ConnectionId, Event)>) {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            max_connections: 10,
+            ..Default::default()
+        };
+
+        let (_router, router_tx) = Router::new(0, config).spawn();
+        let settings = ConsoleSettings {
+            listen: "127.0.0.1:0".to_owned(),
+            admin_token: admin_token.map(str::to_owned),
+        };
+
+        let (reload_tx, _reload_rx) = flume::bounded(1);
+        let accepts = Default::default();
+        let console = ConsoleLink::new(settings, router_tx.clone(), reload_tx, accepts);
+        (console, router_tx)
+    }
+
+    /// Status and body of the response to an admin request
+    fn request(console: &ConsoleLink, method: &str, url: &str, body: &str) -> (u16, String) {
+        let headers = vec![
+            ("Authorization".to_owned(), "Bearer secret".to_owned()),
+            ("Content-Type".to_owned(), "application/json".to_owned()),
+        ];
+
+        let request = Request::fake_http(method, url, headers, body.as_bytes().to_vec());
+        let response = handle(console, &request);
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        (response.status_code, body)
+    }
+
+    #[test]
+    fn admin_endpoints_need_the_token() {
+        let (console, _router_tx) = start(Some("secret"));
+        for authorization in [
+            None,
+            Some("Bearer secre"),
+            Some("Bearer secrets"),
+            Some("secret"),
+        ] {
+            let headers = authorization
+                .map(|v| ("Authorization".to_owned(), v.to_owned()))
+                .into_iter()
+                .collect();
+
+            let request = Request::fake_http("GET", "/retained", headers, vec![]);
+            assert_eq!(handle(&console, &request).status_code, 401);
+        }
+
+        assert_eq!(request(&console, "GET", "/retained", "").0, 200);
+
+        // Admin endpoints are disabled without a token
+        let (console, _router_tx) = start(None);
+        assert_eq!(request(&console, "GET", "/retained", "").0, 403);
+    }
+
+    #[test]
+    fn clients_are_disconnected_and_their_sessions_deleted() {
+        let (console, router_tx) = start(Some("secret"));
+        let settings = LinkSettings {
+            clean: false,
+            ..Default::default()
+        };
+
+        let (_tx, mut rx, _) = Link::new("device", router_tx, settings).unwrap();
+        assert_eq!(request(&console, "DELETE", "/session/device", "").0, 404);
+        assert_eq!(
+            request(&console, "POST", "/device/device/disconnect", "").0,
+            204
+        );
+        match rx.recv() {
+            Ok(Some(Notification::Disconnect(reason))) => {
+                assert_eq!(reason, DisconnectReasonCode::AdministrativeAction)
+            }
+            v => panic!("{:?}", v),
+        }
+
+        assert_eq!(
+            request(&console, "POST", "/device/device/disconnect", "").0,
+            404
+        );
+        assert_eq!(request(&console, "DELETE", "/session/device", "").0, 204);
+        assert_eq!(request(&console, "DELETE", "/session/device", "").0, 404);
+    }
+
+    #[test]
+    fn retained_publishes_are_listed_and_cleared() {
+        let (console, _router_tx) = start(Some("secret"));
+        let publish = r#"{"topic": "hello/world", "payload": "hi", "retain": true}"#;
+        assert_eq!(request(&console, "POST", "/publish", publish).0, 204);
+
+        // Payloads are only shown for a single topic
+        let (status, body) = request(&console, "GET", "/retained", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""topic":"hello/world""#), "{}", body);
+        assert!(!body.contains("payload"), "{}", body);
+
+        let (status, body) = request(&console, "GET", "/retained/hello.world", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""payload":"hi""#), "{}", body);
+
+        assert_eq!(
+            request(&console, "DELETE", "/retained", ""),
+            (200, "1".to_owned())
+        );
+        assert_eq!(
+            request(&console, "GET", "/retained", ""),
+            (200, "[]".to_owned())
+        );
+        assert_eq!(request(&console, "GET", "/retained/hello.world", "").0, 404);
+    }
+
+    #[test]
+    fn injected_publishes_reach_subscribers() {
+        let (console, router_tx) = start(Some("secret"));
+        let settings = LinkSettings::default();
+        let (mut tx, mut rx, _) = Link::new("device", router_tx, settings).unwrap();
+        tx.subscribe("hello/+").unwrap();
+        assert!(matches!(rx.recv(), Ok(Some(Notification::DeviceAck(..)))));
+
+        let publish = r#"{"topic": "hello/world", "payload": "hi", "qos": 3}"#;
+        assert_eq!(request(&console, "POST", "/publish", publish).0, 400);
+        let publish = r#"{"topic": "hello/world", "payload": "hi", "qos": 1}"#;
+        assert_eq!(request(&console, "POST", "/publish", publish).0, 204);
+        match rx.recv() {
+            Ok(Some(Notification::Forward(forward))) => {
+                assert_eq!(forward.publish.topic, "hello/world");
+                assert_eq!(forward.publish.payload, "hi");
+            }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn concurrent_requests_get_their_own_replies() {
+        let (console, _router_tx) = start(Some("secret"));
+        let console = Arc::new(console);
+        let topics = ["a", "b", "c", "d"];
+        for topic in topics {
+            let publish = format!(
+                r#"{{"topic": "{}", "payload": "{}", "retain": true}}"#,
+                topic, topic
+            );
+            assert_eq!(request(&console, "POST", "/publish", &publish).0, 204);
+        }
+
+        let workers: Vec<_> = topics
+            .into_iter()
+            .map(|topic| {
+                let console = console.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let url = format!("/retained/{}", topic);
+                        let (status, body) = request(&console, "GET", &url, "");
+                        assert_eq!(status, 200);
+                        assert!(
+                            body.contains(&format!(r#""payload":"{}""#, topic)),
+                            "{}",
+                            body
+                        );
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
 }
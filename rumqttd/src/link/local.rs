@@ -1,10 +1,11 @@
 use crate::protocol::{
-    ConnAck, Filter, LastWill, Packet, Publish, QoS, RetainForwardRule, Subscribe,
+    ConnAck, ConnectReturnCode, Filter, LastWill, LastWillProperties, Packet, Publish, QoS,
+    RetainForwardRule, Subscribe,
 };
 use crate::router::Ack;
 use crate::router::{
-    iobufs::{Incoming, Outgoing},
-    Connection, Event, MetricsReply, Notification, ShadowRequest,
+    iobufs::{Incoming, Outgoing, MAX_INFLIGHT, MAX_INFLIGHT_SIZE},
+    Connection, Event, MetricsReply, Notification, Quota, ShadowRequest,
 };
 use crate::ConnectionId;
 use bytes::Bytes;
@@ -23,6 +24,8 @@ pub enum LinkError {
     NotConnectionAck,
     #[error("ConnAck error {0}")]
     ConnectionAck(String),
+    #[error("Connection refused by router. Code = {0:?}")]
+    Refused(ConnectReturnCode),
     #[error("Channel try send error")]
     TrySend(#[from] TrySendError<(ConnectionId, Event)>),
     #[error("Channel send error")]
@@ -35,16 +38,56 @@ pub enum LinkError {
     Elapsed(#[from] tokio::time::error::Elapsed),
 }
 
+/// Settings of a connection to the router
+#[derive(Debug, Clone)]
+pub struct LinkSettings {
+    /// Tenant of the client. Topics are prefixed with `/tenants/<tenant_id>/`
+    pub tenant_id: Option<String>,
+    /// Username the client authenticated with
+    pub username: Option<String>,
+    pub clean: bool,
+    /// Seconds to keep a persistent session after disconnection. Kept forever
+    /// when None
+    pub session_expiry_interval: Option<u32>,
+    pub last_will: Option<LastWill>,
+    pub last_will_properties: Option<LastWillProperties>,
+    /// Create filters for publishes on topics which don't have one yet
+    pub dynamic_filters: bool,
+    /// Maximum number of unacked publishes to the client
+    pub max_inflight: u16,
+    /// New publishes aren't sent while unacked publishes take this many bytes
+    pub max_inflight_size: usize,
+    /// Publish quota. Not limited when None
+    pub quota: Option<Quota>,
+    /// Connection of the broker itself. Acl doesn't apply to it
+    pub local: bool,
+}
+
+impl Default for LinkSettings {
+    fn default() -> Self {
+        LinkSettings {
+            tenant_id: None,
+            username: None,
+            clean: true,
+            session_expiry_interval: None,
+            last_will: None,
+            last_will_properties: None,
+            dynamic_filters: false,
+            max_inflight: MAX_INFLIGHT,
+            max_inflight_size: MAX_INFLIGHT_SIZE,
+            quota: None,
+            local: false,
+        }
+    }
+}
+
 pub struct Link;
 
 impl Link {
     #[allow(clippy::type_complexity)]
     fn prepare(
-        tenant_id: Option<String>,
         client_id: &str,
-        clean: bool,
-        last_will: Option<LastWill>,
-        dynamic_filters: bool,
+        settings: LinkSettings,
     ) -> (
         Event,
         Arc<Mutex<VecDeque<Packet>>>,
@@ -52,15 +95,13 @@ impl Link {
         Receiver<()>,
         Receiver<MetricsReply>,
     ) {
-        let (connection, metrics_rx) = Connection::new(
-            tenant_id,
-            client_id.to_owned(),
-            clean,
-            last_will,
-            dynamic_filters,
-        );
         let incoming = Incoming::new(client_id.to_string());
-        let (outgoing, link_rx) = Outgoing::new(client_id.to_string());
+        let (outgoing, link_rx) = Outgoing::new(
+            client_id.to_string(),
+            settings.max_inflight,
+            settings.max_inflight_size,
+        );
+        let (connection, metrics_rx) = Connection::new(client_id.to_owned(), settings);
         let outgoing_data_buffer = outgoing.buffer();
         let incoming_data_buffer = incoming.buffer();
 
@@ -81,18 +122,14 @@ impl Link {
 
     #[allow(clippy::new_ret_no_self)]
     pub fn new(
-        tenant_id: Option<String>,
         client_id: &str,
         router_tx: Sender<(ConnectionId, Event)>,
-        clean: bool,
-        last_will: Option<LastWill>,
-        dynamic_filters: bool,
+        settings: LinkSettings,
     ) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
         // Connect to router
         // Local connections to the router shall have access to all subscriptions
 
-        let (message, i, o, link_rx, metrics_rx) =
-            Link::prepare(tenant_id, client_id, clean, last_will, dynamic_filters);
+        let (message, i, o, link_rx, metrics_rx) = Link::prepare(client_id, settings);
         router_tx.send((0, message))?;
 
         link_rx.recv()?;
@@ -101,6 +138,11 @@ impl Link {
         // Right now link identifies failure with dropped rx in router,
         // which is probably ok. We need this here to get id assigned by router
         let id = match notification {
+            Notification::DeviceAck(Ack::ConnAck(_, ack))
+                if ack.code != ConnectReturnCode::Success =>
+            {
+                return Err(LinkError::Refused(ack.code))
+            }
             Notification::DeviceAck(Ack::ConnAck(id, ..)) => id,
             _message => return Err(LinkError::NotConnectionAck),
         };
@@ -111,18 +153,14 @@ impl Link {
     }
 
     pub async fn init(
-        tenant_id: Option<String>,
         client_id: &str,
         router_tx: Sender<(ConnectionId, Event)>,
-        clean: bool,
-        last_will: Option<LastWill>,
-        dynamic_filters: bool,
+        settings: LinkSettings,
     ) -> Result<(LinkTx, LinkRx, ConnAck), LinkError> {
         // Connect to router
         // Local connections to the router shall have access to all subscriptions
 
-        let (message, i, o, link_rx, metrics_rx) =
-            Link::prepare(tenant_id, client_id, clean, last_will, dynamic_filters);
+        let (message, i, o, link_rx, metrics_rx) = Link::prepare(client_id, settings);
         router_tx.send_async((0, message)).await?;
 
         link_rx.recv_async().await?;
@@ -130,6 +168,11 @@ impl Link {
         // Right now link identifies failure with dropped rx in router,
         // which is probably ok. We need this here to get id assigned by router
         let (id, ack) = match notification {
+            Notification::DeviceAck(Ack::ConnAck(_, ack))
+                if ack.code != ConnectReturnCode::Success =>
+            {
+                return Err(LinkError::Refused(ack.code))
+            }
             Notification::DeviceAck(Ack::ConnAck(id, ack)) => (id, ack),
             _message => return Err(LinkError::NotConnectionAck),
         };
@@ -243,7 +286,7 @@ impl LinkTx {
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -259,7 +302,7 @@ impl LinkTx {
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -359,7 +402,12 @@ impl LinkRx {
         &mut self,
         notifications: &mut VecDeque<Notification>,
     ) -> Result<(), LinkError> {
-        self.router_rx.recv_async().await?;
+        // Notifications which came along with the connack, like PubRels of a
+        // resumed session, were already triggered. Don't wait for another trigger
+        if self.send_buffer.lock().is_empty() {
+            self.router_rx.recv_async().await?;
+        }
+
         mem::swap(&mut *self.send_buffer.lock(), notifications);
         Ok(())
     }
@@ -376,6 +424,11 @@ impl LinkRx {
         Ok(())
     }
 
+    /// Drops metrics replies which weren't received in time
+    pub(crate) fn discard_metrics(&self) {
+        while self.metrics_rx.try_recv().is_ok() {}
+    }
+
     pub fn metrics(&self) -> Option<MetricsReply> {
         self.metrics_rx
             .recv_deadline(Instant::now() + Duration::from_secs(1))
@@ -1,4 +1,5 @@
-// pub mod bridge;
+pub mod alias;
+pub mod bridge;
 pub mod console;
 pub mod local;
 pub mod network;
@@ -8,7 +8,7 @@ use std::{
 use tokio::time::{error::Elapsed, Duration};
 
 use crate::{
-    protocol::{self, Packet, Protocol},
+    protocol::{self, v4::V4, Packet, Protocol},
     Notification,
 };
 
@@ -65,6 +65,11 @@ impl<P: Protocol> Network<P> {
         self.keepalive = keepalive + keepalive.mul_f32(0.5);
     }
 
+    /// Time within which a packet is expected from the peer
+    pub fn keepalive(&self) -> Duration {
+        self.keepalive
+    }
+
     /// Reads more than 'required' bytes to frame a packet into self.read buffer
     async fn read_bytes(&mut self, required: usize) -> io::Result<usize> {
         // TODO: Fix this cancellation bug and write unit test
@@ -153,5 +158,15 @@ impl<P: Protocol> Network<P> {
     }
 }
 
+impl Network<V4> {
+    /// Writes a packet as a client of other broker
+    pub async fn write_packet(&mut self, packet: Packet) -> Result<(), Error> {
+        protocol::v4::write_packet(&packet, &mut self.write)?;
+        self.socket.write_all(&self.write).await?;
+        self.write.clear();
+        Ok(())
+    }
+}
+
 pub trait N: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 impl<T> N for T where T: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
@@ -1,16 +1,22 @@
-use crate::link::local::{LinkError, LinkRx, LinkTx};
+use crate::link::alias::TopicAliases;
+use crate::link::local::{LinkError, LinkRx, LinkSettings, LinkTx};
 use crate::link::network;
 use crate::link::network::Network;
-use crate::protocol::{Connect, Packet, Protocol};
-use crate::router::{Event, Notification};
+use crate::protocol::{
+    ConnAck, ConnAckProperties, Connect, DisconnectReasonCode, Packet, Protocol,
+};
+use crate::router::{Ack, Event, Notification, Quota};
+use crate::server::{AuthError, AuthHandler};
 use crate::{ConnectionId, ConnectionSettings, Link};
 
 use flume::{RecvError, SendError, Sender, TrySendError};
 use std::collections::VecDeque;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::error::Elapsed;
+use tokio::time::Instant;
 use tokio::{select, time};
 
 #[derive(Debug, thiserror::Error)]
@@ -35,10 +41,16 @@ pub enum Error {
     NotConnectionAck,
     #[error("ConnAck error {0}")]
     ConnectionAck(String),
+    #[error("Authentication error {0:?}")]
+    Auth(AuthError),
+    #[error("Invalid topic alias {0}")]
+    InvalidTopicAlias(u16),
     #[error("Channel try send error")]
     TrySend(#[from] TrySendError<(ConnectionId, Event)>),
     #[error("Link error = {0}")]
     Link(#[from] LinkError),
+    #[error("Disconnected by router. Reason = {0:?}")]
+    Disconnected(DisconnectReasonCode),
 }
 
 /// Orchestrates between Router and Network.
@@ -50,6 +62,9 @@ pub struct RemoteLink<P> {
     link_tx: LinkTx,
     link_rx: LinkRx,
     notifications: VecDeque<Notification>,
+    aliases: TopicAliases,
+    /// Pause in reading from the network after every batch of incoming packets
+    throttle: Duration,
 }
 
 impl<P: Protocol> RemoteLink<P> {
@@ -58,23 +73,35 @@ impl<P: Protocol> RemoteLink<P> {
         router_tx: Sender<(ConnectionId, Event)>,
         tenant_id: Option<String>,
         mut network: Network<P>,
+        addr: SocketAddr,
+        auth_handler: Option<Arc<dyn AuthHandler>>,
     ) -> Result<RemoteLink<P>, Error> {
         // Wait for MQTT connect packet and error out if it's not received in time to prevent
         // DOS attacks by filling total connections that the server can handle with idle open
         // connections which results in server rejecting new connections
         let connection_timeout_ms = config.connection_timeout_ms.into();
         let dynamic_filters = config.dynamic_filters;
+        let throttle = Duration::from_millis(config.throttle_delay_ms);
         let packet = time::timeout(Duration::from_millis(connection_timeout_ms), async {
             let packet = network.read().await?;
             Ok::<_, io::Error>(packet)
         })
         .await??;
 
-        let (connect, lastwill) = match packet {
-            Packet::Connect(connect, _, lastwill, ..) => (connect, lastwill),
+        let (mut connect, properties, lastwill, lastwill_properties, login) = match packet {
+            Packet::Connect(connect, properties, lastwill, lastwill_properties, login) => {
+                (connect, properties, lastwill, lastwill_properties, login)
+            }
             packet => return Err(Error::NotConnectPacket(packet)),
         };
 
+        // Keep alive assigned by the server replaces the one of the client. Only MQTT 5
+        // clients learn about it from connack, others keep their own keep alive
+        let server_keep_alive = config.server_keep_alive.filter(|_| P::PROPERTIES);
+        if let Some(keep_alive) = server_keep_alive {
+            connect.keep_alive = keep_alive;
+        }
+
         // When keep_alive feature is disabled client can live forever, which is not good in
         // distributed broker context so currenlty we don't allow it.
         if connect.keep_alive == 0 {
@@ -89,16 +116,102 @@ impl<P: Protocol> RemoteLink<P> {
             return Err(Error::InvalidClientId);
         }
 
-        let (link_tx, link_rx, notification) = Link::new(
+        // Reject the connection before registering with the router if authentication fails
+        if let Some(auth_handler) = auth_handler {
+            let username = login.as_ref().map(|l| l.username.as_str());
+            let password = login.as_ref().map(|l| l.password.as_str());
+            if let Err(e) = auth_handler.authenticate(&client_id, username, password, addr) {
+                let ack = ConnAck {
+                    session_present: false,
+                    code: e.code(),
+                };
+
+                let notification = Notification::DeviceAck(Ack::ConnAck(0, ack));
+                network.write(notification).await?;
+                return Err(Error::Auth(e));
+            }
+        }
+
+        // Session expiry of MQTT 5 clients, a missing interval ends the session with the
+        // connection. Maximum interval means the session never expires, same as sessions of
+        // MQTT 3.1.1 clients which aren't clean
+        let session_expiry_interval = match P::PROPERTIES {
+            true => properties
+                .as_ref()
+                .and_then(|p| p.session_expiry_interval)
+                .or(Some(0)),
+            false => None,
+        }
+        .filter(|interval| *interval != u32::MAX);
+
+        // Inflight publishes are limited by receive maximum of MQTT 5 clients
+        let max_inflight = properties
+            .as_ref()
+            .and_then(|p| p.receive_maximum)
+            .map_or(config.max_inflight_count, |max| {
+                max.min(config.max_inflight_count)
+            });
+
+        let topic_alias_max = config.topic_alias_max;
+        let client_topic_alias_max = properties.and_then(|p| p.topic_alias_max).unwrap_or(0);
+        let aliases = TopicAliases::new(topic_alias_max, client_topic_alias_max);
+
+        // Publishes over the limit are rejected when acks can say so. Clients
+        // without reason codes are slowed down instead
+        let quota = Quota {
+            limit: config.rate_limit,
+            reject: P::REASON_CODES,
+        };
+
+        let settings = LinkSettings {
             tenant_id,
-            &client_id,
-            router_tx,
-            clean_session,
-            lastwill,
+            username: login.map(|l| l.username),
+            clean: clean_session,
+            session_expiry_interval,
+            last_will: lastwill,
+            last_will_properties: lastwill_properties,
             dynamic_filters,
-        )?;
+            max_inflight,
+            max_inflight_size: config.max_inflight_size,
+            quota: Some(quota),
+            local: false,
+        };
+
+        let link = Link::new(&client_id, router_tx, settings);
+
+        // Client is told why the router refused the connection before the link stops
+        let (link_tx, link_rx, notification) = match link {
+            Ok(v) => v,
+            Err(LinkError::Refused(code)) => {
+                let ack = ConnAck {
+                    session_present: false,
+                    code,
+                };
+
+                let notification = Notification::DeviceAck(Ack::ConnAck(0, ack));
+                network.write(notification).await?;
+                return Err(LinkError::Refused(code).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
         let id = link_rx.id();
 
+        // Advertise maximum topic alias and keep alive assigned by the server to the client
+        let notification = match notification {
+            Notification::DeviceAck(Ack::ConnAck(id, ack))
+                if topic_alias_max > 0 || server_keep_alive.is_some() =>
+            {
+                let properties = ConnAckProperties {
+                    topic_alias_max: Some(topic_alias_max).filter(|max| *max > 0),
+                    server_keep_alive,
+                    ..Default::default()
+                };
+
+                Notification::DeviceAck(Ack::ConnAckWithProperties(id, ack, properties))
+            }
+            notification => notification,
+        };
+
         network.write(notification).await?;
 
         Ok(RemoteLink {
@@ -109,33 +222,99 @@ impl<P: Protocol> RemoteLink<P> {
             link_tx,
             link_rx,
             notifications: VecDeque::with_capacity(100),
+            aliases,
+            throttle,
         })
     }
 
     pub async fn start(&mut self) -> Result<(), Error> {
         self.network.set_keepalive(self.connect.keep_alive);
+        let keepalive = self.network.keepalive();
+        let mut deadline = Instant::now() + keepalive;
+        let mut resume = None;
+        // Router held back packets for being over quota and waits for a notify
+        let mut held = false;
 
         // Note:
         // Shouldn't result in bounded queue deadlocks because of blocking n/w send
         loop {
             select! {
-                o = self.network.read() => {
-                    let packet = o?;
-                    let len = {
+                // Connection is dead when nothing is read within keep alive. Writes don't
+                // extend the deadline as they succeed on half open connections
+                o = time::timeout_at(deadline, self.network.read()), if resume.is_none() => {
+                    let packet = o.map_err(network::Error::KeepAlive)??;
+                    deadline = Instant::now() + keepalive;
+                    if !self.throttle.is_zero() {
+                        resume = Some(Instant::now() + self.throttle);
+                    }
+
+                    let (len, resolved) = {
                         let mut buffer = self.link_tx.buffer();
+                        let start = buffer.len();
                         buffer.push_back(packet);
                         self.network.readv(&mut buffer)?;
-                        buffer.len()
+
+                        // Resolve topic aliases before publishes reach the router
+                        let aliases = &mut self.aliases;
+                        let resolved = buffer.iter_mut().skip(start).try_for_each(|packet| {
+                            match packet {
+                                Packet::Publish(publish, properties) => {
+                                    aliases.incoming(publish, properties)
+                                }
+                                _ => Ok(()),
+                            }
+                        });
+
+                        (buffer.len(), resolved)
                     };
 
+                    // Client is told about the protocol error before the link closes
+                    if let Err(alias) = resolved {
+                        let reason = DisconnectReasonCode::TopicAliasInvalid;
+                        self.network.write(Notification::Disconnect(reason)).await?;
+                        return Err(Error::InvalidTopicAlias(alias));
+                    }
+
                     debug!("{:15.15}[I] {:20} buffercount = {}", self.client_id, "packets", len);
                     self.link_tx.notify().await?;
                 }
+                // Packets which arrive while throttled wait in the socket. Keep alive
+                // deadline doesn't hit them as timeout polls the read first
+                _ = time::sleep_until(resume.unwrap_or(deadline)), if resume.is_some() => {
+                    resume = None;
+                    // Held packets are handed to the router again. They were read from
+                    // the client, so keep alive counts from now
+                    if held {
+                        held = false;
+                        deadline = Instant::now() + keepalive;
+                        self.link_tx.notify().await?;
+                    }
+                }
                 // Receive from router when previous when state isn't in collision
                 // due to previously received data request
                 o = self.link_rx.exchange(&mut self.notifications) => {
                     o?;
+                    let mut disconnect = None;
+                    for notification in self.notifications.iter_mut() {
+                        if let Notification::Disconnect(reason)
+                        | Notification::DisconnectWithProperties(reason, _) = notification
+                        {
+                            disconnect = Some(*reason);
+                        }
+
+                        if let Notification::Throttle(delay) = notification {
+                            resume = resume.max(Some(Instant::now() + *delay));
+                            held = true;
+                        }
+
+                        self.aliases.outgoing(notification);
+                    }
+
                     let unscheduled = self.network.writev(&mut self.notifications).await?;
+                    if let Some(reason) = disconnect {
+                        return Err(Error::Disconnected(reason));
+                    }
+
                     if unscheduled {
                         self.link_rx.wake().await?;
                     }
@@ -144,3 +323,100 @@ impl<P: Protocol> RemoteLink<P> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Error, RemoteLink};
+    use crate::link::local::{Link, LinkError};
+    use crate::link::network::Network;
+    use crate::protocol::{v5::V5, ConnectReturnCode, DisconnectReasonCode};
+    use crate::router::{Event, Router};
+    use crate::{ConnectionId, ConnectionSettings, RouterConfig};
+    use flume::Sender;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    /// MQTT 5 clean start connect of client `a` with a keep alive of 60 seconds
+    const CONNECT: [u8; 16] = [
+        0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 5, 2, 0, 60, 0, 0, 1, b'a',
+    ];
+
+    fn router(max_connections: usize) -> Sender<(ConnectionId, Event)> {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            max_connections,
+            ..Default::default()
+        };
+
+        let (_router, router_tx) = Router::new(0, config).spawn();
+        router_tx
+    }
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            connection_timeout_ms: 1000,
+            throttle_delay_ms: 0,
+            max_payload_size: 1024,
+            max_inflight_count: 10,
+            max_inflight_size: 1024,
+            dynamic_filters: false,
+            topic_alias_max: 10,
+            server_keep_alive: None,
+            rate_limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_topic_alias_disconnects_with_reason() {
+        let router_tx = router(10);
+        let settings = Arc::new(settings());
+
+        // Clean session connect followed by a publish with an alias which was never set
+        let (mut client, server) = duplex(1024);
+        let publish = [0x30, 7, 0, 0, 3, 0x23, 0, 3, b'x'];
+        client.write_all(&CONNECT).await.unwrap();
+        client.write_all(&publish).await.unwrap();
+
+        let network = Network::new(Box::new(server), 1024, 10, V5);
+        let addr = "127.0.0.1:1883".parse().unwrap();
+        let mut link = RemoteLink::new(settings, router_tx, None, network, addr, None)
+            .await
+            .unwrap();
+        assert!(link.start().await.is_err());
+        drop(link);
+
+        let mut read = Vec::new();
+        client.read_to_end(&mut read).await.unwrap();
+        let (connack, disconnect) = read.split_at(read[1] as usize + 2);
+        assert_eq!(connack[0], 0x20);
+        assert_eq!(disconnect[0], 0xE0);
+        assert_eq!(disconnect[2], DisconnectReasonCode::TopicAliasInvalid as u8);
+    }
+
+    #[tokio::test]
+    async fn connections_over_limit_are_refused_with_server_busy() {
+        let router_tx = router(1);
+        let _device = Link::new("device", router_tx.clone(), Default::default()).unwrap();
+
+        let (mut client, server) = duplex(1024);
+        client.write_all(&CONNECT).await.unwrap();
+
+        let network = Network::new(Box::new(server), 1024, 10, V5);
+        let addr = "127.0.0.1:1883".parse().unwrap();
+        let link = RemoteLink::new(Arc::new(settings()), router_tx, None, network, addr, None);
+        match timeout(Duration::from_secs(5), link).await.unwrap() {
+            Err(Error::Link(LinkError::Refused(ConnectReturnCode::ServiceUnavailable))) => (),
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => panic!("Connection over limit was accepted"),
+        }
+
+        let mut read = Vec::new();
+        client.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read[0], 0x20);
+        assert_eq!(read[3], 0x89);
+    }
+}
@@ -1,5 +1,8 @@
-use crate::link::local::{LinkError, LinkRx, LinkTx};
+use crate::link::local::{LinkError, LinkRx, LinkSettings, LinkTx};
+use crate::protocol::v4::connect_code;
+use crate::protocol::ConnectReturnCode;
 use crate::router::{Event, Notification};
+use crate::server::{AuthError, AuthHandler};
 use crate::{ConnectionId, ConnectionSettings, Filter, Link};
 use bytes::Bytes;
 use flume::{RecvError, SendError, Sender, TrySendError};
@@ -9,6 +12,7 @@ use serde_json::Value;
 use std::borrow::Cow;
 use std::collections::HashSet;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::error::Elapsed;
@@ -44,6 +48,8 @@ pub enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("Shadow filter not set properly")]
     InvalidFilter,
+    #[error("Authentication error {0:?}")]
+    Auth(AuthError),
 }
 
 pub struct ShadowLink {
@@ -60,6 +66,8 @@ impl ShadowLink {
         config: Arc<ConnectionSettings>,
         router_tx: Sender<(ConnectionId, Event)>,
         stream: Box<dyn N>,
+        addr: SocketAddr,
+        auth_handler: Option<Arc<dyn AuthHandler>>,
     ) -> Result<ShadowLink, Error> {
         // Connect to router
         let mut network = Network::new(stream).await?;
@@ -71,18 +79,34 @@ impl ShadowLink {
         let subscriptions = HashSet::new();
         let client_id = connect.client_id.clone();
 
-        let (link_tx, link_rx, _ack) = Link::new(
-            None,
-            &client_id,
-            router_tx,
-            true,
-            None,
-            config.dynamic_filters,
-        )?;
+        // Reject the connection before registering with the router if authentication fails
+        if let Err(e) = authenticate(&connect, auth_handler, addr) {
+            network.connack(e.code()).await?;
+            return Err(Error::Auth(e));
+        }
+
+        let settings = LinkSettings {
+            dynamic_filters: config.dynamic_filters,
+            max_inflight: config.max_inflight_count,
+            max_inflight_size: config.max_inflight_size,
+            ..Default::default()
+        };
+
+        let link = Link::new(&client_id, router_tx, settings);
+
+        // Client is told why the router refused the connection before the link stops
+        let (link_tx, link_rx, _ack) = match link {
+            Ok(v) => v,
+            Err(LinkError::Refused(code)) => {
+                network.connack(code).await?;
+                return Err(LinkError::Refused(code).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
         let connection_id = link_rx.id();
 
         // Send connection acknowledgement back to the client
-        network.connack().await?;
+        network.connack(ConnectReturnCode::Success).await?;
         Ok(ShadowLink {
             client_id,
             connection_id,
@@ -135,7 +159,7 @@ impl ShadowLink {
 
                     let message = match message {
                         // TODO: Differentiate pushes and pulls in router
-                        Notification::Forward(_forward) => {
+                        Notification::Forward(_) | Notification::ForwardWithProperties(..) => {
                             continue
                         },
                         Notification::DeviceAck(ack) => {
@@ -147,6 +171,8 @@ impl ShadowLink {
                             let publish = Outgoing::Publish { topic, data: shadow.payload };
                             Message::Text(serde_json::to_string(&publish)?)
                         }
+                        Notification::Disconnect(_)
+                        | Notification::DisconnectWithProperties(..) => return Ok(()),
                         v => unreachable!("Expecting only data or device acks. Received = {:?}", v)
                     };
 
@@ -202,6 +228,23 @@ impl ShadowLink {
     }
 }
 
+/// Authenticates the connect message with the credentials in it, the same way
+/// MQTT connections are authenticated
+fn authenticate(
+    connect: &Connect,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
+    addr: SocketAddr,
+) -> Result<(), AuthError> {
+    let auth_handler = match auth_handler {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    let username = connect.username.as_deref();
+    let password = connect.password.as_deref();
+    auth_handler.authenticate(&connect.client_id, username, password, addr)
+}
+
 /// Validates that the fields `tenant_id` and `device_id` are the same as that of device connected
 /// for a topic filter of format "/tenant/tenant_id/device/device_id/..."
 /// Note: Tenant not checked, but could be, in the future
@@ -232,6 +275,10 @@ pub struct Connect {
     client_id: String,
     tenant_id: Option<String>,
     body: Option<Jwt>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -247,8 +294,9 @@ pub struct Jwt {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 #[serde(tag = "type")]
 pub enum Outgoing {
+    /// Code is the MQTT 3.1.1 return code. Status is false when it isn't 0
     #[serde(alias = "connack")]
-    ConnAck { status: bool },
+    ConnAck { status: bool, code: u8 },
     #[serde(alias = "publish")]
     Publish { topic: String, data: Bytes },
     #[serde(alias = "pong")]
@@ -329,8 +377,11 @@ impl Network {
         Ok(message)
     }
 
-    pub async fn connack(&mut self) -> Result<(), Error> {
-        let ack = Outgoing::ConnAck { status: true };
+    pub async fn connack(&mut self, code: ConnectReturnCode) -> Result<(), Error> {
+        let ack = Outgoing::ConnAck {
+            status: code == ConnectReturnCode::Success,
+            code: connect_code(code),
+        };
         let message = Message::Text(serde_json::to_string(&ack)?);
         self.socket.send(message).await?;
         Ok(())
@@ -341,3 +392,52 @@ impl Network {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{authenticate, Connect, Outgoing};
+    use crate::protocol::v4::connect_code;
+    use crate::server::{AuthError, AuthHandler};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn websocket_connections_are_authenticated() {
+        let handler =
+            |_: &str, username: Option<&str>, password: Option<&str>, _: SocketAddr| match (
+                username, password,
+            ) {
+                (Some("alice"), Some("secret")) => Ok(()),
+                (Some(_), _) => Err(AuthError::BadUserNamePassword),
+                (None, _) => Err(AuthError::NotAuthorized),
+            };
+
+        let handler: Arc<dyn AuthHandler> = Arc::new(handler);
+        let addr = "127.0.0.1:1883".parse().unwrap();
+        let connect = |json: &str| serde_json::from_str::<Connect>(json).unwrap();
+
+        let valid = connect(r#"{"client_id":"c","username":"alice","password":"secret"}"#);
+        let wrong = connect(r#"{"client_id":"c","username":"alice","password":"wrong"}"#);
+        let anonymous = connect(r#"{"client_id":"c"}"#);
+
+        assert_eq!(authenticate(&valid, Some(handler.clone()), addr), Ok(()));
+        assert_eq!(
+            authenticate(&wrong, Some(handler.clone()), addr),
+            Err(AuthError::BadUserNamePassword)
+        );
+        assert_eq!(
+            authenticate(&anonymous, Some(handler), addr),
+            Err(AuthError::NotAuthorized)
+        );
+        assert_eq!(authenticate(&anonymous, None, addr), Ok(()));
+
+        // Refusals carry the same return codes as MQTT 3.1.1 connacks
+        let ack = Outgoing::ConnAck {
+            status: false,
+            code: connect_code(AuthError::BadUserNamePassword.code()),
+        };
+
+        let ack = serde_json::to_string(&ack).unwrap();
+        assert_eq!(ack, r#"{"type":"ConnAck","status":false,"code":4}"#);
+    }
+}
@@ -1,4 +1,4 @@
-use rumqttd::Broker;
+use rumqttd::{Broker, Config};
 
 use simplelog::{
     Color, ColorChoice, CombinedLogger, Level, LevelFilter, LevelPadding, TargetPadding,
@@ -37,19 +37,52 @@ fn main() {
     banner(&commandline);
     initialize_logging(&commandline);
 
-    let config = config::Config::builder()
-        .add_source(config::File::with_name(&commandline.config))
-        .build()
-        .unwrap();
-
-    let config = config.try_deserialize().unwrap();
+    let config = load_config(&commandline.config).unwrap();
 
     println!("{:#?}", config);
 
     let mut broker = Broker::new(config);
+    let path = commandline.config.clone();
+    broker.set_config_loader(move || Ok(load_config(&path)?));
+
+    #[cfg(unix)]
+    reload_on_sighup(broker.handle());
+
     broker.start().unwrap();
 }
 
+fn load_config(path: &str) -> Result<Config, config::ConfigError> {
+    let config = config::Config::builder()
+        .add_source(config::File::with_name(path))
+        .build()?;
+
+    config.try_deserialize()
+}
+
+/// Reloads config file on SIGHUP
+#[cfg(unix)]
+fn reload_on_sighup(handle: rumqttd::BrokerHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Register the handler before broker starts so that early SIGHUPs
+    // don't kill the process
+    let mut hangup = runtime
+        .block_on(async { signal(SignalKind::hangup()) })
+        .unwrap();
+    std::thread::spawn(move || {
+        runtime.block_on(async {
+            while hangup.recv().await.is_some() {
+                handle.reload();
+            }
+        })
+    });
+}
+
 fn initialize_logging(commandline: &CommandLine) {
     let mut config = simplelog::ConfigBuilder::new();
 
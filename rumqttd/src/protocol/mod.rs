@@ -146,7 +146,7 @@ pub struct ConnAck {
     pub code: ConnectReturnCode,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ConnAckProperties {
     pub session_expiry_interval: Option<u32>,
     pub receive_max: Option<u16>,
@@ -206,30 +206,38 @@ impl Publish {
         o.freeze()
     }
 
-    /// Serialization which is independent of MQTT
-    pub fn deserialize(mut o: Bytes) -> Publish {
+    /// Serialization which is independent of MQTT. Errors out on truncated data
+    pub fn deserialize(mut o: Bytes) -> Result<Publish, Error> {
+        if o.len() < 5 {
+            return Err(Error::InsufficientBytes(5 - o.len()));
+        }
+
         let header = o.get_u8();
         let qos_num = (header & 0b0110) >> 1;
-        let qos = qos(qos_num).unwrap_or(QoS::AtMostOnce);
+        let qos = qos(qos_num).ok_or(Error::InvalidQoS(qos_num))?;
         let dup = (header & 0b1000) != 0;
         let retain = (header & 0b0001) != 0;
 
         let pkid = o.get_u16();
-        let topic_len = o.get_u16();
-        let topic = o.split_to(topic_len as usize);
+        let topic_len = o.get_u16() as usize;
+        if o.len() < topic_len {
+            return Err(Error::InsufficientBytes(topic_len - o.len()));
+        }
+
+        let topic = o.split_to(topic_len);
         let payload = o;
-        Publish {
+        Ok(Publish {
             dup,
             qos,
             retain,
             topic,
             pkid,
             payload,
-        }
+        })
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct PublishProperties {
     pub payload_format_indicator: Option<u8>,
     pub message_expiry_interval: Option<u32>,
@@ -383,65 +391,81 @@ pub struct UnsubAckProperties {
 
 //--------------------------- Disconnect packet -------------------------------
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
 pub enum DisconnectReasonCode {
     /// Close the connection normally. Do not send the Will Message.
-    NormalDisconnection,
+    NormalDisconnection = 0x00,
     /// The Client wishes to disconnect but requires that the Server also publishes its Will Message.
-    DisconnectWithWillMessage,
+    DisconnectWithWillMessage = 0x04,
     /// The Connection is closed but the sender either does not wish to reveal the reason, or none of the other Reason Codes apply.
-    UnspecifiedError,
+    UnspecifiedError = 0x80,
     /// The received packet does not conform to this specification.
-    MalformedPacket,
+    MalformedPacket = 0x81,
     /// An unexpected or out of order packet was received.
-    ProtocolError,
+    ProtocolError = 0x82,
     /// The packet received is valid but cannot be processed by this implementation.
-    ImplementationSpecificError,
+    ImplementationSpecificError = 0x83,
     /// The request is not authorized.
-    NotAuthorized,
+    NotAuthorized = 0x87,
     /// The Server is busy and cannot continue processing requests from this Client.
-    ServerBusy,
+    ServerBusy = 0x89,
     /// The Server is shutting down.
-    ServerShuttingDown,
+    ServerShuttingDown = 0x8B,
     /// The Connection is closed because no packet has been received for 1.5 times the Keepalive time.
-    KeepAliveTimeout,
+    KeepAliveTimeout = 0x8D,
     /// Another Connection using the same ClientID has connected causing this Connection to be closed.
-    SessionTakenOver,
+    SessionTakenOver = 0x8E,
     /// The Topic Filter is correctly formed, but is not accepted by this Sever.
-    TopicFilterInvalid,
+    TopicFilterInvalid = 0x8F,
     /// The Topic Name is correctly formed, but is not accepted by this Client or Server.
-    TopicNameInvalid,
+    TopicNameInvalid = 0x90,
     /// The Client or Server has received more than Receive Maximum publication for which it has not sent PUBACK or PUBCOMP.
-    ReceiveMaximumExceeded,
+    ReceiveMaximumExceeded = 0x93,
     /// The Client or Server has received a PUBLISH packet containing a Topic Alias which is greater than the Maximum Topic Alias it sent in the CONNECT or CONNACK packet.
-    TopicAliasInvalid,
+    TopicAliasInvalid = 0x94,
     /// The packet size is greater than Maximum Packet Size for this Client or Server.
-    PacketTooLarge,
+    PacketTooLarge = 0x95,
     /// The received data rate is too high.
-    MessageRateTooHigh,
+    MessageRateTooHigh = 0x96,
     /// An implementation or administrative imposed limit has been exceeded.
-    QuotaExceeded,
+    QuotaExceeded = 0x97,
     /// The Connection is closed due to an administrative action.
-    AdministrativeAction,
+    AdministrativeAction = 0x98,
     /// The payload format does not match the one specified by the Payload Format Indicator.
-    PayloadFormatInvalid,
+    PayloadFormatInvalid = 0x99,
     /// The Server has does not support retained messages.
-    RetainNotSupported,
+    RetainNotSupported = 0x9A,
     /// The Client specified a QoS greater than the QoS specified in a Maximum QoS in the CONNACK.
-    QoSNotSupported,
+    QoSNotSupported = 0x9B,
     /// The Client should temporarily change its Server.
-    UseAnotherServer,
+    UseAnotherServer = 0x9C,
     /// The Server is moved and the Client should permanently change its server location.
-    ServerMoved,
+    ServerMoved = 0x9D,
     /// The Server does not support Shared Subscriptions.
-    SharedSubscriptionNotSupported,
+    SharedSubscriptionNotSupported = 0x9E,
     /// This connection is closed because the connection rate is too high.
-    ConnectionRateExceeded,
+    ConnectionRateExceeded = 0x9F,
     /// The maximum connection time authorized for this connection has been exceeded.
-    MaximumConnectTime,
+    MaximumConnectTime = 0xA0,
     /// The Server does not support Subscription Identifiers; the subscription is not accepted.
-    SubscriptionIdentifiersNotSupported,
+    SubscriptionIdentifiersNotSupported = 0xA1,
     /// The Server does not support Wildcard subscription; the subscription is not accepted.
-    WildcardSubscriptionsNotSupported,
+    WildcardSubscriptionsNotSupported = 0xA2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectProperties {
+    /// Session Expiry Interval in seconds
+    pub session_expiry_interval: Option<u32>,
+
+    /// Human readable reason for the disconnect
+    pub reason_string: Option<String>,
+
+    /// List of user properties
+    pub user_properties: Vec<(String, String)>,
+
+    /// String which can be used by the Client to identify another Server to use.
+    pub server_reference: Option<String>,
 }
 
 //--------------------------- Ping packet -------------------------------
@@ -606,7 +630,8 @@ pub fn valid_filter(filter: &str) -> bool {
 /// **NOTE**: make sure a topic is validated during a publish and filter is validated
 /// during a subscribe
 pub fn matches(topic: &str, filter: &str) -> bool {
-    if !topic.is_empty() && topic[..1].contains('$') {
+    // Topics starting with '$' (like $SYS) aren't matched by filters starting with a wildcard
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
         return false;
     }
 
@@ -697,6 +722,13 @@ pub enum Error {
 }
 
 pub trait Protocol {
+    /// Acks carry reason codes. Publishes over quota are rejected instead of
+    /// delayed when they do
+    const REASON_CODES: bool = false;
+    /// Packets carry MQTT 5 properties. Session expiry and keep alive assigned
+    /// by the server are negotiated through them
+    const PROPERTIES: bool = false;
+
     fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet, Error>;
     fn write(&self, notification: Notification, write: &mut BytesMut) -> Result<bool, Error>;
 }
@@ -47,7 +47,7 @@ fn connect_return(num: u8) -> Result<ConnectReturnCode, Error> {
     }
 }
 
-fn connect_code(return_code: ConnectReturnCode) -> u8 {
+pub(crate) fn connect_code(return_code: ConnectReturnCode) -> u8 {
     match return_code {
         ConnectReturnCode::Success => 0,
         ConnectReturnCode::RefusedProtocolVersion => 1,
@@ -22,6 +22,9 @@ mod subscribe;
 mod unsuback;
 mod unsubscribe;
 
+/// MQTT 3.1.1 return code in connack. Also used by websocket connections
+pub(crate) use connack::connect_code;
+
 /// MQTT packet type
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -282,6 +285,27 @@ fn len_len(len: usize) -> usize {
     }
 }
 
+/// Writes any packet. Server links write router notifications with `Protocol::write`,
+/// this is for links which talk to other brokers as a client, like bridges
+pub fn write_packet(packet: &Packet, buffer: &mut BytesMut) -> Result<usize, Error> {
+    match packet {
+        Packet::Connect(connect, _, will, _, login) => connect::write(connect, login, will, buffer),
+        Packet::ConnAck(connack) => connack::write(connack, buffer),
+        Packet::Publish(publish, _) => publish::write(publish, buffer),
+        Packet::PubAck(puback, _) => puback::write(puback, buffer),
+        Packet::PingReq(_) => ping::pingreq::write(buffer),
+        Packet::PingResp(_) => ping::pingresp::write(buffer),
+        Packet::Subscribe(subscribe, _) => subscribe::write(subscribe, buffer),
+        Packet::SubAck(suback, _) => suback::write(suback, buffer),
+        Packet::PubRec(pubrec, _) => pubrec::write(pubrec, buffer),
+        Packet::PubRel(pubrel, _) => pubrel::write(pubrel, buffer),
+        Packet::PubComp(pubcomp, _) => pubcomp::write(pubcomp, buffer),
+        Packet::Unsubscribe(unsubscribe) => unsubscribe::write(unsubscribe, buffer),
+        Packet::UnsubAck(unsuback) => unsuback::write(unsuback, buffer),
+        Packet::Disconnect => disconnect::Disconnect.write(buffer),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct V4;
 
@@ -338,33 +362,41 @@ impl Protocol for V4 {
             Notification::Forward(forward) => {
                 publish::write(&forward.publish, write)?;
             }
+            // MQTT 3.1.1 publishes don't have properties
+            Notification::ForwardWithProperties(forward, _) => {
+                publish::write(&forward.publish, write)?;
+            }
             Notification::DeviceAck(ack) => match ack {
-                Ack::ConnAck(_, ack) => {
+                Ack::ConnAck(_, ack) | Ack::ConnAckWithProperties(_, ack, _) => {
                     connack::write(&ack, write)?;
                 }
-                Ack::PubAck(puback) => {
+                // MQTT 3.1.1 acks don't have properties
+                Ack::PubAck(puback) | Ack::PubAckWithProperties(puback, _) => {
                     puback::write(&puback, write)?;
                 }
-                Ack::SubAck(suback) => {
+                Ack::SubAck(suback) | Ack::SubAckWithProperties(suback, _) => {
                     suback::write(&suback, write)?;
                 }
                 Ack::PingResp(pingresp) => {
                     ping::pingresp::write(write)?;
                 }
-                Ack::PubRec(pubrec) => {
+                Ack::PubRec(pubrec) | Ack::PubRecWithProperties(pubrec, _) => {
                     pubrec::write(&pubrec, write)?;
                 }
-                Ack::PubRel(pubrel) => {
+                Ack::PubRel(pubrel) | Ack::PubRelWithProperties(pubrel, _) => {
                     pubrel::write(&pubrel, write)?;
                 }
-                Ack::PubComp(pubcomp) => {
+                Ack::PubComp(pubcomp) | Ack::PubCompWithProperties(pubcomp, _) => {
                     pubcomp::write(&pubcomp, write)?;
                 }
-                Ack::UnsubAck(unsuback) => {
+                Ack::UnsubAck(unsuback) | Ack::UnsubAckWithProperties(unsuback, _) => {
                     unsuback::write(&unsuback, write)?;
                 }
-                _ => unimplemented!(),
             },
+            // MQTT 3.1.1 servers close the connection without a disconnect packet
+            Notification::Disconnect(_) | Notification::DisconnectWithProperties(..) => {}
+            // Handled by the link
+            Notification::Throttle(_) => {}
             Notification::Unschedule => return Ok(true),
             v => unreachable!("{:?}", v),
         }
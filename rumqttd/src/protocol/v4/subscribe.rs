@@ -61,7 +61,8 @@ mod filter {
                 qos: qos(requested_qos).ok_or(Error::InvalidQoS(requested_qos))?,
                 nolocal: false,
                 preserve_retain: false,
-                retain_forward_rule: RetainForwardRule::Never,
+                // Retained publishes are sent on every v4 subscribe
+                retain_forward_rule: RetainForwardRule::OnEverySubscribe,
             });
         }
 
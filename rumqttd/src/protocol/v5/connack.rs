@@ -417,6 +417,8 @@ fn connect_code(return_code: ConnectReturnCode) -> u8 {
         ConnectReturnCode::UseAnotherServer => 156,
         ConnectReturnCode::ServerMoved => 157,
         ConnectReturnCode::ConnectionRateExceeded => 159,
+        // Router refuses connections with the v4 code when it is full
+        ConnectReturnCode::ServiceUnavailable => 137,
         _ => unreachable!(),
     }
 }
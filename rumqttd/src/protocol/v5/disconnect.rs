@@ -110,21 +110,6 @@ impl TryFrom<u8> for DisconnectReasonCode {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct DisconnectProperties {
-    /// Session Expiry Interval in seconds
-    pub session_expiry_interval: Option<u32>,
-
-    /// Human readable reason for the disconnect
-    pub reason_string: Option<String>,
-
-    /// List of user properties
-    pub user_properties: Vec<(String, String)>,
-
-    /// String which can be used by the Client to identify another Server to use.
-    pub server_reference: Option<String>,
-}
-
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Disconnect {
     /// Disconnect Reason Code
@@ -323,10 +308,9 @@ impl Disconnect {
 
         buffer.put_u8(self.reason_code as u8);
 
+        // Property length is left out along with properties when there are none
         if let Some(properties) = &self.properties {
             properties.write(buffer)?;
-        } else {
-            write_remaining_length(buffer, 0)?;
         }
 
         Ok(1 + len_len + length)
@@ -373,6 +357,25 @@ mod test {
         assert_eq!(&buffer[..], &expected);
     }
 
+    #[test]
+    fn disconnect_with_only_reason_encoding_works() {
+        let mut buffer = BytesMut::new();
+        let disconnect = Disconnect {
+            reason_code: DisconnectReasonCode::ServerShuttingDown,
+            properties: None,
+        };
+
+        let expected = [
+            0xE0, // Packet type
+            0x01, // Remaining length
+            0x8B, // Disconnect Reason Code
+        ];
+
+        disconnect.write(&mut buffer).unwrap();
+
+        assert_eq!(&buffer[..], &expected);
+    }
+
     fn sample2() -> Disconnect {
         let properties = DisconnectProperties {
             // TODO: change to 2137 xD
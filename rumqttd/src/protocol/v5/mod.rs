@@ -11,7 +11,7 @@ mod disconnect;
 mod ping;
 mod puback;
 mod pubcomp;
-mod publish;
+pub(crate) mod publish;
 mod pubrec;
 mod pubrel;
 mod suback;
@@ -313,7 +313,7 @@ fn write_remaining_length(stream: &mut BytesMut, len: usize) -> Result<usize, Er
 }
 
 /// Return number of remaining length bytes required for encoding length
-fn len_len(len: usize) -> usize {
+pub(crate) fn len_len(len: usize) -> usize {
     if len >= 2_097_152 {
         4
     } else if len >= 16_384 {
@@ -358,6 +358,9 @@ fn read_u32(stream: &mut Bytes) -> Result<u32, Error> {
 pub struct V5;
 
 impl Protocol for V5 {
+    const REASON_CODES: bool = true;
+    const PROPERTIES: bool = true;
+
     /// Reads a stream of bytes and extracts next MQTT packet out of it
     fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet, Error> {
         let fixed_header = check(stream.iter(), max_size)?;
@@ -399,6 +402,22 @@ impl Protocol for V5 {
                 let (suback, properties) = suback::read(fixed_header, packet)?;
                 Packet::SubAck(suback, properties)
             }
+            PacketType::PubRec => {
+                let (pubrec, properties) = pubrec::read(fixed_header, packet)?;
+                Packet::PubRec(pubrec, properties)
+            }
+            PacketType::PubRel => {
+                let (pubrel, properties) = pubrel::read(fixed_header, packet)?;
+                Packet::PubRel(pubrel, properties)
+            }
+            PacketType::PubComp => {
+                let (pubcomp, properties) = pubcomp::read(fixed_header, packet)?;
+                Packet::PubComp(pubcomp, properties)
+            }
+            PacketType::Unsubscribe => {
+                let (unsubscribe, _properties) = unsubscribe::read(fixed_header, packet)?;
+                Packet::Unsubscribe(unsubscribe)
+            }
             PacketType::PingReq => Packet::PingReq(PingReq),
             PacketType::PingResp => Packet::PingResp(PingResp),
             PacketType::Disconnect => Packet::Disconnect,
@@ -420,6 +439,9 @@ impl Protocol for V5 {
                 Ack::ConnAck(_, ack) => {
                     connack::write(&ack, &None, write)?;
                 }
+                Ack::ConnAckWithProperties(_, ack, properties) => {
+                    connack::write(&ack, &Some(properties), write)?;
+                }
                 Ack::PubAck(ack) => {
                     puback::write(&ack, &None, write)?;
                 }
@@ -453,8 +475,31 @@ impl Protocol for V5 {
                 Ack::PubCompWithProperties(pubcomp, properties) => {
                     pubcomp::write(&pubcomp, &Some(properties), write)?;
                 }
-                _ => unimplemented!(),
+                Ack::UnsubAck(unsuback) => {
+                    unsuback::write(&unsuback, &None, write)?;
+                }
+                Ack::UnsubAckWithProperties(unsuback, properties) => {
+                    unsuback::write(&unsuback, &Some(properties), write)?;
+                }
             },
+            Notification::Disconnect(reason) => {
+                let disconnect = disconnect::Disconnect {
+                    reason_code: (reason as u8).try_into()?,
+                    properties: None,
+                };
+
+                disconnect.write(write)?;
+            }
+            Notification::DisconnectWithProperties(reason, properties) => {
+                let disconnect = disconnect::Disconnect {
+                    reason_code: (reason as u8).try_into()?,
+                    properties: Some(properties),
+                };
+
+                disconnect.write(write)?;
+            }
+            // Handled by the link
+            Notification::Throttle(_) => {}
             Notification::Unschedule => return Ok(true),
             v => unreachable!("{:?}", v),
         }
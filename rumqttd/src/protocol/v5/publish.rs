@@ -92,7 +92,8 @@ pub fn write(
     Ok(1 + count + len)
 }
 
-mod properties {
+/// Also used to store properties of publishes in commitlog
+pub(crate) mod properties {
     use super::*;
 
     pub fn len(properties: &PublishProperties) -> usize {
@@ -0,0 +1,215 @@
+use crate::{AclAction, AclPermission, AclRule};
+
+/// Checks if a client is allowed to publish on `topic`. Rules are evaluated in
+/// order and the first one which matches decides. Publishes which don't match
+/// any rule are denied
+pub fn can_publish(
+    rules: &[AclRule],
+    client_id: &str,
+    username: Option<&str>,
+    topic: &str,
+) -> bool {
+    for rule in rules.iter().filter(|r| r.action != AclAction::Subscribe) {
+        let filter = match substitute(rule, client_id, username) {
+            Some(filter) => filter,
+            None => continue,
+        };
+
+        if covers(&filter, topic) {
+            return rule.permission == AclPermission::Allow;
+        }
+    }
+
+    false
+}
+
+/// Checks if a client is allowed to subscribe to `filter`. An allow rule only
+/// matches when it covers all the topics of the subscription, while a deny
+/// rule matches when it shares any topic with the subscription
+pub fn can_subscribe(
+    rules: &[AclRule],
+    client_id: &str,
+    username: Option<&str>,
+    filter: &str,
+) -> bool {
+    for rule in rules.iter().filter(|r| r.action != AclAction::Publish) {
+        let rule_filter = match substitute(rule, client_id, username) {
+            Some(filter) => filter,
+            None => continue,
+        };
+
+        match rule.permission {
+            AclPermission::Allow if covers(&rule_filter, filter) => return true,
+            AclPermission::Deny if overlaps(&rule_filter, filter) => return false,
+            _ => continue,
+        }
+    }
+
+    false
+}
+
+/// Filter of the rule with `%c` and `%u` substituted. None if the rule doesn't
+/// apply to this client
+fn substitute(rule: &AclRule, client_id: &str, username: Option<&str>) -> Option<String> {
+    if matches!(&rule.client_id, Some(id) if id != client_id) {
+        return None;
+    }
+
+    if rule.username.is_some() && rule.username.as_deref() != username {
+        return None;
+    }
+
+    // Wildcards or levels in substituted values would widen the filter. Such
+    // clients don't get allowed by the rule and deny rules deny them everything
+    let unsafe_value = |value: &str| value.contains(['+', '#', '/']);
+    let filter = &rule.filter;
+    if (filter.contains("%c") && unsafe_value(client_id))
+        || (filter.contains("%u") && username.is_some_and(unsafe_value))
+    {
+        return match rule.permission {
+            AclPermission::Allow => None,
+            AclPermission::Deny => Some("#".to_owned()),
+        };
+    }
+
+    let filter = filter.replace("%c", client_id);
+    match username {
+        Some(username) => Some(filter.replace("%u", username)),
+        None if filter.contains("%u") => None,
+        None => Some(filter),
+    }
+}
+
+/// Checks if every topic which matches `filter` also matches `rule`. Like in
+/// topic matching, wildcards at the first level don't cover `$` topics
+fn covers(rule: &str, filter: &str) -> bool {
+    if filter.starts_with('$') && rule.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut filters = filter.split('/');
+    for r in rule.split('/') {
+        if r == "#" {
+            return true;
+        }
+
+        match filters.next() {
+            Some("#") | None => return false,
+            Some("+") if r == "+" => continue,
+            Some("+") => return false,
+            Some(_) if r == "+" => continue,
+            Some(f) if f != r => return false,
+            Some(_) => continue,
+        }
+    }
+
+    filters.next().is_none()
+}
+
+/// Checks if there is a topic which matches both the filters
+fn overlaps(a: &str, b: &str) -> bool {
+    let mut a = a.split('/');
+    let mut b = b.split('/');
+    loop {
+        match (a.next(), b.next()) {
+            (Some("#"), _) | (_, Some("#")) => return true,
+            (Some(x), Some(y)) if x == "+" || y == "+" || x == y => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{can_publish, can_subscribe};
+    use crate::{AclAction, AclPermission, AclRule};
+
+    fn rule(username: Option<&str>, filter: &str, action: AclAction, allow: bool) -> AclRule {
+        AclRule {
+            client_id: None,
+            username: username.map(str::to_owned),
+            filter: filter.to_owned(),
+            action,
+            permission: match allow {
+                true => AclPermission::Allow,
+                false => AclPermission::Deny,
+            },
+        }
+    }
+
+    #[test]
+    fn publishes_are_checked_against_substituted_filters() {
+        let rules = vec![
+            rule(None, "devices/%c/#", AclAction::Publish, true),
+            rule(None, "users/%u/+", AclAction::All, true),
+            rule(Some("admin"), "#", AclAction::All, true),
+        ];
+
+        assert!(can_publish(&rules, "d1", None, "devices/d1/temp"));
+        assert!(!can_publish(&rules, "d1", None, "devices/d2/temp"));
+        assert!(!can_publish(&rules, "d1", None, "users/%u/temp"));
+        assert!(can_publish(&rules, "d1", Some("alice"), "users/alice/temp"));
+        assert!(!can_publish(&rules, "d1", Some("alice"), "users/bob/temp"));
+        assert!(can_publish(&rules, "d1", Some("admin"), "anything/at/all"));
+    }
+
+    #[test]
+    fn subscriptions_are_allowed_only_within_rule_filters() {
+        let rules = vec![
+            rule(None, "sensors/secret/#", AclAction::Subscribe, false),
+            rule(None, "sensors/#", AclAction::Subscribe, true),
+            rule(None, "commands/%c", AclAction::All, true),
+        ];
+
+        assert!(can_subscribe(&rules, "d1", None, "sensors/public/+"));
+        assert!(can_subscribe(&rules, "d1", None, "commands/d1"));
+        assert!(!can_subscribe(&rules, "d1", None, "commands/+"));
+        assert!(!can_subscribe(&rules, "d1", None, "sensors/secret/temp"));
+        assert!(!can_subscribe(&rules, "d1", None, "sensors/+/temp"));
+        assert!(!can_subscribe(&rules, "d1", None, "sensors/+/#"));
+        assert!(!can_subscribe(&rules, "d1", None, "#"));
+        assert!(!can_publish(&rules, "d1", None, "sensors/temp"));
+    }
+
+    #[test]
+    fn sys_subscriptions_need_explicit_rules() {
+        let rules = vec![
+            rule(Some("admin"), "$SYS/#", AclAction::Subscribe, true),
+            rule(None, "#", AclAction::All, true),
+        ];
+
+        assert!(can_subscribe(&rules, "d1", None, "hello/world"));
+        assert!(!can_subscribe(&rules, "d1", None, "$SYS/#"));
+        assert!(!can_subscribe(&rules, "d1", None, "$SYS/broker/uptime"));
+        assert!(can_subscribe(&rules, "d1", Some("admin"), "$SYS/broker/+"));
+    }
+
+    #[test]
+    fn wildcards_and_levels_in_ids_dont_widen_rules() {
+        let rules = vec![
+            rule(None, "devices/%c/#", AclAction::All, true),
+            rule(None, "users/%u", AclAction::All, true),
+        ];
+
+        for id in ["#", "+", "a/b"] {
+            assert!(!can_publish(&rules, id, None, "devices/d2/temp"));
+            assert!(!can_publish(&rules, "d1", Some(id), "users/bob"));
+            assert!(!can_subscribe(&rules, id, None, "devices/#"));
+            assert!(!can_subscribe(&rules, "d1", Some(id), "users/+"));
+        }
+
+        assert!(can_publish(&rules, "a", None, "devices/a/b"));
+        assert!(!can_publish(&rules, "a/b", None, "devices/a/b"));
+
+        // Deny rules which can't be substituted deny everything
+        let rules = vec![
+            rule(None, "devices/%c/secret", AclAction::All, false),
+            rule(None, "#", AclAction::All, true),
+        ];
+
+        assert!(can_publish(&rules, "d1", None, "devices/d1/public"));
+        assert!(!can_publish(&rules, "+", None, "devices/d1/secret"));
+        assert!(!can_subscribe(&rules, "#", None, "devices/d1/secret"));
+    }
+}
@@ -1,22 +1,41 @@
-use crate::protocol::LastWill;
-use crate::Filter;
+use crate::link::local::LinkSettings;
+use crate::protocol::{LastWill, LastWillProperties};
+use crate::{Filter, RateLimit};
 use flume::{bounded, Receiver, Sender};
 use std::collections::HashSet;
 
+use super::limiter::Limiter;
 use super::{ConnectionMeter, MetricsReply};
 
+/// Publish quota of a connection. Connections without one, like the ones of
+/// the broker itself, aren't limited
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    /// Limit of this connection. Tenant limit of the router still applies
+    /// when this isn't set
+    pub limit: Option<RateLimit>,
+    /// Reject publishes over quota with a reason code instead of delaying
+    /// them. Needs MQTT 5
+    pub reject: bool,
+}
+
 /// Used to register a new connection with the router
 /// Connection messages encompasses a handle for router to
 /// communicate with this connection
 #[derive(Debug)]
 pub struct Connection {
     pub client_id: String,
+    /// Username the client authenticated with
+    pub username: Option<String>,
     /// Id of client's organisation/tenant and the prefix associated with tenant's MQTT topic
     pub tenant_prefix: Option<String>,
     /// Dynamically create subscription filters incase they didn't exist during a publish
     pub dynamic_filters: bool,
     /// Clean session
     pub clean: bool,
+    /// Seconds to keep state of a persistent session after disconnection.
+    /// Kept forever when None
+    pub session_expiry_interval: Option<u32>,
     /// Subscriptions
     pub subscriptions: HashSet<Filter>,
     /// Handle to send metrics reply
@@ -24,18 +43,33 @@ pub struct Connection {
     /// Connection metrics
     pub meter: ConnectionMeter,
     pub last_will: Option<LastWill>,
+    /// Delay interval and publish properties of the last will
+    pub last_will_properties: Option<LastWillProperties>,
+    /// Publish quota. Not limited when None
+    pub quota: Option<Quota>,
+    /// Token buckets of the limit in quota
+    pub limiter: Option<Limiter>,
+    /// Connection of the broker itself, like bridges and the console. Acl
+    /// doesn't apply to it
+    pub local: bool,
 }
 
 impl Connection {
     /// Create connection state to hold identifying information of connecting device
-    pub fn new(
-        tenant_id: Option<String>,
-        client_id: String,
-        clean: bool,
-        last_will: Option<LastWill>,
-        dynamic_filters: bool,
-    ) -> (Connection, Receiver<MetricsReply>) {
+    pub fn new(client_id: String, settings: LinkSettings) -> (Connection, Receiver<MetricsReply>) {
         let (metrics_tx, metrics_rx) = bounded(1);
+        let LinkSettings {
+            tenant_id,
+            username,
+            clean,
+            session_expiry_interval,
+            last_will,
+            last_will_properties,
+            dynamic_filters,
+            quota,
+            local,
+            ..
+        } = settings;
 
         // Change client id to -> tenant_id.client_id and derive topic path prefix
         // to validate topics
@@ -50,13 +84,19 @@ impl Connection {
 
         let connection = Connection {
             client_id,
+            username,
             tenant_prefix,
             dynamic_filters,
             clean,
+            session_expiry_interval,
             subscriptions: HashSet::default(),
             metrics: metrics_tx,
             meter: ConnectionMeter::default(),
             last_will,
+            last_will_properties,
+            quota,
+            limiter: quota.and_then(|q| q.limit).map(Limiter::new),
+            local,
         };
 
         (connection, metrics_rx)
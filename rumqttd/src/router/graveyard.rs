@@ -1,61 +1,379 @@
 use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
 
 use super::{
+    logs::encode_name,
+    now_millis,
     scheduler::{PauseReason, Tracker},
     ConnectionMeter,
 };
 
 pub struct Graveyard {
+    /// Directory to persist saved state of persistent sessions in
+    dir: Option<PathBuf>,
     connections: HashMap<String, SavedState>,
+    /// Earliest expiry among the saved sessions
+    next_expiry: Option<u64>,
 }
 
 impl Graveyard {
-    pub fn new() -> Graveyard {
-        Graveyard {
-            connections: HashMap::new(),
+    /// Creates a new graveyard. Persistent sessions saved by previous runs are
+    /// reloaded from `dir`
+    pub fn new(dir: Option<PathBuf>) -> Graveyard {
+        let mut connections = HashMap::new();
+        if let Some(dir) = &dir {
+            if let Err(e) = load(dir, &mut connections) {
+                error!("Failed to load sessions from {:?}. Error = {:?}", dir, e);
+            }
         }
+
+        // Sessions which expired while the broker was down are purged right away
+        let next_expiry = connections.values().filter_map(|s| s.expiry).min();
+        let mut graveyard = Graveyard {
+            dir,
+            connections,
+            next_expiry,
+        };
+
+        graveyard.purge(now_millis());
+        graveyard
     }
 
     /// Add a new connection.
     /// Return tracker of previous connection if connection id already exists
     pub fn retrieve(&mut self, id: &str) -> Option<SavedState> {
+        self.purge(now_millis());
         self.connections.remove(id)
     }
 
-    /// Save connection tracker
+    /// Discards saved state of a disconnected client, on disk as well.
+    /// Returns false if there is no saved state for `id`
+    pub fn remove(&mut self, id: &str) -> bool {
+        if self.connections.remove(id).is_none() {
+            return false;
+        }
+
+        if let Some(dir) = &self.dir {
+            if let Err(e) = remove(&dir.join(encode_name(id) + ".json")) {
+                error!("{:15.15}[E] {:20} error = {:?}", id, "session-persist", e);
+            }
+        }
+
+        true
+    }
+
+    /// Time at which the earliest saved session expires
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let expiry = self.next_expiry?;
+        let remaining = expiry.saturating_sub(now_millis());
+        Some(Instant::now() + Duration::from_millis(remaining))
+    }
+
+    /// Removes sessions which expired by `now`. Sessions are only walked when
+    /// the earliest expiry has passed
+    pub fn purge(&mut self, now: u64) {
+        match self.next_expiry {
+            Some(expiry) if expiry <= now => (),
+            _ => return,
+        }
+
+        let dir = &self.dir;
+        self.connections.retain(|id, state| match state.expiry {
+            Some(expiry) if expiry <= now => {
+                info!("{:15.15}[I] {:20}", id, "session-expired");
+                if let Some(dir) = dir {
+                    if let Err(e) = remove(&dir.join(encode_name(id) + ".json")) {
+                        error!("{:15.15}[E] {:20} error = {:?}", id, "session-persist", e);
+                    }
+                }
+
+                false
+            }
+            _ => true,
+        });
+
+        self.next_expiry = self.connections.values().filter_map(|s| s.expiry).min();
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut SavedState> {
+        self.connections.values_mut()
+    }
+
+    /// Save connection tracker. State of persistent sessions is also written
+    /// to disk when a directory is configured. Persistent sessions with an
+    /// expiry interval (in seconds) are purged once it elapses
     pub fn save(
         &mut self,
         mut tracker: Tracker,
+        pubrels: Vec<u16>,
         subscriptions: HashSet<String>,
         metrics: ConnectionMeter,
+        clean: bool,
+        session_expiry_interval: Option<u32>,
     ) {
         tracker.pause(PauseReason::Busy);
         let id = tracker.id.clone();
 
-        self.connections.insert(
-            id,
-            SavedState {
-                tracker,
-                subscriptions,
-                metrics,
-            },
-        );
+        let expiry = match clean {
+            true => None,
+            false => expiry(session_expiry_interval),
+        };
+
+        if let Some(expiry) = expiry {
+            self.next_expiry = Some(self.next_expiry.map_or(expiry, |v| v.min(expiry)));
+        }
+
+        let state = SavedState {
+            tracker,
+            pubrels,
+            subscriptions,
+            metrics,
+            expiry,
+            clean,
+        };
+
+        if let Some(dir) = &self.dir {
+            let path = dir.join(encode_name(&id) + ".json");
+            let o = match clean {
+                true => remove(&path),
+                false => write(&path, &state),
+            };
+
+            if let Err(e) = o {
+                error!("{:15.15}[E] {:20} error = {:?}", id, "session-persist", e);
+            }
+        }
+
+        self.connections.insert(id, state);
+    }
+
+    /// Writes state of a connected persistent session to disk, so that it survives
+    /// a crash of the broker. Expiry counts from now, as if the client disconnected
+    pub fn persist(
+        &self,
+        mut tracker: Tracker,
+        pubrels: Vec<u16>,
+        subscriptions: HashSet<String>,
+        metrics: ConnectionMeter,
+        session_expiry_interval: Option<u32>,
+    ) {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        tracker.pause(PauseReason::Busy);
+        let id = tracker.id.clone();
+        let state = SavedState {
+            tracker,
+            pubrels,
+            subscriptions,
+            metrics,
+            expiry: expiry(session_expiry_interval),
+            clean: false,
+        };
+
+        if let Err(e) = write(&dir.join(encode_name(&id) + ".json"), &state) {
+            error!("{:15.15}[E] {:20} error = {:?}", id, "session-persist", e);
+        }
     }
 }
 
-#[derive(Debug)]
+/// Time at which a session expires if its client disconnects now
+fn expiry(session_expiry_interval: Option<u32>) -> Option<u64> {
+    session_expiry_interval.map(|v| now_millis() + v as u64 * 1000)
+}
+
+/// Loads all the sessions in `dir`
+fn load(dir: &Path, connections: &mut HashMap<String, SavedState>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        let state: SavedState = match serde_json::from_slice(&fs::read(&path)?) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Ignoring invalid session {:?}. Error = {:?}", path, e);
+                continue;
+            }
+        };
+
+        connections.insert(state.tracker.id.clone(), state);
+    }
+
+    Ok(())
+}
+
+/// Writes to a temporary file first, so that a crash while writing doesn't
+/// corrupt previously saved state
+fn write(path: &Path, state: &SavedState) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(state)?)?;
+    fs::rename(tmp, path)
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SavedState {
     pub tracker: Tracker,
+    /// Packet ids of QoS 2 publishes to the client which are waiting for PubComp
+    #[serde(default)]
+    pub pubrels: Vec<u16>,
     pub subscriptions: HashSet<String>,
     pub metrics: ConnectionMeter,
+    /// Time (ms since unix epoch) at which the session expires
+    #[serde(default)]
+    pub expiry: Option<u64>,
+    /// Only metrics are kept of clean sessions. They are never written to disk
+    #[serde(skip)]
+    pub clean: bool,
 }
 
 impl SavedState {
     pub fn new(client_id: String) -> SavedState {
         SavedState {
             tracker: Tracker::new(client_id),
+            pubrels: Vec::new(),
             subscriptions: HashSet::new(),
             metrics: ConnectionMeter::default(),
+            expiry: None,
+            clean: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Graveyard;
+    use crate::router::{now_millis, scheduler::Tracker, ConnectionMeter, DataRequest};
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    #[test]
+    fn persistent_sessions_are_reloaded() {
+        let dir = std::env::temp_dir().join("rumqttd-graveyard");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut graveyard = Graveyard::new(Some(dir.clone()));
+        let mut tracker = Tracker::new("device/1".to_owned());
+        tracker.register_data_request(DataRequest {
+            filter: "hello/+/world".to_owned(),
+            filter_idx: 3,
+            qos: 1,
+            cursor: (2, 25),
+            read_count: 10,
+            max_count: 100,
+            share: None,
+            nolocal: true,
+            preserve_retain: false,
+            received: vec![(2, 27)],
+        });
+
+        let subscriptions: HashSet<String> = ["hello/+/world".to_owned()].into();
+        graveyard.save(
+            tracker,
+            vec![3],
+            subscriptions.clone(),
+            ConnectionMeter::default(),
+            false,
+            None,
+        );
+        graveyard.save(
+            Tracker::new("device/2".to_owned()),
+            Vec::new(),
+            HashSet::new(),
+            ConnectionMeter::default(),
+            true,
+            None,
+        );
+
+        let mut graveyard = Graveyard::new(Some(dir.clone()));
+        assert!(graveyard.retrieve("device/2").is_none());
+
+        let saved = graveyard.retrieve("device/1").unwrap();
+        assert_eq!(saved.subscriptions, subscriptions);
+        let request = saved.tracker.get_data_requests().front().unwrap();
+        assert_eq!(request.filter, "hello/+/world");
+        assert_eq!(request.cursor, (2, 25));
+        assert_eq!(saved.pubrels, [3]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sessions_of_connected_clients_survive_crashes() {
+        let dir = std::env::temp_dir().join("rumqttd-graveyard-persist");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let graveyard = Graveyard::new(Some(dir.clone()));
+        let subscriptions: HashSet<String> = ["hello/world".to_owned()].into();
+        graveyard.persist(
+            Tracker::new("device/1".to_owned()),
+            Vec::new(),
+            subscriptions.clone(),
+            ConnectionMeter::default(),
+            Some(3600),
+        );
+
+        // Persisted sessions aren't held in memory, the client is still connected
+        let mut graveyard = Graveyard::new(Some(dir.clone()));
+        let saved = graveyard.retrieve("device/1").unwrap();
+        assert_eq!(saved.subscriptions, subscriptions);
+        assert!(saved.expiry.is_some());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sessions_are_purged_after_expiry() {
+        let dir = std::env::temp_dir().join("rumqttd-graveyard-expiry");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut graveyard = Graveyard::new(Some(dir.clone()));
+        for (id, expiry) in [
+            ("device/1", Some(0)),
+            ("device/2", Some(3600)),
+            ("device/3", None),
+        ] {
+            let tracker = Tracker::new(id.to_owned());
+            let subscriptions = HashSet::new();
+            graveyard.save(
+                tracker,
+                Vec::new(),
+                subscriptions,
+                ConnectionMeter::default(),
+                false,
+                expiry,
+            );
         }
+
+        // Expired sessions are purged without waiting for the client to return
+        assert!(graveyard.next_deadline().unwrap() <= Instant::now());
+        graveyard.purge(now_millis());
+        assert!(!dir.join("device%2f1.json").exists());
+        assert!(graveyard.retrieve("device/1").is_none());
+
+        // Expiry survives restarts
+        let mut graveyard = Graveyard::new(Some(dir.clone()));
+        let saved = graveyard.retrieve("device/2").unwrap();
+        assert!(saved.expiry.is_some());
+        let saved = graveyard.retrieve("device/3").unwrap();
+        assert!(saved.expiry.is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
@@ -1,18 +1,27 @@
-use std::{collections::VecDeque, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::Instant,
+};
 
 use flume::{Receiver, Sender};
 use parking_lot::Mutex;
 
 use crate::{
-    protocol::Packet,
+    protocol::{Packet, PublishProperties},
     router::{FilterIdx, MAX_CHANNEL_CAPACITY},
     Cursor, Notification,
 };
 
 use super::Forward;
 
-const MAX_INFLIGHT: usize = 100;
-const MAX_PKID: u16 = MAX_INFLIGHT as u16;
+/// Inflight limit of connections which don't come with connection settings
+pub const MAX_INFLIGHT: u16 = 100;
+/// Inflight size limit of connections which don't come with connection
+/// settings. Their inflight publishes are only limited by count
+pub const MAX_INFLIGHT_SIZE: usize = usize::MAX;
+/// Filter of inflight slots which hold PubRels of a resumed session
+const RESUMED: FilterIdx = FilterIdx::MAX;
 
 #[derive(Debug)]
 pub struct Incoming {
@@ -44,6 +53,15 @@ impl Incoming {
         std::mem::swap(&mut v, &mut self.buffer.lock());
         v
     }
+
+    /// Puts back packets which were exchanged but not handled, ahead of the
+    /// ones received since
+    pub(crate) fn hold(&mut self, packets: &mut VecDeque<Packet>) {
+        let mut buffer = self.buffer.lock();
+        while let Some(packet) = packets.pop_back() {
+            buffer.push_front(packet);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -54,8 +72,17 @@ pub struct Outgoing {
     pub(crate) data_buffer: Arc<Mutex<VecDeque<Notification>>>,
     /// Handle which is given to router to allow router to communicate with this connection
     pub(crate) handle: Sender<()>,
-    /// The buffer to keep track of inflight packets.
-    inflight_buffer: VecDeque<(u16, FilterIdx, Cursor)>,
+    /// Inflight packets and their sizes indexed by packet id. Index 0 is unused
+    /// as 0 isn't a valid packet id
+    inflight_buffer: Vec<Option<(FilterIdx, Cursor, Inflight, usize)>>,
+    /// Number of inflight packets
+    inflight: usize,
+    /// Maximum number of inflight packets. Also the maximum packet id
+    max_inflight: u16,
+    /// Topic and payload bytes of publishes which are yet to be received
+    inflight_size: usize,
+    /// New publishes aren't sent while inflight publishes take this many bytes
+    max_inflight_size: usize,
     /// Last packet id
     last_pkid: u16,
     /// Metrics of outgoing messages of this connection
@@ -64,19 +91,27 @@ pub struct Outgoing {
 
 impl Outgoing {
     #[inline]
-    pub(crate) fn new(client_id: String) -> (Self, Receiver<()>) {
+    pub(crate) fn new(
+        client_id: String,
+        max_inflight: u16,
+        max_inflight_size: usize,
+    ) -> (Self, Receiver<()>) {
         let (handle, rx) = flume::bounded(MAX_CHANNEL_CAPACITY);
         let data_buffer = VecDeque::with_capacity(MAX_CHANNEL_CAPACITY);
-        let inflight_buffer = VecDeque::with_capacity(MAX_INFLIGHT as usize);
+        let max_inflight = max_inflight.max(1);
+        let inflight_buffer = vec![None; max_inflight as usize + 1];
 
         // Ensure that there won't be any new allocations
-        assert!(MAX_INFLIGHT <= inflight_buffer.capacity());
         assert!(MAX_CHANNEL_CAPACITY <= data_buffer.capacity());
 
         let outgoing = Self {
             client_id,
             data_buffer: Arc::new(Mutex::new(data_buffer)),
             inflight_buffer,
+            inflight: 0,
+            max_inflight,
+            inflight_size: 0,
+            max_inflight_size,
             handle,
             last_pkid: 0,
             meter: Default::default(),
@@ -90,8 +125,19 @@ impl Outgoing {
         self.data_buffer.clone()
     }
 
+    /// Number of buffered notifications and inflight publishes
+    pub fn counts(&self) -> (usize, usize) {
+        (self.data_buffer.lock().len(), self.inflight)
+    }
+
+    /// Number of publishes which can be sent. A batch can take inflight size
+    /// over the limit, it holds back the batches after it
     pub fn free_slots(&self) -> usize {
-        MAX_INFLIGHT - self.inflight_buffer.len()
+        if self.inflight_size >= self.max_inflight_size {
+            return 0;
+        }
+
+        self.max_inflight as usize - self.inflight
     }
 
     pub fn push_notification(&mut self, notification: Notification) -> usize {
@@ -103,7 +149,7 @@ impl Outgoing {
     /// Push packets to the outgoing buffer.
     pub fn push_forwards(
         &mut self,
-        publishes: impl Iterator<Item = Forward>,
+        publishes: impl Iterator<Item = (Forward, Option<PublishProperties>)>,
         qos: u8,
         filter_idx: usize,
     ) -> (usize, usize) {
@@ -111,65 +157,172 @@ impl Outgoing {
         let publishes = publishes;
 
         if qos == 0 {
-            for p in publishes {
+            for (p, properties) in publishes {
                 self.meter.publish_count += 1;
-                buffer.push_back(Notification::Forward(p));
+                buffer.push_back(notification(p, properties));
                 // self.meter.total_size += p.len();
             }
 
             // self.meter.update_data_rate(total_size);
             let buffer_count = buffer.len();
-            let inflight_count = self.inflight_buffer.len();
-            return (buffer_count, inflight_count);
+            return (buffer_count, self.inflight);
         }
 
-        for mut p in publishes {
-            // Index and pkid of current outgoing packet
-            self.last_pkid += 1;
-            p.publish.pkid = self.last_pkid;
+        for (mut p, properties) in publishes {
+            // Callers don't push more publishes than free slots
+            let pkid = match next_pkid(&self.inflight_buffer, &mut self.last_pkid) {
+                Some(pkid) => pkid,
+                None => {
+                    error!(
+                        "inflight_count = {:<2} max_inflight = {:<2}",
+                        self.inflight, self.max_inflight
+                    );
+                    break;
+                }
+            };
+
+            p.publish.pkid = pkid;
+            let size = p.publish.topic.len() + p.publish.payload.len();
+            self.inflight_buffer[pkid as usize] =
+                Some((filter_idx, p.cursor, Inflight::Publish, size));
+            self.inflight += 1;
+            self.inflight_size += size;
+
+            self.meter.publish_count += 1;
+            self.meter.total_size += size;
+            buffer.push_back(notification(p, properties));
+        }
 
-            self.inflight_buffer
-                .push_back((self.last_pkid, filter_idx, p.cursor));
+        (buffer.len(), self.inflight)
+    }
 
-            // Place max pkid packet at index 0
-            if self.last_pkid == MAX_PKID {
-                self.last_pkid = 0;
-            }
+    /// Handles PubAck of a QoS 1 publish and frees its inflight slot.
+    /// Returns None on unsolicited acks
+    pub fn register_ack(&mut self, pkid: u16) -> Option<()> {
+        self.inflight_size -= self.slot(pkid, Inflight::Publish)?.3;
+        self.inflight_buffer[pkid as usize] = None;
+        self.inflight -= 1;
+        Some(())
+    }
 
-            self.meter.publish_count += 1;
-            self.meter.total_size += p.publish.topic.len() + p.publish.payload.len();
-            buffer.push_back(Notification::Forward(p));
+    /// Handles PubRec of a QoS 2 publish. Its slot is held until PubComp as
+    /// the packet id isn't free until then. Its size is freed right away
+    pub fn register_pubrec(&mut self, pkid: u16) -> Option<()> {
+        let slot = self.slot(pkid, Inflight::Publish)?;
+        slot.2 = Inflight::PubRel;
+        let size = std::mem::take(&mut slot.3);
+        self.inflight_size -= size;
+        Some(())
+    }
+
+    /// Handles PubComp of a QoS 2 publish and frees its inflight slot
+    pub fn register_pubcomp(&mut self, pkid: u16) -> Option<()> {
+        self.slot(pkid, Inflight::PubRel)?;
+        self.inflight_buffer[pkid as usize] = None;
+        self.inflight -= 1;
+        Some(())
+    }
+
+    /// Cursor of the oldest publish per filter which is yet to be acked.
+    /// Publishes waiting for PubComp were received and aren't included
+    pub fn unacked_cursors(&self) -> HashMap<FilterIdx, Cursor> {
+        let mut cursors = HashMap::new();
+        let unacked = self.inflight_buffer.iter().flatten();
+        for (filter_idx, cursor, ..) in unacked.filter(|slot| slot.2 == Inflight::Publish) {
+            let oldest = cursors.entry(*filter_idx).or_insert(*cursor);
+            *oldest = (*oldest).min(*cursor);
         }
 
-        let buffer_count = buffer.len();
-        let inflight_count = self.inflight_buffer.len();
+        cursors
+    }
 
-        if inflight_count > MAX_INFLIGHT {
-            error!(
-                "inflight_count = {:<2} MAX_INFLIGHT = {:<2}",
-                inflight_count, MAX_INFLIGHT
-            );
+    /// Cursors per filter of QoS 2 publishes which were received by the client
+    /// and are waiting for PubComp. PubRels resumed from a previous session
+    /// aren't included
+    pub fn received_cursors(&self) -> HashMap<FilterIdx, Vec<Cursor>> {
+        let mut cursors: HashMap<FilterIdx, Vec<Cursor>> = HashMap::new();
+        let received = self.inflight_buffer.iter().flatten();
+        for (filter_idx, cursor, ..) in received.filter(|slot| slot.2 == Inflight::PubRel) {
+            if *filter_idx != RESUMED {
+                cursors.entry(*filter_idx).or_default().push(*cursor);
+            }
         }
 
-        (buffer_count, inflight_count)
+        cursors
     }
 
-    // Returns (unsolicited, outoforder) flags
-    // Return: Out of order or unsolicited acks
-    pub fn register_ack(&mut self, pkid: u16) -> Option<()> {
-        let (head, _filter_idx, _cursor) = match self.inflight_buffer.pop_front() {
-            Some(v) => v,
-            None => return None,
-        };
+    /// Packet ids of QoS 2 publishes which are waiting for PubComp. Their
+    /// PubRel is sent again when the session resumes
+    pub fn pubrels(&self) -> Vec<u16> {
+        let slots = self.inflight_buffer.iter().enumerate();
+        slots
+            .filter(|(_, slot)| matches!(slot, Some((.., Inflight::PubRel, _))))
+            .map(|(pkid, _)| pkid as u16)
+            .collect()
+    }
 
-        // We don't support out of order acks
-        if pkid != head {
-            error!("out of order ack. pkid = {}, head = {}", pkid, head);
+    /// Holds the slot of a PubRel which is sent again to a resumed session until
+    /// its PubComp. Returns None if `pkid` doesn't fit in the inflight limit
+    pub fn resume_pubrel(&mut self, pkid: u16) -> Option<()> {
+        let slot = self.inflight_buffer.get_mut(pkid as usize)?;
+        if pkid == 0 || slot.is_some() {
             return None;
         }
 
+        // Cursors of resumed PubRels are kept by data requests of the session
+        *slot = Some((RESUMED, (0, 0), Inflight::PubRel, 0));
+        self.inflight += 1;
         Some(())
     }
+
+    /// Inflight packet with `pkid` if it is waiting for an ack of `state`.
+    /// Acks are accepted in any order
+    fn slot(
+        &mut self,
+        pkid: u16,
+        state: Inflight,
+    ) -> Option<&mut (FilterIdx, Cursor, Inflight, usize)> {
+        match self.inflight_buffer.get_mut(pkid as usize) {
+            Some(Some(slot)) if slot.2 == state => Some(slot),
+            _ => {
+                error!("unsolicited ack. pkid = {}, expected = {:?}", pkid, state);
+                None
+            }
+        }
+    }
+}
+
+/// Stage of the handshake an inflight publish is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Inflight {
+    /// Waiting for PubAck (QoS 1) or PubRec (QoS 2)
+    Publish,
+    /// PubRel sent, waiting for PubComp (QoS 2)
+    PubRel,
+}
+
+/// Next free packet id after the last one which was used. Packet ids are
+/// freed out of order, so occupied ones are skipped
+fn next_pkid(
+    inflight_buffer: &[Option<(FilterIdx, Cursor, Inflight, usize)>],
+    last_pkid: &mut u16,
+) -> Option<u16> {
+    let max_pkid = inflight_buffer.len() as u16 - 1;
+    for _ in 0..max_pkid {
+        *last_pkid = *last_pkid % max_pkid + 1;
+        if inflight_buffer[*last_pkid as usize].is_none() {
+            return Some(*last_pkid);
+        }
+    }
+
+    None
+}
+
+fn notification(forward: Forward, properties: Option<PublishProperties>) -> Notification {
+    match properties {
+        Some(properties) => Notification::ForwardWithProperties(forward, properties),
+        None => Notification::Forward(forward),
+    }
 }
 
 #[derive(Debug)]
@@ -239,6 +392,151 @@ impl OutgoingMeter {
 
 #[cfg(test)]
 mod test {
+    use super::Outgoing;
+    use crate::protocol::{Publish, PublishProperties, QoS};
+    use crate::router::Forward;
+    use crate::Notification;
+
+    fn forwards(count: usize) -> impl Iterator<Item = (Forward, Option<PublishProperties>)> {
+        (1..=count).map(|v| {
+            let publish = Publish {
+                dup: false,
+                retain: false,
+                pkid: 0,
+                qos: QoS::AtLeastOnce,
+                topic: "hello/world".into(),
+                payload: vec![1, 2, 3].into(),
+            };
+
+            let forward = Forward {
+                cursor: (0, v as u64),
+                publish,
+                size: 0,
+            };
+
+            (forward, None)
+        })
+    }
+
+    fn pkids(outgoing: &Outgoing) -> Vec<u16> {
+        let buffer = outgoing.data_buffer.lock();
+        buffer
+            .iter()
+            .map(|n| match n {
+                Notification::Forward(f) => f.publish.pkid,
+                v => unreachable!("{:?}", v),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn qos2_slots_are_held_until_pubcomp() {
+        let (mut outgoing, _rx) = Outgoing::new("hello".to_owned(), 10, usize::MAX);
+        outgoing.push_forwards(forwards(3), 2, 0);
+        assert_eq!(outgoing.free_slots(), 7);
+
+        assert!(outgoing.register_pubcomp(1).is_none());
+        assert!(outgoing.register_pubrec(1).is_some());
+        assert!(outgoing.register_pubrec(1).is_none());
+        assert_eq!(outgoing.free_slots(), 7);
+
+        assert!(outgoing.register_pubcomp(1).is_some());
+        assert_eq!(outgoing.free_slots(), 8);
+    }
+
+    #[test]
+    fn pubrels_are_resumed_in_new_connections() {
+        let (mut outgoing, _rx) = Outgoing::new("hello".to_owned(), 3, usize::MAX);
+        outgoing.push_forwards(forwards(2), 2, 0);
+        assert!(outgoing.register_pubrec(2).is_some());
+        assert_eq!(outgoing.pubrels(), vec![2]);
+
+        let (mut resumed, _rx) = Outgoing::new("hello".to_owned(), 3, usize::MAX);
+        for pkid in outgoing.pubrels() {
+            assert!(resumed.resume_pubrel(pkid).is_some());
+        }
+
+        assert!(resumed.resume_pubrel(2).is_none());
+        assert!(resumed.resume_pubrel(4).is_none());
+
+        // Packet id of the PubRel isn't reused until its PubComp
+        resumed.push_forwards(forwards(2), 1, 0);
+        assert_eq!(pkids(&resumed), vec![1, 3]);
+        assert!(resumed.register_pubcomp(2).is_some());
+        assert_eq!(resumed.free_slots(), 1);
+    }
+
+    #[test]
+    fn acks_are_accepted_out_of_order() {
+        let (mut outgoing, _rx) = Outgoing::new("hello".to_owned(), 3, usize::MAX);
+        outgoing.push_forwards(forwards(3), 1, 0);
+        assert_eq!(outgoing.free_slots(), 0);
+
+        assert!(outgoing.register_ack(2).is_some());
+        assert!(outgoing.register_ack(2).is_none());
+        assert!(outgoing.register_ack(4).is_none());
+
+        // Only the freed packet id can be reused
+        outgoing.push_forwards(forwards(1), 1, 0);
+        assert_eq!(pkids(&outgoing), vec![1, 2, 3, 2]);
+
+        for pkid in [3, 1, 2] {
+            assert!(outgoing.register_ack(pkid).is_some());
+        }
+
+        assert_eq!(outgoing.free_slots(), 3);
+    }
+
+    #[test]
+    fn inflight_size_holds_back_publishes_until_acked() {
+        // Publishes are 14 bytes each
+        let (mut outgoing, _rx) = Outgoing::new("hello".to_owned(), 10, 30);
+        outgoing.push_forwards(forwards(2), 1, 0);
+        assert_eq!(outgoing.free_slots(), 8);
+
+        outgoing.push_forwards(forwards(1), 2, 0);
+        assert_eq!(outgoing.free_slots(), 0);
+
+        assert!(outgoing.register_pubrec(3).is_some());
+        assert_eq!(outgoing.free_slots(), 7);
+        assert!(outgoing.register_ack(1).is_some());
+        assert!(outgoing.register_ack(2).is_some());
+        assert!(outgoing.register_pubcomp(3).is_some());
+        assert_eq!(outgoing.inflight_size, 0);
+        assert_eq!(outgoing.free_slots(), 10);
+    }
+
+    #[test]
+    fn unacked_cursors_are_the_oldest_per_filter() {
+        let (mut outgoing, _rx) = Outgoing::new("hello".to_owned(), 10, usize::MAX);
+        outgoing.push_forwards(forwards(3), 1, 0);
+        outgoing.push_forwards(forwards(2), 2, 1);
+
+        assert!(outgoing.register_ack(1).is_some());
+        assert!(outgoing.register_pubrec(4).is_some());
+        let cursors = outgoing.unacked_cursors();
+        assert_eq!(cursors.len(), 2);
+        assert_eq!(cursors[&0], (0, 2));
+        assert_eq!(cursors[&1], (0, 2));
+
+        assert!(outgoing.register_pubrec(5).is_some());
+        assert!(!outgoing.unacked_cursors().contains_key(&1));
+    }
+
+    #[test]
+    fn received_cursors_dont_include_resumed_pubrels() {
+        let (mut outgoing, _rx) = Outgoing::new("hello".to_owned(), 10, usize::MAX);
+        assert!(outgoing.resume_pubrel(1).is_some());
+        outgoing.push_forwards(forwards(3), 2, 4);
+
+        assert!(outgoing.register_pubrec(2).is_some());
+        assert!(outgoing.register_pubrec(4).is_some());
+        let cursors = outgoing.received_cursors();
+        assert_eq!(cursors.len(), 1);
+        assert_eq!(cursors[&4], [(0, 1), (0, 3)]);
+        assert_eq!(outgoing.unacked_cursors()[&4], (0, 2));
+    }
+
     // use super::{Outgoing, MAX_INFLIGHT};
     // use crate::protocol::{Publish, QoS};
     // use crate::router::Forward;
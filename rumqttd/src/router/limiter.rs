@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use crate::RateLimit;
+
+/// Token buckets of a rate limit. Buckets hold a second worth of tokens, so
+/// idle clients can burst up to the limit of a second
+#[derive(Debug)]
+pub struct Limiter {
+    limit: RateLimit,
+    /// Messages which can be sent right away
+    messages: f64,
+    /// Bytes which can be sent right away
+    bytes: f64,
+    /// Time of last refill
+    last: Instant,
+}
+
+impl Limiter {
+    pub fn new(limit: RateLimit) -> Limiter {
+        Limiter {
+            limit,
+            messages: limit.messages_per_sec.unwrap_or(0) as f64,
+            bytes: limit.bytes_per_sec.unwrap_or(0) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Time until a publish of `size` bytes fits in the buckets. Publishes
+    /// bigger than a bucket only have to wait for a full bucket
+    pub fn wait(&mut self, size: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+
+        let mut wait: f64 = 0.0;
+        if let Some(rate) = self.limit.messages_per_sec.map(|v| v as f64) {
+            self.messages = (self.messages + elapsed * rate).min(rate);
+            if self.messages < 1.0 {
+                wait = wait.max((1.0 - self.messages) / rate);
+            }
+        }
+
+        if let Some(rate) = self.limit.bytes_per_sec.map(|v| v as f64) {
+            self.bytes = (self.bytes + elapsed * rate).min(rate);
+            let size = (size as f64).min(rate);
+            if self.bytes < size {
+                wait = wait.max((size - self.bytes) / rate);
+            }
+        }
+
+        // Buckets are full within a second. Zero rates never refill, they are
+        // checked again after a second
+        Duration::from_secs_f64(wait.min(1.0))
+    }
+
+    /// Takes tokens of a publish of `size` bytes. Check `wait` first
+    pub fn take(&mut self, size: usize) {
+        if self.limit.messages_per_sec.is_some() {
+            self.messages -= 1.0;
+        }
+
+        if let Some(rate) = self.limit.bytes_per_sec {
+            self.bytes -= (size as f64).min(rate as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Limiter;
+    use crate::RateLimit;
+    use std::time::Duration;
+
+    #[test]
+    fn publishes_over_limit_wait_for_refill() {
+        let limit = RateLimit {
+            messages_per_sec: Some(2),
+            bytes_per_sec: Some(100),
+        };
+
+        let mut limiter = Limiter::new(limit);
+        let now = limiter.last;
+        for _ in 0..2 {
+            assert_eq!(limiter.wait(10, now), Duration::ZERO);
+            limiter.take(10);
+        }
+
+        // Message bucket is empty and refills 2 messages a second
+        assert_eq!(limiter.wait(10, now), Duration::from_millis(500));
+        let now = now + Duration::from_millis(500);
+        assert_eq!(limiter.wait(10, now), Duration::ZERO);
+        limiter.take(10);
+
+        // Publishes bigger than the byte bucket take all of a full bucket
+        let now = now + Duration::from_secs(1);
+        assert_eq!(limiter.wait(1000, now), Duration::ZERO);
+        limiter.take(1000);
+        assert_eq!(limiter.wait(50, now), Duration::from_millis(500));
+    }
+}
@@ -1,16 +1,20 @@
-use super::Ack;
+use super::{shared, Ack};
 use slab::Slab;
 
 use crate::protocol::{
-    matches, ConnAck, PingResp, PubAck, PubComp, PubRec, PubRel, Publish, SubAck, UnsubAck,
+    matches, ConnAck, PingResp, PubAck, PubComp, PubCompReason, PubRec, PubRel, Publish,
+    PublishProperties, SubAck, SubAckProperties, UnsubAck, UnsubAckProperties,
 };
-use crate::router::{DataRequest, FilterIdx, SubscriptionMeter, Waiters};
+use crate::router::{now_millis, DataRequest, FilterIdx, SubscriptionMeter, Waiters};
 use crate::{ConnectionId, Filter, Offset, RouterConfig, Topic};
 
 use crate::segments::{CommitLog, Position};
 use crate::Storage;
 use std::collections::{HashMap, VecDeque};
+use std::fs;
 use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
 
 /// Stores 'device' data and 'actions' data in native commitlog
 /// organized by subscription filter. Device data is replicated
@@ -24,10 +28,10 @@ pub struct DataLog {
     /// Also has waiters used to wake connections/replicator tracker
     /// which are caught up with all the data on 'Filter' and waiting
     /// for new data
-    pub native: Slab<Data<Publish>>,
+    pub native: Slab<Data<PublishData>>,
     /// Map of subscription filter name to filter index
     filter_indexes: HashMap<Filter, FilterIdx>,
-    retained_publishes: HashMap<Topic, Publish>,
+    retained_publishes: HashMap<Topic, PublishData>,
     /// List of filters associated with a topic
     publish_filters: HashMap<Topic, Vec<FilterIdx>>,
 }
@@ -39,17 +43,40 @@ impl DataLog {
         let retained_publishes = HashMap::new();
         let publish_filters = HashMap::new();
 
-        if let Some(warmup_filters) = config.initialized_filters.clone() {
-            for filter in warmup_filters {
-                let data = Data::new(&filter, config.max_segment_size, config.max_segment_count);
+        let mut filters = config.initialized_filters.clone().unwrap_or_default();
 
-                // Add commitlog to datalog and add datalog index to filter to
-                // datalog index map
-                let idx = native.insert(data);
-                filter_indexes.insert(filter, idx);
+        // Reload commitlogs of filters which have segments on disk from previous runs
+        if let (Some(log_dir), true) = (&config.log_dir, config.max_disk_segments > 0) {
+            fs::create_dir_all(log_dir)?;
+            for entry in fs::read_dir(log_dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+
+                let filter = entry.file_name().to_str().and_then(decode_name);
+                match filter {
+                    Some(filter) if !filters.contains(&filter) => filters.push(filter),
+                    Some(_) => continue,
+                    None => warn!("Ignoring invalid log directory {:?}", entry.path()),
+                }
             }
         }
 
+        for filter in filters {
+            let data = Data::new(
+                &filter,
+                config.max_segment_size,
+                config.max_segment_count,
+                disk(&config, &filter),
+            );
+
+            // Add commitlog to datalog and add datalog index to filter to
+            // datalog index map
+            let idx = native.insert(data);
+            filter_indexes.insert(filter, idx);
+        }
+
         Ok(DataLog {
             config,
             native,
@@ -65,6 +92,14 @@ impl DataLog {
             .map(|data| data.meter.clone())
     }
 
+    /// Meters of all the filters
+    pub fn meters(&self) -> HashMap<&Filter, &SubscriptionMeter> {
+        self.native
+            .iter()
+            .map(|(_, data)| (&data.filter, &data.meter))
+            .collect()
+    }
+
     pub fn waiters(&self, filter: &Filter) -> Option<&Waiters<DataRequest>> {
         self.native
             .get(*self.filter_indexes.get(filter)?)
@@ -74,14 +109,19 @@ impl DataLog {
     pub fn remove_waiters_for_id(
         &mut self,
         id: ConnectionId,
-        filter: &Filter,
+        subscription: &Filter,
     ) -> Option<DataRequest> {
+        let filter = shared::split(subscription).map_or(subscription.as_str(), |(_, f)| f);
         let data = self
             .native
             .get_mut(*self.filter_indexes.get(filter)?)
             .unwrap();
         let waiters = data.waiters.get_mut();
-        return match waiters.iter().position(|x| x.0 == id) {
+        let position = waiters
+            .iter()
+            .position(|x| x.0 == id && x.1.subscription() == subscription);
+
+        return match position {
             Some(index) => waiters.swap_remove_back(index).map(|v| v.1),
             None => None,
         };
@@ -120,6 +160,7 @@ impl DataLog {
                     filter,
                     self.config.max_segment_size,
                     self.config.max_segment_count,
+                    disk(&self.config, filter),
                 );
 
                 // Add commitlog to datalog and add datalog index to filter to
@@ -146,7 +187,7 @@ impl DataLog {
         filter_idx: FilterIdx,
         offset: Offset,
         len: u64,
-    ) -> io::Result<(Position, Vec<Publish>)> {
+    ) -> io::Result<(Position, Vec<PublishData>)> {
         // unwrap to get index of `self.native` is fine here, because when a new subscribe packet
         // arrives in `Router::handle_device_payload`, it first calls the function
         // `next_native_offset` which creates a new commitlog if one doesn't exist. So any new
@@ -159,7 +200,7 @@ impl DataLog {
 
     pub fn shadow(&mut self, filter: &str) -> Option<Publish> {
         let data = self.native.get_mut(*self.filter_indexes.get(filter)?)?;
-        data.log.last()
+        data.log.last().map(|data| data.publish)
     }
 
     /// This method is called when the subscriber has caught up with the commit log. In which case,
@@ -177,6 +218,17 @@ impl DataLog {
         data.waiters.register(id, request);
     }
 
+    /// Requests of a connection which are parked in the waiters. Waiters are left
+    /// as they are
+    pub fn parked(&self, id: ConnectionId) -> Vec<DataRequest> {
+        self.native
+            .iter()
+            .flat_map(|(_, data)| data.waiters.waiters().iter())
+            .filter(|(waiter, _)| *waiter == id)
+            .map(|(_, request)| request.clone())
+            .collect()
+    }
+
     /// Cleanup a connection from all the waiters
     pub fn clean(&mut self, id: ConnectionId) -> Vec<DataRequest> {
         let mut inflight = Vec::new();
@@ -187,7 +239,7 @@ impl DataLog {
         inflight
     }
 
-    pub fn insert_to_retained_publishes(&mut self, publish: Publish, topic: Topic) {
+    pub fn insert_to_retained_publishes(&mut self, publish: PublishData, topic: Topic) {
         self.retained_publishes.insert(topic, publish);
     }
 
@@ -195,6 +247,28 @@ impl DataLog {
         self.retained_publishes.remove(&topic);
     }
 
+    /// Retained publishes with topics matching `filter`. Expired publishes are skipped
+    pub fn retained_publishes(&self, filter: &str) -> Vec<(&Topic, &PublishData)> {
+        let now = now_millis();
+        let mut retained: Vec<(&Topic, &PublishData)> = self
+            .retained_publishes
+            .iter()
+            .filter(|(topic, publish)| matches(topic, filter) && publish.remaining(now) != Some(0))
+            .collect();
+
+        retained.sort_by(|a, b| a.0.cmp(b.0));
+        retained
+    }
+
+    /// Removes retained publishes with topics matching `filter`. Returns the number removed
+    pub fn clear_retained_publishes(&mut self, filter: &str) -> usize {
+        let count = self.retained_publishes.len();
+        self.retained_publishes
+            .retain(|topic, _| !matches(topic, filter));
+
+        count - self.retained_publishes.len()
+    }
+
     pub fn handle_retained_messages(
         &mut self,
         filter: &str,
@@ -206,6 +280,11 @@ impl DataLog {
 
         let datalog = self.native.get_mut(*idx).unwrap();
 
+        // Expired retained publishes are dropped instead of being replayed
+        let now = now_millis();
+        self.retained_publishes
+            .retain(|_, publish| publish.remaining(now) != Some(0));
+
         for (topic, publish) in self.retained_publishes.iter_mut() {
             if matches(topic, filter) {
                 datalog.append(publish.clone(), notifications);
@@ -214,6 +293,107 @@ impl DataLog {
     }
 }
 
+/// Publish as stored in the commitlog. MQTT 5 publishes with a message expiry
+/// interval carry the time (ms since unix epoch) at which they expire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishData {
+    /// Retain flag of the publish is only set on retained publishes which
+    /// are replayed to new subscriptions
+    pub publish: Publish,
+    /// MQTT 5 properties which are forwarded to subscribers
+    pub properties: Option<PublishProperties>,
+    pub expiry: Option<u64>,
+    /// Client id of the publisher. Subscriptions with No Local skip their own publishes
+    pub origin: Option<Arc<str>>,
+    /// Retain flag the publish was published with. Forwarded to subscriptions
+    /// with Retain As Published
+    pub retain: bool,
+}
+
+impl PublishData {
+    /// Message expiry interval (in seconds) starts counting from now. Topic
+    /// alias and subscription identifiers are dropped as they only apply to
+    /// the connection the publish came from
+    pub fn new(publish: Publish, properties: Option<PublishProperties>) -> PublishData {
+        let properties = properties.unwrap_or_default();
+        let expiry = properties
+            .message_expiry_interval
+            .map(|interval| now_millis() + interval as u64 * 1000);
+
+        let properties = PublishProperties {
+            payload_format_indicator: properties.payload_format_indicator,
+            response_topic: properties.response_topic,
+            correlation_data: properties.correlation_data,
+            user_properties: properties.user_properties,
+            content_type: properties.content_type,
+            ..Default::default()
+        };
+
+        let properties = match properties == PublishProperties::default() {
+            true => None,
+            false => Some(properties),
+        };
+
+        PublishData {
+            retain: publish.retain,
+            publish,
+            properties,
+            expiry,
+            origin: None,
+        }
+    }
+
+    /// Seconds left before the publish expires, rounded up. `Some(0)` once
+    /// expired and None for publishes which never expire
+    pub fn remaining(&self, now: u64) -> Option<u32> {
+        let expiry = self.expiry?;
+        let remaining = expiry.saturating_sub(now);
+        Some(remaining.div_ceil(1000).min(u32::MAX as u64) as u32)
+    }
+}
+
+/// Disk directory and segment limit for commitlog of `filter`, when disk
+/// persistence is enabled
+fn disk(config: &RouterConfig, filter: &str) -> Option<(PathBuf, usize)> {
+    let log_dir = config.log_dir.as_ref()?;
+    if config.max_disk_segments == 0 {
+        return None;
+    }
+
+    Some((log_dir.join(encode_name(filter)), config.max_disk_segments))
+}
+
+/// Filters and client ids are used as file names. Escapes everything
+/// except alphanumerics, '-' and '_' as `%xx`
+pub(super) fn encode_name(id: &str) -> String {
+    let mut name = String::with_capacity(id.len());
+    for b in id.bytes() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(b as char),
+            _ => name.push_str(&format!("%{:02x}", b)),
+        }
+    }
+
+    name
+}
+
+fn decode_name(name: &str) -> Option<String> {
+    let mut id = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                id.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b => id.push(b),
+        }
+    }
+
+    String::from_utf8(id).ok()
+}
+
 pub struct Data<T> {
     filter: Filter,
     log: CommitLog<T>,
@@ -225,8 +405,22 @@ impl<T> Data<T>
 where
     T: Storage + Clone,
 {
-    fn new(filter: &str, max_segment_size: usize, max_mem_segments: usize) -> Data<T> {
-        let log = CommitLog::new(max_segment_size, max_mem_segments).unwrap();
+    fn new(
+        filter: &str,
+        max_segment_size: usize,
+        max_mem_segments: usize,
+        disk: Option<(PathBuf, usize)>,
+    ) -> Data<T> {
+        let log = match CommitLog::new(max_segment_size, max_mem_segments, disk) {
+            Ok(log) => log,
+            Err(e) => {
+                error!(
+                    "Failed to open disk log of {}, using memory only. Error = {:?}",
+                    filter, e
+                );
+                CommitLog::new(max_segment_size, max_mem_segments, None).unwrap()
+            }
+        };
 
         let waiters = Waiters::with_capacity(10);
         let metrics = SubscriptionMeter::default();
@@ -255,6 +449,7 @@ where
         self.meter.append_offset = offset;
         self.meter.total_size += size;
         self.meter.head_and_tail_id = self.log.head_and_tail();
+        self.meter.disk_segments = self.log.disk_segments_count();
 
         (offset, &self.filter)
     }
@@ -266,7 +461,7 @@ pub struct AckLog {
     // Committed acks per connection. First pkid, last pkid, data
     committed: VecDeque<Ack>,
     // Recorded qos 2 publishes
-    recorded: VecDeque<Publish>,
+    recorded: VecDeque<PublishData>,
 }
 
 impl AckLog {
@@ -283,8 +478,13 @@ impl AckLog {
         self.committed.push_back(ack);
     }
 
-    pub fn suback(&mut self, ack: SubAck) {
-        let ack = Ack::SubAck(ack);
+    /// Properties are only written to v5 connections
+    pub fn suback(&mut self, ack: SubAck, properties: Option<SubAckProperties>) {
+        let ack = match properties {
+            Some(properties) => Ack::SubAckWithProperties(ack, properties),
+            None => Ack::SubAck(ack),
+        };
+
         self.committed.push_back(ack);
     }
 
@@ -293,21 +493,36 @@ impl AckLog {
         self.committed.push_back(ack);
     }
 
-    pub fn pubrec(&mut self, publish: Publish, ack: PubRec) {
+    pub fn pubrec(&mut self, publish: PublishData, ack: PubRec) {
         let ack = Ack::PubRec(ack);
         self.recorded.push_back(publish);
         self.committed.push_back(ack);
     }
 
+    /// PubRec with a failure reason code. Publish isn't recorded as v5 clients
+    /// don't follow up failures with PubRel
+    pub fn pubrec_failure(&mut self, ack: PubRec) {
+        let ack = Ack::PubRec(ack);
+        self.committed.push_back(ack);
+    }
+
     pub fn pubrel(&mut self, ack: PubRel) {
         let ack = Ack::PubRel(ack);
         self.committed.push_back(ack);
     }
 
-    pub fn pubcomp(&mut self, ack: PubComp) -> Option<Publish> {
+    /// Completes the qos 2 exchange of the recorded publish with the same pkid.
+    /// PubRel without a recorded publish is acked with `PacketIdentifierNotFound`
+    pub fn pubcomp(&mut self, mut ack: PubComp) -> Option<PublishData> {
+        let pkid = ack.pkid;
+        let position = self.recorded.iter().position(|p| p.publish.pkid == pkid);
+        if position.is_none() {
+            ack.reason = PubCompReason::PacketIdentifierNotFound;
+        }
+
         let ack = Ack::PubComp(ack);
         self.committed.push_back(ack);
-        self.recorded.pop_front()
+        self.recorded.remove(position?)
     }
 
     pub fn pingresp(&mut self, ack: PingResp) {
@@ -315,8 +530,13 @@ impl AckLog {
         self.committed.push_back(ack);
     }
 
-    pub fn unsuback(&mut self, ack: UnsubAck) {
-        let ack = Ack::UnsubAck(ack);
+    /// Properties are only written to v5 connections
+    pub fn unsuback(&mut self, ack: UnsubAck, properties: Option<UnsubAckProperties>) {
+        let ack = match properties {
+            Some(properties) => Ack::UnsubAckWithProperties(ack, properties),
+            None => Ack::UnsubAck(ack),
+        };
+
         self.committed.push_back(ack);
     }
 
@@ -327,8 +547,12 @@ impl AckLog {
 
 #[cfg(test)]
 mod test {
-    use super::DataLog;
-    use crate::RouterConfig;
+    use super::{decode_name, encode_name, AckLog, DataLog, PublishData};
+    use crate::protocol::{PubComp, PubCompReason, Publish, PublishProperties, QoS};
+    use crate::router::Ack;
+    use crate::segments::Storage;
+    use crate::{RouterConfig, SharedStrategy, Topic};
+    use std::collections::VecDeque;
 
     #[test]
     fn publish_filters_updating_correctly_on_new_topic_subscription() {
@@ -339,6 +563,14 @@ mod test {
             max_segment_count: 10,
             max_read_len: 1024,
             initialized_filters: None,
+            log_dir: None,
+            max_disk_segments: 0,
+            session_dir: None,
+            acl: None,
+            shared_strategy: SharedStrategy::RoundRobin,
+            sys_interval_secs: 0,
+            tenant_rate_limit: None,
+            session_sync_secs: 0,
         };
         let mut data = DataLog::new(config).unwrap();
         data.next_native_offset("topic/a");
@@ -349,6 +581,41 @@ mod test {
         assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 2);
     }
 
+    #[test]
+    fn sys_topics_only_match_sys_filters() {
+        let config = RouterConfig {
+            instant_ack: true,
+            max_segment_size: 1024,
+            max_connections: 10,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            initialized_filters: None,
+            log_dir: None,
+            max_disk_segments: 0,
+            session_dir: None,
+            acl: None,
+            shared_strategy: SharedStrategy::RoundRobin,
+            sys_interval_secs: 0,
+            tenant_rate_limit: None,
+            session_sync_secs: 0,
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let (all, _) = data.next_native_offset("#");
+        let (any, _) = data.next_native_offset("+/broker/uptime");
+        let (sys, _) = data.next_native_offset("$SYS/#");
+        let (uptime, _) = data.next_native_offset("$SYS/broker/+");
+
+        let mut filters = data.matches("$SYS/broker/uptime").unwrap();
+        filters.sort();
+        assert_eq!(filters, [sys, uptime]);
+
+        let mut filters = data.matches("hello/world").unwrap();
+        filters.sort();
+        assert_eq!(filters, [all]);
+        assert!(!data.matches("hello/broker/uptime").unwrap().contains(&sys));
+        assert!(data.matches("hello/broker/uptime").unwrap().contains(&any));
+    }
+
     #[test]
     fn publish_filters_updating_correctly_on_new_publish() {
         let config = RouterConfig {
@@ -358,6 +625,14 @@ mod test {
             max_segment_count: 10,
             max_read_len: 1024,
             initialized_filters: None,
+            log_dir: None,
+            max_disk_segments: 0,
+            session_dir: None,
+            acl: None,
+            shared_strategy: SharedStrategy::RoundRobin,
+            sys_interval_secs: 0,
+            tenant_rate_limit: None,
+            session_sync_secs: 0,
         };
         let mut data = DataLog::new(config).unwrap();
         data.next_native_offset("+/+");
@@ -367,6 +642,169 @@ mod test {
         assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 1);
     }
 
+    #[test]
+    fn expired_retained_publishes_are_not_replayed() {
+        let config = RouterConfig {
+            instant_ack: true,
+            max_segment_size: 1024,
+            max_connections: 10,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            initialized_filters: None,
+            log_dir: None,
+            max_disk_segments: 0,
+            session_dir: None,
+            acl: None,
+            shared_strategy: SharedStrategy::RoundRobin,
+            sys_interval_secs: 0,
+            tenant_rate_limit: None,
+            session_sync_secs: 0,
+        };
+        let mut data = DataLog::new(config).unwrap();
+
+        let publish = |topic: &'static str| Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic: topic.into(),
+            pkid: 0,
+            payload: vec![1, 2, 3].into(),
+        };
+        let properties = || {
+            Some(PublishProperties {
+                message_expiry_interval: Some(10),
+                ..Default::default()
+            })
+        };
+        let mut expired = PublishData::new(publish("hello/1"), properties());
+        expired.expiry = Some(1);
+        let live = PublishData::new(publish("hello/2"), properties());
+        data.insert_to_retained_publishes(expired, "hello/1".to_owned());
+        data.insert_to_retained_publishes(live.clone(), "hello/2".to_owned());
+
+        let (idx, cursor) = data.next_native_offset("hello/+");
+        data.handle_retained_messages("hello/+", &mut VecDeque::new());
+        let (_, publishes) = data.native_readv(idx, cursor, 10).unwrap();
+        assert_eq!(publishes, vec![live.clone()]);
+        assert_eq!(live.remaining(live.expiry.unwrap() - 10_000), Some(10));
+
+        // Expiry is written to disk along with the publish
+        assert_eq!(PublishData::deserialize(live.serialize()).unwrap(), live);
+    }
+
+    #[test]
+    fn properties_and_origin_are_stored_with_publish() {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: "hello/world".into(),
+            pkid: 1,
+            payload: vec![1, 2, 3].into(),
+        };
+
+        let properties = PublishProperties {
+            payload_format_indicator: Some(1),
+            topic_alias: Some(4),
+            response_topic: Some("hello/reply".to_owned()),
+            correlation_data: Some(vec![9, 9].into()),
+            user_properties: vec![("key".to_owned(), "value".to_owned())],
+            subscription_identifiers: vec![3],
+            content_type: Some("text/plain".to_owned()),
+            ..Default::default()
+        };
+
+        let mut data = PublishData::new(publish.clone(), Some(properties));
+        data.origin = Some("device/1".into());
+        let stored = data.properties.clone().unwrap();
+        assert_eq!(stored.topic_alias, None);
+        assert!(stored.subscription_identifiers.is_empty());
+        assert_eq!(stored.response_topic.as_deref(), Some("hello/reply"));
+        assert_eq!(PublishData::deserialize(data.serialize()).unwrap(), data);
+
+        // Truncated data is an error. Payload is the rest of the data, so data cut
+        // anywhere before it is invalid
+        let serialized = data.serialize();
+        assert_eq!(data.size(), serialized.len());
+        for len in 0..serialized.len() - data.publish.payload.len() {
+            assert!(PublishData::deserialize(serialized.slice(..len)).is_err());
+        }
+
+        let data = PublishData::new(publish, Some(PublishProperties::default()));
+        assert_eq!(data.properties, None);
+        assert_eq!(PublishData::deserialize(data.serialize()).unwrap(), data);
+    }
+
+    #[test]
+    fn retained_publishes_are_listed_and_cleared_by_filter() {
+        let config = RouterConfig {
+            instant_ack: true,
+            max_segment_size: 1024,
+            max_connections: 10,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            initialized_filters: None,
+            log_dir: None,
+            max_disk_segments: 0,
+            session_dir: None,
+            acl: None,
+            shared_strategy: SharedStrategy::RoundRobin,
+            sys_interval_secs: 0,
+            tenant_rate_limit: None,
+            session_sync_secs: 0,
+        };
+        let mut data = DataLog::new(config).unwrap();
+
+        for topic in ["hello/2", "hello/1", "world/1"] {
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: true,
+                topic: topic.into(),
+                pkid: 0,
+                payload: vec![1, 2, 3].into(),
+            };
+            data.insert_to_retained_publishes(PublishData::new(publish, None), topic.to_owned());
+        }
+
+        let topics: Vec<&Topic> = data
+            .retained_publishes("hello/+")
+            .into_iter()
+            .map(|(t, _)| t)
+            .collect();
+        assert_eq!(topics, ["hello/1", "hello/2"]);
+
+        assert_eq!(data.clear_retained_publishes("hello/#"), 2);
+        assert_eq!(data.clear_retained_publishes("hello/#"), 0);
+        assert_eq!(data.retained_publishes("#").len(), 1);
+    }
+
+    #[test]
+    fn pubrel_of_unknown_pkid_is_acked_with_failure() {
+        let mut acks = AckLog::new();
+        let pubcomp = PubComp {
+            pkid: 7,
+            reason: PubCompReason::Success,
+        };
+
+        assert!(acks.pubcomp(pubcomp).is_none());
+        match acks.readv().pop_front() {
+            Some(Ack::PubComp(pubcomp)) => {
+                assert_eq!(pubcomp.reason, PubCompReason::PacketIdentifierNotFound)
+            }
+            v => panic!("Unexpected ack {:?}", v),
+        }
+    }
+
+    #[test]
+    fn names_are_encoded_to_valid_file_names() {
+        for filter in ["hello/+/world", "a/#", "../..", "temp%sensor", "été/#"] {
+            let name = encode_name(filter);
+            assert!(!name.contains(['/', '.', '+', '#']));
+            assert_eq!(decode_name(&name).unwrap(), filter);
+        }
+    }
+
     //     #[test]
     //     fn appends_are_written_to_correct_commitlog() {
     //         pretty_env_logger::init();
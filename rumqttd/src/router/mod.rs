@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     fmt,
+    time::{Duration, SystemTime},
 };
 
 use bytes::Bytes;
@@ -8,31 +9,49 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     protocol::{
-        ConnAck, PingResp, PubAck, PubAckProperties, PubComp, PubCompProperties, PubRec,
-        PubRecProperties, PubRel, PubRelProperties, Publish, PublishProperties, SubAck,
-        SubAckProperties, UnsubAck,
+        ConnAck, ConnAckProperties, DisconnectProperties, DisconnectReasonCode, PingResp, PubAck,
+        PubAckProperties, PubComp, PubCompProperties, PubRec, PubRecProperties, PubRel,
+        PubRelProperties, Publish, PublishProperties, SubAck, SubAckProperties, UnsubAck,
+        UnsubAckProperties,
     },
-    ConnectionId, Filter, RouterConfig, RouterId,
+    AclRule, ConnectionId, Filter, RouterConfig, RouterId, Topic,
 };
 
+mod acl;
 mod connection;
 mod graveyard;
 pub mod iobufs;
+mod limiter;
 mod logs;
+pub mod prometheus;
 mod routing;
 mod scheduler;
+mod shared;
+mod timer;
 mod waiters;
 
-pub use connection::Connection;
+pub use connection::{Connection, Quota};
+pub(crate) use logs::PublishData;
 pub use routing::Router;
 pub use waiters::Waiters;
 
 use self::scheduler::Tracker;
 pub const MAX_SCHEDULE_ITERATIONS: usize = 100;
 pub const MAX_CHANNEL_CAPACITY: usize = 200;
+/// Slots of the delayed will timer wheel. Ticks are a second long
+pub const WILL_TIMER_SLOTS: usize = 60;
 
 pub(crate) type FilterIdx = usize;
 
+/// Milliseconds since unix epoch. Expiry deadlines use wall clock time as they
+/// are persisted across restarts
+pub(crate) fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(v) => v.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
 #[derive(Debug)]
 // TODO: Fix this
 #[allow(clippy::large_enum_variant)]
@@ -53,6 +72,12 @@ pub enum Event {
     Shadow(ShadowRequest),
     /// Get metrics of a connection or all connections
     Metrics(MetricsRequest),
+    /// Disconnect all the connections and stop the router
+    Shutdown,
+    /// Replace acl rules. Applies to publishes and subscriptions from now on
+    Acl(Option<Vec<AclRule>>),
+    /// Operator action from the console. Replied to with `MetricsReply::Admin`
+    Admin(AdminRequest),
 }
 
 /// Notification from router to connection
@@ -79,6 +104,13 @@ pub enum Notification {
     Metrics(MetricsReply),
     /// Shadow
     Shadow(ShadowReply),
+    /// Router closed the connection. Links write the disconnect and stop
+    Disconnect(DisconnectReasonCode),
+    /// Router closed the connection with a reason string (ignored in v4)
+    DisconnectWithProperties(DisconnectReasonCode, DisconnectProperties),
+    /// Router held back packets of the connection because it is over quota.
+    /// Links stop reading for this long and then notify the router again
+    Throttle(Duration),
     Unschedule,
 }
 
@@ -93,6 +125,7 @@ pub struct Forward {
 #[allow(clippy::enum_variant_names)]
 pub enum Ack {
     ConnAck(ConnectionId, ConnAck),
+    ConnAckWithProperties(ConnectionId, ConnAck, ConnAckProperties),
     PubAck(PubAck),
     PubAckWithProperties(PubAck, PubAckProperties),
     SubAck(SubAck),
@@ -104,12 +137,14 @@ pub enum Ack {
     PubComp(PubComp),
     PubCompWithProperties(PubComp, PubCompProperties),
     UnsubAck(UnsubAck),
+    UnsubAckWithProperties(UnsubAck, UnsubAckProperties),
     PingResp(PingResp),
 }
 
 fn packetid(ack: &Ack) -> u16 {
     match ack {
         Ack::ConnAck(..) => 0,
+        Ack::ConnAckWithProperties(..) => 0,
         Ack::PubAck(puback) => puback.pkid,
         Ack::PubAckWithProperties(puback, _) => puback.pkid,
         Ack::SubAck(suback) => suback.pkid,
@@ -121,6 +156,7 @@ fn packetid(ack: &Ack) -> u16 {
         Ack::PubComp(pubcomp) => pubcomp.pkid,
         Ack::PubCompWithProperties(pubcomp, _) => pubcomp.pkid,
         Ack::UnsubAck(unsuback) => unsuback.pkid,
+        Ack::UnsubAckWithProperties(unsuback, _) => unsuback.pkid,
         Ack::PingResp(_) => 0,
     }
 }
@@ -142,6 +178,28 @@ pub struct DataRequest {
     pub read_count: usize,
     /// Maximum count of payload buffer per replica
     max_count: usize,
+    /// Shared subscription (`$share/<group>/<filter>`) this request reads
+    /// `filter` for. Cursor of the request follows the cursor of the group
+    #[serde(default)]
+    pub share: Option<Filter>,
+    /// MQTT 5 No Local. Publishes of the subscribing client aren't forwarded
+    #[serde(default)]
+    pub nolocal: bool,
+    /// MQTT 5 Retain As Published. Forwards publishes with the retain flag
+    /// they were published with
+    #[serde(default)]
+    pub preserve_retain: bool,
+    /// Cursors of QoS 2 publishes at or after `cursor` which a resumed session
+    /// already received. They aren't sent again, their PubRel is resent instead
+    #[serde(default)]
+    pub received: Vec<(u64, u64)>,
+}
+
+impl DataRequest {
+    /// Filter the connection subscribed with
+    pub fn subscription(&self) -> &Filter {
+        self.share.as_ref().unwrap_or(&self.filter)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -221,6 +279,8 @@ pub struct RouterMetrics {
     pub total_subscriptions: usize,
     pub total_publishes: usize,
     pub failed_publishes: usize,
+    /// Connections refused because the router was at `max_connections`
+    pub refused_connections: usize,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -228,6 +288,7 @@ pub struct SubscriptionMeter {
     pub count: usize,
     pub total_size: usize,
     pub head_and_tail_id: (u64, u64),
+    pub disk_segments: usize,
     pub append_offset: (u64, u64),
     pub read_offset: usize,
 }
@@ -236,6 +297,9 @@ pub struct SubscriptionMeter {
 pub struct ConnectionMeter {
     publish_count: usize,
     publish_size: usize,
+    /// Publishes rejected or held back for being over quota
+    #[serde(default)]
+    throttled_count: usize,
     subscriptions: HashSet<Filter>,
     events: VecDeque<String>,
 }
@@ -249,6 +313,14 @@ impl ConnectionMeter {
         self.publish_size += size;
     }
 
+    pub fn increment_throttled_count(&mut self) {
+        self.throttled_count += 1
+    }
+
+    pub fn throttled_count(&self) -> usize {
+        self.throttled_count
+    }
+
     pub fn push_subscription(&mut self, filter: Filter) {
         self.subscriptions.insert(filter);
     }
@@ -278,6 +350,8 @@ pub enum MetricsRequest {
     Subscriptions,
     Subscription(Filter),
     Waiters(Filter),
+    /// Router, connection and subscription metrics in Prometheus text format
+    Prometheus,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -290,4 +364,45 @@ pub enum MetricsReply {
     Subscription(Option<SubscriptionMeter>),
     Waiters(Option<VecDeque<(String, DataRequest)>>),
     ReadyQueue(VecDeque<ConnectionId>),
+    Prometheus(String),
+    Admin(AdminReply),
+}
+
+#[derive(Debug, Clone)]
+pub enum AdminRequest {
+    /// Disconnect the client with this id. Its will is published
+    Disconnect(String),
+    /// Discard saved session of a disconnected client
+    DeleteSession(String),
+    /// Retained publishes with topics matching the filter, without payloads
+    Retained(Filter),
+    /// Retained publish on a topic along with its payload
+    RetainedPublish(Topic),
+    /// Remove retained publishes with topics matching the filter
+    ClearRetained(Filter),
+    /// Publish on behalf of the console connection
+    Publish(Publish),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminReply {
+    Done,
+    NotFound,
+    Retained(Vec<RetainedPublish>),
+    RetainedPublish(RetainedPublish),
+    Cleared(usize),
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetainedPublish {
+    pub topic: Topic,
+    pub qos: u8,
+    pub size: usize,
+    /// Seconds left before the publish expires
+    pub expiry_interval: Option<u32>,
+    /// Payload, lossily converted to utf-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
 }
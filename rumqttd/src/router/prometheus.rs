@@ -0,0 +1,145 @@
+use std::fmt::{Display, Write};
+
+/// Upper bounds of router loop latency buckets in seconds
+pub const LATENCY_BUCKETS: [f64; 10] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5,
+];
+
+/// Histogram with fixed buckets. Counts are per bucket and are made
+/// cumulative while encoding
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i] += 1;
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Writes metrics in Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buffer: String,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    /// Writes help and type of a metric. Samples of the metric follow
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.buffer, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.buffer, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.buffer.push_str(name);
+        if !labels.is_empty() {
+            self.buffer.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.buffer.push(',');
+                }
+
+                write!(self.buffer, "{}=\"{}\"", label, escape(value)).unwrap();
+            }
+            self.buffer.push('}');
+        }
+
+        writeln!(self.buffer, " {}", value).unwrap();
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: impl Display) {
+        self.family(name, "counter", help);
+        self.sample(name, &[], value);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.family(name, "histogram", help);
+
+        let bucket = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.bounds.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            self.sample(&bucket, &[("le", &bound.to_string())], cumulative);
+        }
+
+        self.sample(&bucket, &[("le", "+Inf")], histogram.count);
+        self.sample(&format!("{}_sum", name), &[], histogram.sum);
+        self.sample(&format!("{}_count", name), &[], histogram.count);
+    }
+
+    pub fn finish(self) -> String {
+        self.buffer
+    }
+}
+
+/// Escapes backslash, double quote and line feed in label values
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Encoder, Histogram};
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(0.7);
+        histogram.observe(5.0);
+
+        let mut encoder = Encoder::new();
+        encoder.histogram("latency", "Loop latency", &histogram);
+
+        let expected = "# HELP latency Loop latency
+# TYPE latency histogram
+latency_bucket{le=\"0.1\"} 1
+latency_bucket{le=\"1\"} 3
+latency_bucket{le=\"+Inf\"} 4
+latency_sum 6.25
+latency_count 4
+";
+        assert_eq!(encoder.finish(), expected);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut encoder = Encoder::new();
+        encoder.sample("publishes", &[("client_id", "a\"b\\c\n"), ("x", "y")], 10);
+        assert_eq!(
+            encoder.finish(),
+            "publishes{client_id=\"a\\\"b\\\\c\\n\",x=\"y\"} 10\n"
+        );
+    }
+}
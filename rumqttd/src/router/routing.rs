@@ -1,29 +1,36 @@
 use crate::protocol::{
-    ConnAck, ConnectReturnCode, Packet, PingResp, PubAck, PubAckReason, PubComp, PubCompReason,
-    PubRec, PubRecReason, PubRel, PubRelReason, Publish, QoS, SubAck, SubscribeReasonCode,
-    UnsubAck,
+    ConnAck, ConnectReturnCode, DisconnectProperties, DisconnectReasonCode, LastWill,
+    LastWillProperties, Packet, PingResp, PubAck, PubAckReason, PubComp, PubCompReason, PubRec,
+    PubRecReason, PubRel, PubRelReason, Publish, PublishProperties, QoS, RetainForwardRule, SubAck,
+    SubAckProperties, SubscribeReasonCode, UnsubAck, UnsubAckProperties, UnsubAckReason,
 };
 use crate::router::graveyard::SavedState;
 use crate::router::scheduler::{PauseReason, Tracker};
 use crate::router::Forward;
 use crate::segments::Position;
 use crate::*;
-use flume::{bounded, Receiver, RecvError, Sender, TryRecvError};
+use flume::{bounded, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
 use log::*;
 use slab::Slab;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::Utf8Error;
-use std::thread;
-use std::time::SystemTime;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 
+use super::acl;
 use super::graveyard::Graveyard;
 use super::iobufs::{Incoming, Outgoing};
-use super::logs::{AckLog, DataLog};
+use super::limiter::Limiter;
+use super::logs::{AckLog, DataLog, PublishData};
+use super::prometheus::{Encoder, Histogram, LATENCY_BUCKETS};
 use super::scheduler::{ScheduleReason, Scheduler};
+use super::shared::{self, Member, SharedGroup, Slot};
+use super::timer::TimerWheel;
 use super::{
-    packetid, Connection, DataRequest, Event, FilterIdx, MetricsReply, MetricsRequest,
-    Notification, RouterMetrics, ShadowRequest, MAX_CHANNEL_CAPACITY, MAX_SCHEDULE_ITERATIONS,
+    now_millis, packetid, Ack, AdminReply, AdminRequest, Connection, DataRequest, Event, FilterIdx,
+    MetricsReply, MetricsRequest, Notification, RetainedPublish, RouterMetrics, ShadowRequest,
+    SubscriptionMeter, MAX_CHANNEL_CAPACITY, MAX_SCHEDULE_ITERATIONS, WILL_TIMER_SLOTS,
 };
 
 #[derive(Error, Debug)]
@@ -40,10 +47,22 @@ pub enum RouterError {
     BadTenant(String, String),
     #[error("No matching filters to topic {0}")]
     NoMatchingFilters(String),
-    #[error("Unsupported QoS {0:?}")]
-    UnsupportedQoS(QoS),
     #[error("Invalid filter prefix {0}")]
     InvalidFilterPrefix(Filter),
+    #[error("Publish on reserved topic {0}")]
+    ReservedTopic(String),
+    #[error("No Local on shared subscription {0}")]
+    SharedNoLocal(Filter),
+}
+
+/// Last will of a disconnected client waiting for its delay interval
+#[derive(Debug)]
+struct DelayedWill {
+    deadline: Instant,
+    will: LastWill,
+    properties: Option<LastWillProperties>,
+    tenant_prefix: Option<String>,
+    dynamic_filters: bool,
 }
 
 pub struct Router {
@@ -59,6 +78,8 @@ pub struct Router {
     connection_map: HashMap<String, ConnectionId>,
     /// Subscription map to interested connection ids
     subscription_map: HashMap<Filter, HashSet<ConnectionId>>,
+    /// Shared subscription groups by `$share/<group>/<filter>`
+    shared_groups: HashMap<Filter, SharedGroup>,
     /// Incoming data grouped by connection
     ibufs: Slab<Incoming>,
     /// Outgoing data grouped by connection
@@ -83,6 +104,23 @@ pub struct Router {
     router_metrics: RouterMetrics,
     /// Buffer for cache exchange of incoming packets
     cache: Option<VecDeque<Packet>>,
+    /// Start time of the router, for uptime in broker statistics
+    started: Instant,
+    /// Time of next broker statistics publish. None when disabled
+    sys_deadline: Option<Instant>,
+    /// Time of next save of connected persistent sessions. None when disabled
+    session_sync_deadline: Option<Instant>,
+    /// Wills of disconnected clients which are published after their delay
+    /// interval, by client id. Reconnection of the client cancels its will
+    delayed_wills: HashMap<String, DelayedWill>,
+    /// Client ids of delayed wills by their deadlines
+    will_timers: TimerWheel<String>,
+    /// Publish quota shared by the clients of a tenant, by tenant prefix
+    tenant_limiters: HashMap<String, Limiter>,
+    /// Set after all the connections are closed for shutdown. Stops the router
+    shutdown: bool,
+    /// Time taken by iterations of the router loop, excluding waits for events
+    loop_latency: Histogram,
 }
 
 impl Router {
@@ -100,16 +138,43 @@ impl Router {
         };
 
         let max_connections = config.max_connections;
+        let sys_deadline = match config.sys_interval_secs {
+            0 => None,
+            _ => Some(Instant::now()),
+        };
+
+        let session_sync_deadline = match (&config.session_dir, config.session_sync_secs) {
+            (Some(_), secs) if secs > 0 => Some(Instant::now() + Duration::from_secs(secs)),
+            _ => None,
+        };
+
+        let mut graveyard = Graveyard::new(config.session_dir.clone());
+        let mut datalog = DataLog::new(config.clone()).unwrap();
+
+        // Filter indexes of sessions reloaded from disk belong to the previous run. Cursors
+        // beyond the reloaded commitlog point to data which was lost with memory segments
+        for saved in graveyard.iter_mut() {
+            for request in saved.tracker.data_requests.iter_mut() {
+                let (filter_idx, next_offset) = datalog.next_native_offset(&request.filter);
+                request.filter_idx = filter_idx;
+                if request.cursor > next_offset {
+                    request.cursor = next_offset;
+                    request.received.clear();
+                }
+            }
+        }
+
         Router {
             id: router_id,
-            config: config.clone(),
-            graveyard: Graveyard::new(),
+            config,
+            graveyard,
             connections,
             connection_map: Default::default(),
             subscription_map: Default::default(),
+            shared_groups: Default::default(),
             ibufs,
             obufs,
-            datalog: DataLog::new(config).unwrap(),
+            datalog,
             ackslog,
             scheduler: Scheduler::with_capacity(max_connections),
             notifications: VecDeque::with_capacity(1024),
@@ -117,6 +182,14 @@ impl Router {
             router_tx,
             router_metrics,
             cache: Some(VecDeque::with_capacity(MAX_CHANNEL_CAPACITY)),
+            started: Instant::now(),
+            sys_deadline,
+            session_sync_deadline,
+            delayed_wills: HashMap::new(),
+            will_timers: TimerWheel::new(WILL_TIMER_SLOTS, Duration::from_secs(1)),
+            tenant_limiters: HashMap::new(),
+            shutdown: false,
+            loop_latency: Histogram::new(&LATENCY_BUCKETS),
         }
     }
 
@@ -133,17 +206,17 @@ impl Router {
     /// Starts the router in a background thread and returns link to it. Link
     /// to communicate with router should only be returned only after it starts.
     /// For that reason, all the public methods should start the router in the
-    /// background
-    pub fn spawn(mut self) -> Sender<(ConnectionId, Event)> {
+    /// background. Thread handle is returned to join the router after shutdown
+    pub fn spawn(mut self) -> (JoinHandle<()>, Sender<(ConnectionId, Event)>) {
         let router = thread::Builder::new().name(format!("router-{}", self.id));
         let link = self.link();
-        router
-            .spawn(move || {
-                let e = self.run(0);
-                error!("Router done! Reason = {:?}", e);
+        let handle = router
+            .spawn(move || match self.run(0) {
+                Ok(_) => info!("Router stopped"),
+                Err(e) => error!("Router done! Reason = {:?}", e),
             })
             .unwrap();
-        link
+        (handle, link)
     }
 
     /// Waits on incoming events when ready queue is empty.
@@ -151,9 +224,11 @@ impl Router {
     /// before polling ready queue 100 times (connections)
     fn run(&mut self, count: usize) -> Result<(), RouterError> {
         match count {
-            0 => loop {
-                self.run_inner()?;
-            },
+            0 => {
+                while !self.shutdown {
+                    self.run_inner()?;
+                }
+            }
             n => {
                 for _ in 0..n {
                     self.run_inner()?;
@@ -165,10 +240,50 @@ impl Router {
     }
 
     fn run_inner(&mut self) -> Result<(), RouterError> {
+        if matches!(self.sys_deadline, Some(deadline) if deadline <= Instant::now()) {
+            self.publish_sys();
+        }
+
+        if matches!(self.will_timers.next_deadline(), Some(deadline) if deadline <= Instant::now())
+        {
+            self.publish_delayed_wills();
+        }
+
+        if matches!(self.session_sync_deadline, Some(deadline) if deadline <= Instant::now()) {
+            self.sync_sessions();
+        }
+
+        // Expired sessions are swept even if their clients never come back
+        if matches!(self.graveyard.next_deadline(), Some(deadline) if deadline <= Instant::now()) {
+            self.graveyard.purge(now_millis());
+        }
+
         // Block on incoming events if there are no ready connections for consumption
+        let mut start = Instant::now();
         if self.consume().is_none() {
             // trace!("{}:: {:20} {:20} {:?}", self.id, "", "done-await", self.readyqueue);
-            let (id, data) = self.router_rx.recv()?;
+            let deadline = [
+                self.sys_deadline,
+                self.session_sync_deadline,
+                self.will_timers.next_deadline(),
+                self.graveyard.next_deadline(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+
+            let (id, data) = match deadline {
+                // Wake up in time for next broker statistics publish, delayed will or
+                // session expiry
+                Some(deadline) => match self.router_rx.recv_deadline(deadline) {
+                    Ok(v) => v,
+                    Err(RecvTimeoutError::Timeout) => return Ok(()),
+                    Err(RecvTimeoutError::Disconnected) => return Err(RouterError::Disconnected),
+                },
+                None => self.router_rx.recv()?,
+            };
+
+            start = Instant::now();
             self.events(id, data);
         }
 
@@ -177,6 +292,10 @@ impl Router {
         // Accumulating more data lets requests retrieve bigger
         // bulks which in turn increases efficiency
         for _ in 0..500 {
+            if self.shutdown {
+                return Ok(());
+            }
+
             // All these methods will handle state and errors
             match self.router_rx.try_recv() {
                 Ok((id, data)) => self.events(id, data),
@@ -193,6 +312,7 @@ impl Router {
             self.consume();
         }
 
+        self.loop_latency.observe(start.elapsed().as_secs_f64());
         Ok(())
     }
 
@@ -210,59 +330,86 @@ impl Router {
                 retrieve_shadow(&mut self.datalog, &mut self.obufs[id], request)
             }
             Event::Metrics(metrics) => retrieve_metrics(id, self, metrics),
+            Event::Shutdown => self.handle_shutdown(),
+            Event::Acl(acl) => {
+                info!(
+                    "{:15.15}[I] {:20} rules = {:?}",
+                    "",
+                    "acl-reload",
+                    acl.as_ref().map(Vec::len)
+                );
+                self.config.acl = acl;
+            }
+            Event::Admin(request) => self.handle_admin(id, request),
         }
     }
 
     fn handle_new_connection(
         &mut self,
-        mut connection: Connection,
+        connection: Connection,
         incoming: Incoming,
-        outgoing: Outgoing,
+        mut outgoing: Outgoing,
     ) {
         let client_id = outgoing.client_id.clone();
 
-        if self.connections.len() >= self.config.max_connections {
+        // Client id is already connected within the tenant. Clients without client
+        // id are all different clients
+        let existing = self.connection_map.get(&client_id).copied().filter(|id| {
+            !client_id.is_empty() && self.connections[*id].client_id == connection.client_id
+        });
+
+        // Taking over a connection doesn't need a new slot
+        if self.connections.len() >= self.config.max_connections && existing.is_none() {
             error!(
                 "{:15.15}[E] {:20}",
                 client_id, "no space for new connection"
             );
-            // let ack = ConnectionAck::Failure("No space for new connection".to_owned());
-            // let message = Notification::ConnectionAck(ack);
+            self.router_metrics.refused_connections += 1;
+
+            // Link is waiting for this ack to learn whether it got registered
+            let ack = ConnAck {
+                session_present: false,
+                code: ConnectReturnCode::ServiceUnavailable,
+            };
+
+            outgoing.push_notification(Notification::DeviceAck(Ack::ConnAck(0, ack)));
+            outgoing.handle.try_send(()).ok();
             return;
         }
 
-        // Retrieve previous connection state from graveyard
-        let saved = self.graveyard.retrieve(&client_id);
         let clean_session = connection.clean;
-        let previous_session = saved.is_some();
-        let tracker = if !clean_session {
-            let saved = saved.map_or(SavedState::new(client_id.clone()), |s| s);
-            connection.subscriptions = saved.subscriptions;
-            connection.meter = saved.metrics;
-            saved.tracker
-        } else {
-            // Only retrieve metrics in clean session
-            let saved = saved.map_or(SavedState::new(client_id.clone()), |s| s);
-            connection.meter = saved.metrics;
-            connection.meter.subscriptions.clear();
-            Tracker::new(client_id.clone())
-        };
-        let ackslog = AckLog::new();
-
         let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(v) => v.as_millis().to_string(),
             Err(e) => format!("Time error = {:?}", e),
         };
 
-        let event = "connection at ".to_owned() + &time + ", clean = " + &clean_session.to_string();
-        connection.meter.push_event(event);
-        connection
-            .meter
-            .push_subscriptions(connection.subscriptions.clone());
+        // Reconnection within the delay interval cancels the will of previous connection
+        if self.delayed_wills.remove(&client_id).is_some() {
+            info!("{:15.15}[I] {:20}", client_id, "will-cancelled");
+        }
 
+        // Slots are taken before an existing connection is closed, so the new connection
+        // never gets its id. Events which the old link queued before it learns about the
+        // takeover can't act on the new connection
         let connection_id = self.connections.insert(connection);
         assert_eq!(self.ibufs.insert(incoming), connection_id);
         assert_eq!(self.obufs.insert(outgoing), connection_id);
+        assert_eq!(self.ackslog.insert(AckLog::new()), connection_id);
+        let tracker = Tracker::new(client_id.clone());
+        assert_eq!(self.scheduler.add(tracker), connection_id);
+
+        // The existing connection is closed and its session is saved before the graveyard
+        // is looked up below, so the new connection takes the session over before it is
+        // acked. Clean start ends the session, which publishes the will without delay.
+        // Otherwise the session carries on and the will isn't published
+        if let Some(existing) = existing {
+            info!("{:15.15}[I] {:20} id = {}", client_id, "takeover", existing);
+            let reason = DisconnectReasonCode::SessionTakenOver;
+            self.close(existing, reason, None, clean_session);
+            if let Some(will) = self.delayed_wills.remove(&client_id) {
+                self.publish_will(&client_id, will);
+            }
+        }
 
         self.connection_map.insert(client_id.clone(), connection_id);
         info!(
@@ -270,8 +417,43 @@ impl Router {
             client_id, "connect", connection_id
         );
 
-        assert_eq!(self.ackslog.insert(ackslog), connection_id);
-        assert_eq!(self.scheduler.add(tracker), connection_id);
+        // Retrieve previous connection state from graveyard
+        let saved = self.graveyard.retrieve(&client_id);
+        let previous_session = matches!(&saved, Some(s) if !s.clean);
+        let saved = saved.unwrap_or_else(|| SavedState::new(client_id.clone()));
+        let connection = &mut self.connections[connection_id];
+        connection.meter = saved.metrics;
+        let (tracker, pubrels) = if !clean_session {
+            connection.subscriptions = saved.subscriptions;
+            (saved.tracker, saved.pubrels)
+        } else {
+            // Only retrieve metrics in clean session
+            connection.meter.subscriptions.clear();
+            (Tracker::new(client_id.clone()), Vec::new())
+        };
+
+        let event = "connection at ".to_owned() + &time + ", clean = " + &clean_session.to_string();
+        connection.meter.push_event(event);
+        connection
+            .meter
+            .push_subscriptions(connection.subscriptions.clone());
+
+        // Rejoin shared subscription groups of the saved session
+        for request in tracker.data_requests.iter() {
+            if let Some(share) = &request.share {
+                let strategy = self.config.shared_strategy;
+                self.shared_groups
+                    .entry(share.clone())
+                    .or_insert_with(|| SharedGroup::new(request.cursor, strategy))
+                    .join(Member {
+                        id: connection_id,
+                        qos: request.qos,
+                        preserve_retain: request.preserve_retain,
+                    });
+            }
+        }
+
+        self.scheduler.trackers[connection_id] = tracker;
 
         // Check if there are multiple data requests on same filter.
         debug_assert!(self.scheduler.check_tracker_duplicates(connection_id));
@@ -284,6 +466,22 @@ impl Router {
         let ackslog = self.ackslog.get_mut(connection_id).unwrap();
         ackslog.connack(connection_id, ack);
 
+        // Resend PubRels of QoS 2 publishes which weren't completed by the
+        // previous connection
+        let outgoing = &mut self.obufs[connection_id];
+        for pkid in pubrels {
+            if outgoing.resume_pubrel(pkid).is_none() {
+                warn!(
+                    "{:15.15}[W] {:20} pkid = {:?}",
+                    client_id, "dropping-pubrel", pkid
+                );
+                continue;
+            }
+
+            let reason = PubRelReason::Success;
+            ackslog.pubrel(PubRel { pkid, reason });
+        }
+
         self.scheduler
             .reschedule(connection_id, ScheduleReason::Init);
     }
@@ -310,8 +508,8 @@ impl Router {
         // Remove connection from router
         let mut connection = self.connections.remove(id);
         let _incoming = self.ibufs.remove(id);
-        let _outgoing = self.obufs.remove(id);
-        let mut tracker = self.scheduler.remove(id);
+        let outgoing = self.obufs.remove(id);
+        let tracker = self.scheduler.remove(id);
         self.connection_map.remove(&client_id);
         self.ackslog.remove(id);
 
@@ -327,6 +525,8 @@ impl Router {
             if let Some(connections) = self.subscription_map.get_mut(filter) {
                 connections.remove(&id);
             }
+
+            leave_shared_group(&mut self.shared_groups, id, filter);
         }
 
         // Add disconnection event to metrics
@@ -338,20 +538,147 @@ impl Router {
         let event = "disconnection at ".to_owned() + &time;
         connection.meter.push_event(event);
 
-        // Save state for persistent sessions
-        if !connection.clean {
-            // Add inflight data requests back to tracker
-            inflight_data_requests
-                .into_iter()
-                .for_each(|r| tracker.register_data_request(r));
-
-            self.graveyard
-                .save(tracker, connection.subscriptions, connection.meter);
+        // Save state for persistent sessions. Session expiry interval of 0 ends
+        // the session with the connection
+        if !connection.clean && connection.session_expiry_interval != Some(0) {
+            let tracker = saved_tracker(tracker, inflight_data_requests, &outgoing);
+            self.graveyard.save(
+                tracker,
+                outgoing.pubrels(),
+                connection.subscriptions,
+                connection.meter,
+                false,
+                connection.session_expiry_interval,
+            );
         } else {
             // Only save metrics in clean session
             connection.meter.subscriptions.clear();
-            self.graveyard
-                .save(Tracker::new(client_id), HashSet::new(), connection.meter);
+            self.graveyard.save(
+                Tracker::new(client_id),
+                Vec::new(),
+                HashSet::new(),
+                connection.meter,
+                true,
+                None,
+            );
+        }
+    }
+
+    /// Flushes pending acks of all the connections, asks their links to
+    /// disconnect and saves persistent sessions before stopping the router
+    fn handle_shutdown(&mut self) {
+        info!(
+            "{:15.15}[I] {:20} connections = {}",
+            "",
+            "shutdown",
+            self.obufs.len()
+        );
+
+        let ids: Vec<ConnectionId> = self.obufs.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            self.close(id, DisconnectReasonCode::ServerShuttingDown, None, false);
+        }
+
+        self.shutdown = true;
+    }
+
+    /// Flushes pending acks of the connection and asks its link to disconnect
+    /// with `reason` before removing the connection
+    fn close(
+        &mut self,
+        id: ConnectionId,
+        reason: DisconnectReasonCode,
+        reason_string: Option<String>,
+        execute_will: bool,
+    ) {
+        let outgoing = &mut self.obufs[id];
+        ack_device_data(&mut self.ackslog[id], outgoing);
+
+        let notification = match reason_string {
+            Some(reason_string) => {
+                let properties = DisconnectProperties {
+                    session_expiry_interval: None,
+                    reason_string: Some(reason_string),
+                    user_properties: vec![],
+                    server_reference: None,
+                };
+
+                Notification::DisconnectWithProperties(reason, properties)
+            }
+            None => Notification::Disconnect(reason),
+        };
+
+        outgoing.push_notification(notification);
+        outgoing.handle.try_send(()).ok();
+        self.handle_disconnection(id, execute_will);
+    }
+
+    /// Runs an operator action and replies to the requesting connection
+    fn handle_admin(&mut self, id: ConnectionId, request: AdminRequest) {
+        info!("{:15.15}[I] {:20} request = {:?}", "", "admin", request);
+
+        let reply = match request {
+            AdminRequest::Disconnect(client_id) => match self.connection_map.get(&client_id) {
+                Some(&connection_id) => {
+                    let reason = DisconnectReasonCode::AdministrativeAction;
+                    self.close(connection_id, reason, None, true);
+                    AdminReply::Done
+                }
+                None => AdminReply::NotFound,
+            },
+            AdminRequest::DeleteSession(client_id) => match self.graveyard.remove(&client_id) {
+                true => AdminReply::Done,
+                false => AdminReply::NotFound,
+            },
+            AdminRequest::Retained(filter) => {
+                let now = now_millis();
+                let retained = self
+                    .datalog
+                    .retained_publishes(&filter)
+                    .into_iter()
+                    .map(|(topic, data)| retained_publish(topic, data, now, false))
+                    .collect();
+
+                AdminReply::Retained(retained)
+            }
+            AdminRequest::RetainedPublish(topic) => {
+                let now = now_millis();
+                match self.datalog.retained_publishes(&topic).first() {
+                    Some((_, data)) => {
+                        AdminReply::RetainedPublish(retained_publish(&topic, data, now, true))
+                    }
+                    None => AdminReply::NotFound,
+                }
+            }
+            AdminRequest::ClearRetained(filter) => {
+                AdminReply::Cleared(self.datalog.clear_retained_publishes(&filter))
+            }
+            AdminRequest::Publish(publish) => {
+                let publish = PublishData::new(publish, None);
+                match append_to_commitlog(
+                    id,
+                    publish,
+                    &mut self.datalog,
+                    &mut self.notifications,
+                    &mut self.connections,
+                ) {
+                    Ok(_offset) => {
+                        // Prepare all the consumers which are waiting for new data
+                        while let Some((id, request)) = self.notifications.pop_front() {
+                            self.scheduler.track(id, request);
+                            self.scheduler.reschedule(id, ScheduleReason::FreshData);
+                        }
+
+                        AdminReply::Done
+                    }
+                    Err(e) => AdminReply::Failed(e.to_string()),
+                }
+            }
+        };
+
+        // Requesting connection is gone if it disconnected itself
+        if let Some(connection) = self.connections.get_mut(id) {
+            connection.metrics.try_send(MetricsReply::Admin(reply)).ok();
         }
     }
 
@@ -377,12 +704,16 @@ impl Router {
         let mut new_data = false;
         let mut disconnect = false;
         let mut execute_will = true;
+        // Reason sent to the client when the router disconnects it (ignored in v4)
+        let mut reason = None;
+        // Delay of packets held back for being over quota
+        let mut throttle = None;
 
         // info!("{:15.15}[I] {:20} count = {}", client_id, "packets", packets.len());
 
-        for packet in packets.drain(0..) {
+        while let Some(packet) = packets.pop_front() {
             match packet {
-                Packet::Publish(publish, _) => {
+                Packet::Publish(publish, properties) => {
                     trace!(
                         "{:15.15}[I] {:20} {:?}",
                         client_id,
@@ -394,6 +725,68 @@ impl Router {
                     let qos = publish.qos;
                     let pkid = publish.pkid;
 
+                    // Drop publishes which aren't allowed by acl. Clients are informed with
+                    // a failure reason code in the ack (ignored in v4)
+                    if !self.can_publish(id, &publish.topic) {
+                        warn!(
+                            "{:15.15}[E] {:20} topic = {:?}",
+                            client_id, "acl-denied", publish.topic
+                        );
+                        self.router_metrics.failed_publishes += 1;
+
+                        let ackslog = self.ackslog.get_mut(id).unwrap();
+                        match qos {
+                            QoS::AtLeastOnce => ackslog.puback(PubAck {
+                                pkid,
+                                reason: PubAckReason::NotAuthorized,
+                            }),
+                            QoS::ExactlyOnce => ackslog.pubrec_failure(PubRec {
+                                pkid,
+                                reason: PubRecReason::NotAuthorized,
+                            }),
+                            QoS::AtMostOnce => continue,
+                        }
+
+                        force_ack = true;
+                        continue;
+                    }
+
+                    // Publishes over quota are rejected with a reason code or held back
+                    // along with the rest of the batch until the quota allows them
+                    if let Some(delay) = self.take_quota(id, size) {
+                        let connection = self.connections.get_mut(id).unwrap();
+                        connection.meter.increment_throttled_count();
+                        if !connection.quota.is_some_and(|quota| quota.reject) {
+                            packets.push_front(Packet::Publish(publish, properties));
+                            throttle = Some(delay);
+                            break;
+                        }
+
+                        debug!(
+                            "{:15.15}[E] {:20} topic = {:?}",
+                            client_id, "quota-exceeded", publish.topic
+                        );
+
+                        let ackslog = self.ackslog.get_mut(id).unwrap();
+                        match qos {
+                            QoS::AtLeastOnce => ackslog.puback(PubAck {
+                                pkid,
+                                reason: PubAckReason::QuotaExceeded,
+                            }),
+                            QoS::ExactlyOnce => ackslog.pubrec_failure(PubRec {
+                                pkid,
+                                reason: PubRecReason::QuotaExceeded,
+                            }),
+                            QoS::AtMostOnce => continue,
+                        }
+
+                        force_ack = true;
+                        continue;
+                    }
+
+                    // Message expiry of MQTT 5 publishes counts from the time they are received
+                    let publish = PublishData::new(publish, properties);
+
                     // Prepare acks for the above publish
                     // If any of the publish in the batch results in force flush,
                     // set global force flush flag. Force flush is triggered when the
@@ -409,6 +802,8 @@ impl Router {
                     // multiple commit logs.
 
                     match qos {
+                        // Without instant ack, publish is acked after it is appended
+                        QoS::AtLeastOnce if !self.config.instant_ack => (),
                         QoS::AtLeastOnce => {
                             let puback = PubAck {
                                 pkid,
@@ -450,6 +845,17 @@ impl Router {
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
                             new_data = true;
+
+                            if qos == QoS::AtLeastOnce && !self.config.instant_ack {
+                                let puback = PubAck {
+                                    pkid,
+                                    reason: PubAckReason::Success,
+                                };
+
+                                let ackslog = self.ackslog.get_mut(id).unwrap();
+                                ackslog.puback(puback);
+                                force_ack = true;
+                            }
                         }
                         Err(e) => {
                             // Disconnect on bad publishes
@@ -458,6 +864,7 @@ impl Router {
                                 client_id, "append-fail", e
                             );
                             self.router_metrics.failed_publishes += 1;
+                            reason = Some(disconnect_reason(&e));
                             disconnect = true;
                             break;
                         }
@@ -477,6 +884,7 @@ impl Router {
                 }
                 Packet::Subscribe(s, _) => {
                     let mut return_codes = Vec::new();
+                    let mut failures = Vec::new();
                     let pkid = s.pkid;
                     // let len = s.len();
 
@@ -485,25 +893,54 @@ impl Router {
                             "{:15.15}[I] {:20} filter = {}",
                             client_id, "subscribe", f.path
                         );
+                        // Shared subscriptions are checked against the filter they share
+                        let filter = shared::split(&f.path).map_or(f.path.as_str(), |(_, f)| f);
+                        if !self.can_subscribe(id, filter) {
+                            warn!(
+                                "{:15.15}[E] {:20} filter = {}",
+                                client_id, "acl-denied", f.path
+                            );
+                            return_codes.push(SubscribeReasonCode::NotAuthorized);
+                            failures.push(format!("Not authorized to subscribe to {}", f.path));
+                            continue;
+                        }
+
                         let connection = self.connections.get_mut(id).unwrap();
 
                         if let Err(e) = validate_subscription(connection, &f) {
                             let id = &self.ibufs[id].client_id;
                             error!("{:15.15}[E] {:20} error = {:?}", id, "bad-subscription", e);
-                            disconnect = true;
-                            break;
+                            return_codes.push(SubscribeReasonCode::TopicFilterInvalid);
+                            failures.push(e.to_string());
+                            continue;
                         }
 
-                        let filter = f.path;
+                        let subscription = f.path.clone();
                         let qos = f.qos;
 
                         // Update metrics
-                        connection.meter.push_subscription(filter.clone());
+                        connection.meter.push_subscription(subscription.clone());
+
+                        // Retain Handling of MQTT 5 decides whether retained publishes
+                        // are replayed. v4 subscriptions get them on every subscribe
+                        let replay = match f.retain_forward_rule {
+                            RetainForwardRule::OnEverySubscribe => true,
+                            RetainForwardRule::OnNewSubscribe => {
+                                !connection.subscriptions.contains(&subscription)
+                            }
+                            RetainForwardRule::Never => false,
+                        };
 
-                        let (idx, cursor) = self.datalog.next_native_offset(&filter);
-                        self.prepare_filter(id, cursor, idx, filter.clone(), qos as u8);
-                        self.datalog
-                            .handle_retained_messages(&filter, &mut self.notifications);
+                        // Shared subscriptions read from the commitlog of the filter
+                        // they share and don't get retained publishes
+                        let shared = shared::split(&subscription);
+                        let filter = shared.map_or(subscription.as_str(), |(_, f)| f);
+                        let (idx, cursor) = self.datalog.next_native_offset(filter);
+                        self.prepare_filter(id, cursor, idx, &f);
+                        if shared.is_none() && replay {
+                            self.datalog
+                                .handle_retained_messages(filter, &mut self.notifications);
+                        }
 
                         let code = match qos {
                             QoS::AtMostOnce => SubscribeReasonCode::QoS0,
@@ -518,8 +955,13 @@ impl Router {
                     // meter.total_size += len;
 
                     let suback = SubAck { pkid, return_codes };
+                    let properties = reason_string(failures).map(|reason| SubAckProperties {
+                        reason_string: Some(reason),
+                        user_properties: vec![],
+                    });
+
                     let ackslog = self.ackslog.get_mut(id).unwrap();
-                    ackslog.suback(suback);
+                    ackslog.suback(suback, properties);
                     force_ack = true;
                 }
                 Packet::Unsubscribe(unsubscribe) => {
@@ -529,44 +971,49 @@ impl Router {
                     );
                     let connection = self.connections.get_mut(id).unwrap();
                     let pkid = unsubscribe.pkid;
+                    let mut reasons = Vec::with_capacity(unsubscribe.filters.len());
+                    let mut failures = Vec::new();
                     for filter in unsubscribe.filters {
-                        if let Some(connection_ids) = self.subscription_map.get_mut(&filter) {
-                            let removed = connection_ids.remove(&id);
-                            if !removed {
-                                continue;
-                            }
+                        let subscribed = self
+                            .subscription_map
+                            .get_mut(&filter)
+                            .is_some_and(|connection_ids| connection_ids.remove(&id));
 
-                            connection.meter.remove_subscription(filter.clone());
-                            let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
-                            meter.subscribe_count -= 1;
-
-                            let outgoing = self.obufs.get_mut(id).unwrap();
-
-                            if connection.subscriptions.contains(&filter) {
-                                connection.subscriptions.remove(&filter);
-                                debug!(
-                                    "{:15.15}[I] {:20} filter = {}",
-                                    outgoing.client_id, "unsubscribe", filter
-                                );
-                            } else {
-                                error!(
-                                    "{:15.15}[E] {:20} pkid = {:?}",
-                                    id, "unsubscribe-failed", unsubscribe.pkid
-                                );
-                                continue;
-                            }
-                            let unsuback = UnsubAck {
-                                pkid,
-                                // reasons are used in MQTTv5
-                                reasons: vec![],
-                            };
-                            let ackslog = self.ackslog.get_mut(id).unwrap();
-                            ackslog.unsuback(unsuback);
-                            self.scheduler.untrack(id, &filter);
-                            self.datalog.remove_waiters_for_id(id, &filter);
-                            force_ack = true;
+                        if !subscribed || !connection.subscriptions.remove(&filter) {
+                            error!(
+                                "{:15.15}[E] {:20} filter = {}",
+                                client_id, "unsubscribe-failed", filter
+                            );
+                            reasons.push(UnsubAckReason::NoSubscriptionExisted);
+                            failures.push(format!("No subscription to {}", filter));
+                            continue;
                         }
+
+                        debug!(
+                            "{:15.15}[I] {:20} filter = {}",
+                            client_id, "unsubscribe", filter
+                        );
+
+                        connection.meter.remove_subscription(filter.clone());
+                        let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
+                        meter.subscribe_count -= 1;
+
+                        self.scheduler.untrack(id, &filter);
+                        self.datalog.remove_waiters_for_id(id, &filter);
+                        leave_shared_group(&mut self.shared_groups, id, &filter);
+                        reasons.push(UnsubAckReason::Success);
                     }
+
+                    // Unsubscribe is always acked. Reasons are used in MQTTv5
+                    let unsuback = UnsubAck { pkid, reasons };
+                    let properties = reason_string(failures).map(|reason| UnsubAckProperties {
+                        reason_string: Some(reason),
+                        user_properties: vec![],
+                    });
+
+                    let ackslog = self.ackslog.get_mut(id).unwrap();
+                    ackslog.unsuback(unsuback, properties);
+                    force_ack = true;
                 }
                 Packet::PubAck(puback, _) => {
                     let outgoing = self.obufs.get_mut(id).unwrap();
@@ -576,6 +1023,8 @@ impl Router {
                             "{:15.15}[E] {:20} pkid = {:?}",
                             id, "unsolicited/ooo ack", pkid
                         );
+                        let code = DisconnectReasonCode::ProtocolError;
+                        reason = Some((code, format!("Unsolicited ack for pkid {}", pkid)));
                         disconnect = true;
                         break;
                     }
@@ -585,15 +1034,35 @@ impl Router {
                 Packet::PubRec(pubrec, _) => {
                     let outgoing = self.obufs.get_mut(id).unwrap();
                     let pkid = pubrec.pkid;
-                    if outgoing.register_ack(pkid).is_none() {
+
+                    // PubRec with a failure reason code ends the exchange. No PubRel
+                    // is sent and the packet id is free for reuse
+                    let failed = !matches!(
+                        pubrec.reason,
+                        PubRecReason::Success | PubRecReason::NoMatchingSubscribers
+                    );
+
+                    let registered = match failed {
+                        true => outgoing.register_ack(pkid),
+                        false => outgoing.register_pubrec(pkid),
+                    };
+
+                    if registered.is_none() {
                         error!(
                             "{:15.15}[E] {:20} pkid = {:?}",
                             id, "unsolicited/ooo ack", pkid
                         );
+                        let code = DisconnectReasonCode::ProtocolError;
+                        reason = Some((code, format!("Unsolicited ack for pkid {}", pkid)));
                         disconnect = true;
                         break;
                     }
 
+                    if failed {
+                        self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                        continue;
+                    }
+
                     let ackslog = self.ackslog.get_mut(id).unwrap();
                     let pubrel = PubRel {
                         pkid: pubrec.pkid,
@@ -602,20 +1071,20 @@ impl Router {
 
                     ackslog.pubrel(pubrel);
                     self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                    force_ack = true;
                 }
-                Packet::PubRel(pubrel, None) => {
+                Packet::PubRel(pubrel, _) => {
                     let ackslog = self.ackslog.get_mut(id).unwrap();
                     let pubcomp = PubComp {
                         pkid: pubrel.pkid,
                         reason: PubCompReason::Success,
                     };
 
+                    // PubRel of an unknown pkid is answered with a failure PubComp
+                    force_ack = true;
                     let publish = match ackslog.pubcomp(pubcomp) {
                         Some(v) => v,
-                        None => {
-                            disconnect = true;
-                            break;
-                        }
+                        None => continue,
                     };
 
                     // Try to append publish to commitlog
@@ -639,12 +1108,28 @@ impl Router {
                                 client_id, "append-fail", e
                             );
                             self.router_metrics.failed_publishes += 1;
+                            reason = Some(disconnect_reason(&e));
                             disconnect = true;
                             break;
                         }
                     };
                 }
-                Packet::PubComp(_pubcomp, _) => {}
+                Packet::PubComp(pubcomp, _) => {
+                    let outgoing = self.obufs.get_mut(id).unwrap();
+                    let pkid = pubcomp.pkid;
+                    if outgoing.register_pubcomp(pkid).is_none() {
+                        error!(
+                            "{:15.15}[E] {:20} pkid = {:?}",
+                            id, "unsolicited/ooo ack", pkid
+                        );
+                        let code = DisconnectReasonCode::ProtocolError;
+                        reason = Some((code, format!("Unsolicited ack for pkid {}", pkid)));
+                        disconnect = true;
+                        break;
+                    }
+
+                    self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
+                }
                 Packet::PingReq(_) => {
                     let ackslog = self.ackslog.get_mut(id).unwrap();
                     ackslog.pingresp(PingResp);
@@ -662,6 +1147,18 @@ impl Router {
             }
         }
 
+        // Link stops reading and notifies the router again after the delay
+        match throttle {
+            Some(delay) => {
+                self.ibufs.get_mut(id).unwrap().hold(&mut packets);
+                let outgoing = self.obufs.get_mut(id).unwrap();
+                outgoing.push_notification(Notification::Throttle(delay));
+                outgoing.handle.try_send(()).ok();
+            }
+            // Packets after a disconnect are dropped
+            None => packets.clear(),
+        }
+
         self.cache = Some(packets);
 
         // Prepare AcksRequest in tracker if router is operating in a
@@ -685,8 +1182,84 @@ impl Router {
         // on say 5th packet should not block new data notifications for packets
         // 1 - 4. Hence we use a flag instead of diconnecting immediately
         if disconnect {
-            self.handle_disconnection(id, execute_will);
+            match reason {
+                Some((code, reason)) => self.close(id, code, Some(reason), execute_will),
+                None => self.handle_disconnection(id, execute_will),
+            }
+        }
+    }
+
+    /// Takes a publish of `size` bytes from the quota of connection `id` and
+    /// of its tenant. Returns the time to wait when either of them is over quota
+    fn take_quota(&mut self, id: ConnectionId, size: usize) -> Option<Duration> {
+        let connection = self.connections.get_mut(id).unwrap();
+        // Connections without quota aren't limited
+        connection.quota?;
+
+        let tenant = match (&self.config.tenant_rate_limit, &connection.tenant_prefix) {
+            (Some(limit), Some(prefix)) => Some(
+                self.tenant_limiters
+                    .entry(prefix.clone())
+                    .or_insert_with(|| Limiter::new(*limit)),
+            ),
+            _ => None,
+        };
+
+        let now = Instant::now();
+        let mut limiters: Vec<&mut Limiter> = connection.limiter.iter_mut().chain(tenant).collect();
+        let wait = limiters
+            .iter_mut()
+            .map(|limiter| limiter.wait(size, now))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+
+        for limiter in limiters {
+            limiter.take(size);
+        }
+
+        None
+    }
+
+    /// Checks acl for a publish of connection `id`. Everything is allowed when
+    /// acl isn't configured and for local connections
+    fn can_publish(&self, id: ConnectionId, topic: &[u8]) -> bool {
+        let rules = match &self.config.acl {
+            Some(rules) => rules,
+            None => return true,
+        };
+
+        let connection = &self.connections[id];
+        if connection.local {
+            return true;
         }
+
+        let username = connection.username.as_deref();
+        match std::str::from_utf8(topic) {
+            Ok(topic) => acl::can_publish(rules, &connection.client_id, username, topic),
+            Err(_) => false,
+        }
+    }
+
+    /// Checks acl for a subscription of connection `id`. Everything is allowed
+    /// for local connections. `$SYS` needs an explicit allow rule, everything
+    /// else is allowed when acl isn't configured
+    fn can_subscribe(&self, id: ConnectionId, filter: &str) -> bool {
+        let connection = &self.connections[id];
+        if connection.local {
+            return true;
+        }
+
+        let rules = match &self.config.acl {
+            Some(rules) => rules,
+            None => return !sys_filter(filter),
+        };
+
+        let username = connection.username.as_deref();
+        acl::can_subscribe(rules, &connection.client_id, username, filter)
     }
 
     /// Apply filter and prepare this connection to receive subscription data
@@ -695,9 +1268,11 @@ impl Router {
         id: ConnectionId,
         cursor: Offset,
         filter_idx: FilterIdx,
-        filter: String,
-        qos: u8,
+        subscription: &protocol::Filter,
     ) {
+        let filter = subscription.path.clone();
+        let qos = subscription.qos as u8;
+
         // Add connection id to subscription list
         match self.subscription_map.get_mut(&filter) {
             Some(connections) => {
@@ -715,6 +1290,25 @@ impl Router {
 
         if !connection.subscriptions.contains(&filter) {
             connection.subscriptions.insert(filter.clone());
+
+            // Members of a shared subscription group read for the whole group
+            let (filter, share) = match shared::split(&filter) {
+                Some((_, shared_filter)) => {
+                    let strategy = self.config.shared_strategy;
+                    self.shared_groups
+                        .entry(filter.clone())
+                        .or_insert_with(|| SharedGroup::new(cursor, strategy))
+                        .join(Member {
+                            id,
+                            qos,
+                            preserve_retain: subscription.preserve_retain,
+                        });
+
+                    (shared_filter.to_owned(), Some(filter))
+                }
+                None => (filter, None),
+            };
+
             let request = DataRequest {
                 filter,
                 filter_idx,
@@ -722,6 +1316,10 @@ impl Router {
                 cursor,
                 read_count: 0,
                 max_count: 100,
+                share,
+                nolocal: subscription.nolocal,
+                preserve_retain: subscription.preserve_retain,
+                received: Vec::new(),
             };
 
             self.scheduler.track(id, request);
@@ -782,7 +1380,18 @@ impl Router {
                 }
             };
 
-            match forward_device_data(&mut request, datalog, outgoing) {
+            let status = match request.share {
+                Some(_) => forward_shared_data(
+                    id,
+                    &mut request,
+                    &mut self.shared_groups,
+                    datalog,
+                    &mut self.obufs,
+                ),
+                None => forward_device_data(&mut request, datalog, &mut self.obufs[id]),
+            };
+
+            match status {
                 ConsumeStatus::BufferFull => {
                     requests.push_back(request);
                     self.scheduler.pause(id, PauseReason::Busy);
@@ -797,7 +1406,7 @@ impl Router {
                     let filter = &request.filter;
                     trace!(
                         "{:15.15}[S] {:20} f = {filter}",
-                        outgoing.client_id,
+                        self.obufs[id].client_id,
                         "caughtup-park"
                     );
 
@@ -817,27 +1426,202 @@ impl Router {
         Some(())
     }
 
+    /// Saves sessions of connected persistent clients to disk. Sessions are saved
+    /// the same way as on disconnection
+    fn sync_sessions(&mut self) {
+        let interval = Duration::from_secs(self.config.session_sync_secs);
+        self.session_sync_deadline = Some(Instant::now() + interval);
+
+        for (id, connection) in self.connections.iter() {
+            if connection.clean || connection.session_expiry_interval == Some(0) {
+                continue;
+            }
+
+            let tracker = self.scheduler.trackers[id].clone();
+            let parked = self.datalog.parked(id);
+            let outgoing = &self.obufs[id];
+            let tracker = saved_tracker(tracker, parked, outgoing);
+            self.graveyard.persist(
+                tracker,
+                outgoing.pubrels(),
+                connection.subscriptions.clone(),
+                connection.meter.clone(),
+                connection.session_expiry_interval,
+            );
+        }
+    }
+
+    /// Publishes broker statistics as retained messages under `$SYS/broker`
+    fn publish_sys(&mut self) {
+        let interval = Duration::from_secs(self.config.sys_interval_secs);
+        self.sys_deadline = Some(Instant::now() + interval);
+
+        let subscriptions: usize = self
+            .connections
+            .iter()
+            .map(|(_, connection)| connection.subscriptions.len())
+            .sum();
+
+        let logs = match serde_json::to_string(&self.datalog.meters()) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to serialize log meters. Error = {:?}", e);
+                return;
+            }
+        };
+
+        let metrics = &self.router_metrics;
+        let stats = [
+            ("uptime", self.started.elapsed().as_secs().to_string()),
+            ("clients/connected", self.connections.len().to_string()),
+            ("subscriptions/count", subscriptions.to_string()),
+            ("clients/refused", metrics.refused_connections.to_string()),
+            (
+                "publish/messages/received",
+                metrics.total_publishes.to_string(),
+            ),
+            (
+                "publish/messages/failed",
+                metrics.failed_publishes.to_string(),
+            ),
+            ("logs", logs),
+        ];
+
+        for (name, payload) in stats {
+            let topic = format!("$SYS/broker/{}", name);
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: true,
+                topic: topic.clone().into(),
+                pkid: 0,
+                payload: payload.into(),
+            };
+
+            // Retained copy is replayed to new subscribers
+            let mut data = PublishData::new(publish, None);
+            self.datalog
+                .insert_to_retained_publishes(data.clone(), topic.clone());
+            data.publish.retain = false;
+
+            for filter_idx in self.datalog.matches(&topic).unwrap_or_default() {
+                let datalog = self.datalog.native.get_mut(filter_idx).unwrap();
+                datalog.append(data.clone(), &mut self.notifications);
+            }
+        }
+
+        // Prepare all the consumers which are waiting for new data
+        while let Some((id, request)) = self.notifications.pop_front() {
+            self.scheduler.track(id, request);
+            self.scheduler.reschedule(id, ScheduleReason::FreshData);
+        }
+    }
+
+    /// Publishes the will of a disconnecting connection or schedules it for its
+    /// delay interval. Will is published when the session ends if that's earlier
     pub fn handle_last_will(&mut self, id: ConnectionId, client_id: String) {
-        let connection = self.connections.get_mut(id).unwrap();
-        let will = match connection.last_will.take() {
-            Some(v) => v,
+        // Wills are publishes of the client, so acl applies to them as well
+        let denied = match &self.connections[id].last_will {
+            Some(will) => !self.can_publish(id, &will.topic),
             None => return,
         };
 
-        let publish = Publish {
-            dup: false,
-            qos: will.qos,
-            retain: will.retain,
-            topic: will.topic,
+        let connection = self.connections.get_mut(id).unwrap();
+        let will = connection.last_will.take().unwrap();
+        let properties = connection.last_will_properties.take();
+        if denied {
+            warn!(
+                "{:15.15}[E] {:20} topic = {:?}",
+                client_id, "will-acl-denied", will.topic
+            );
+            self.router_metrics.failed_publishes += 1;
+            return;
+        }
+
+        let delay = properties
+            .as_ref()
+            .and_then(|p| p.delay_interval)
+            .unwrap_or(0);
+        let delay = match connection.session_expiry_interval {
+            _ if connection.clean => 0,
+            Some(expiry) => delay.min(expiry),
+            None => delay,
+        };
+
+        let will = DelayedWill {
+            deadline: Instant::now() + Duration::from_secs(delay as u64),
+            will,
+            properties,
+            tenant_prefix: connection.tenant_prefix.clone(),
+            dynamic_filters: connection.dynamic_filters,
+        };
+
+        if delay == 0 {
+            self.publish_will(&client_id, will);
+            return;
+        }
+
+        info!(
+            "{:15.15}[I] {:20} delay = {}s",
+            client_id, "will-delayed", delay
+        );
+        self.will_timers.insert(will.deadline, client_id.clone());
+        self.delayed_wills.insert(client_id, will);
+    }
+
+    /// Publishes delayed wills whose delay interval has passed
+    fn publish_delayed_wills(&mut self) {
+        let now = Instant::now();
+        for client_id in self.will_timers.expire(now) {
+            // Timers of cancelled wills are left in the wheel. So are the timers of
+            // wills which were delayed again by a later disconnection
+            match self.delayed_wills.get(&client_id) {
+                Some(will) if will.deadline <= now => (),
+                _ => continue,
+            }
+
+            let will = self.delayed_wills.remove(&client_id).unwrap();
+            self.publish_will(&client_id, will);
+        }
+    }
+
+    fn publish_will(&mut self, client_id: &str, will: DelayedWill) {
+        let DelayedWill {
+            will,
+            properties,
+            tenant_prefix,
+            dynamic_filters,
+            ..
+        } = will;
+
+        let publish = Publish {
+            dup: false,
+            qos: will.qos,
+            retain: will.retain,
+            topic: will.topic,
             pkid: 0,
             payload: will.message,
         };
-        match append_to_commitlog(
-            id,
+
+        let properties = properties.map(|p| PublishProperties {
+            payload_format_indicator: p.payload_format_indicator,
+            message_expiry_interval: p.message_expiry_interval,
+            topic_alias: None,
+            response_topic: p.response_topic,
+            correlation_data: p.correlation_data,
+            user_properties: p.user_properties,
+            subscription_identifiers: Vec::new(),
+            content_type: p.content_type,
+        });
+
+        let publish = PublishData::new(publish, properties);
+        match append(
+            client_id,
+            tenant_prefix.as_deref(),
+            dynamic_filters,
             publish,
             &mut self.datalog,
             &mut self.notifications,
-            &mut self.connections,
         ) {
             Ok(_offset) => {
                 // Prepare all the consumers which are waiting for new data
@@ -861,15 +1645,40 @@ impl Router {
 
 fn append_to_commitlog(
     id: ConnectionId,
-    mut publish: Publish,
+    data: PublishData,
     datalog: &mut DataLog,
     notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
     connections: &mut Slab<Connection>,
 ) -> Result<Offset, RouterError> {
-    let topic = std::str::from_utf8(&publish.topic)?;
+    let connection = &connections[id];
+    append(
+        &connection.client_id,
+        connection.tenant_prefix.as_deref(),
+        connection.dynamic_filters,
+        data,
+        datalog,
+        notifications,
+    )
+}
+
+/// Appends a publish of `client_id` to the commitlogs of matching filters
+fn append(
+    client_id: &str,
+    tenant_prefix: Option<&str>,
+    dynamic_filters: bool,
+    mut data: PublishData,
+    datalog: &mut DataLog,
+    notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+) -> Result<Offset, RouterError> {
+    let topic = std::str::from_utf8(&data.publish.topic)?;
+
+    // $SYS topics are published only by the broker
+    if topic.starts_with("$SYS") {
+        return Err(RouterError::ReservedTopic(topic.to_owned()));
+    }
 
     // Ensure that only clients associated with a tenant can publish to tenant's topic
-    if let Some(tenant_prefix) = &connections[id].tenant_prefix {
+    if let Some(tenant_prefix) = tenant_prefix {
         if !topic.starts_with(tenant_prefix) {
             return Err(RouterError::BadTenant(
                 tenant_prefix.to_owned(),
@@ -878,21 +1687,22 @@ fn append_to_commitlog(
         }
     }
 
-    if publish.payload.is_empty() {
+    data.origin = Some(client_id.into());
+    if data.publish.payload.is_empty() {
         datalog.remove_from_retained_publishes(topic.to_owned());
-    } else if publish.retain {
-        datalog.insert_to_retained_publishes(publish.clone(), topic.to_owned());
+    } else if data.publish.retain {
+        datalog.insert_to_retained_publishes(data.clone(), topic.to_owned());
     }
 
-    publish.retain = false;
-    let pkid = publish.pkid;
+    data.publish.retain = false;
+    let pkid = data.publish.pkid;
 
     let filter_idxs = datalog.matches(topic);
 
     // Create a dynamic filter if dynamic_filters are enabled for this connection
     let filter_idxs = match filter_idxs {
         Some(v) => v,
-        None if connections[id].dynamic_filters => {
+        None if dynamic_filters => {
             let mut filter_idxs = vec![];
             let (idx, _cursor) = datalog.next_native_offset(topic);
             filter_idxs.push(idx);
@@ -904,22 +1714,15 @@ fn append_to_commitlog(
     let mut o = (0, 0);
     for filter_idx in filter_idxs {
         let datalog = datalog.native.get_mut(filter_idx).unwrap();
-        let (offset, filter) = datalog.append(publish.clone(), notifications);
+        let (offset, filter) = datalog.append(data.clone(), notifications);
         debug!(
             "{:15.15}[I] {:20} append = {}[{}, {}), pkid = {}",
-            // map client id from connection id
-            connections[id].client_id,
-            "publish",
-            filter,
-            offset.0,
-            offset.1,
-            pkid
+            client_id, "publish", filter, offset.0, offset.1, pkid
         );
 
         o = offset;
     }
 
-    // error!("{:15.15}[E] {:20} topic = {}", connections[id].client_id, "no-filter", topic);
     Ok(o)
 }
 
@@ -985,7 +1788,7 @@ fn forward_device_data(
         request.cursor.1
     );
 
-    let inflight_slots = if request.qos == 1 {
+    let inflight_slots = if request.qos > 0 {
         let len = outgoing.free_slots();
         if len == 0 {
             return ConsumeStatus::InflightFull;
@@ -1028,8 +1831,12 @@ fn forward_device_data(
 
     let qos = request.qos;
     let filter_idx = request.filter_idx;
+    let nolocal = request.nolocal;
+    let preserve_retain = request.preserve_retain;
+    let received = std::mem::take(&mut request.received);
     request.read_count += publishes.len();
     request.cursor = next;
+    request.received = received.iter().filter(|c| **c >= next).copied().collect();
     // println!("{:?} {:?} {}", start, next, request.read_count);
 
     if publishes.is_empty() {
@@ -1047,14 +1854,17 @@ fn forward_device_data(
         publishes.len()
     );
 
-    let forwards = publishes.into_iter().map(|mut publish| {
-        publish.qos = protocol::qos(qos).unwrap();
-        Forward {
-            cursor: next,
-            size: 0,
-            publish,
-        }
-    });
+    // Offsets are consecutive in the commitlog. Inflight publishes keep their own
+    // cursor so that persistent sessions resume from the oldest unacked one
+    let now = now_millis();
+    let client_id = outgoing.client_id.clone();
+    let forwards = publishes
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| ((start.0, start.1 + i as u64), data))
+        .filter(|(cursor, _)| !received.contains(cursor))
+        .filter(|(_, data)| !nolocal || data.origin.as_deref() != Some(client_id.as_str()))
+        .filter_map(|(cursor, data)| forward(data, qos, preserve_retain, cursor, now));
 
     let (len, inflight) = outgoing.push_forwards(forwards, qos, filter_idx);
 
@@ -1080,6 +1890,222 @@ fn forward_device_data(
     }
 }
 
+/// Sweeps datalog from the cursor of a shared subscription group on behalf of
+/// member `id` and spreads the publishes across members of the group. Members
+/// which are out of buffer or inflight space are skipped
+fn forward_shared_data(
+    id: ConnectionId,
+    request: &mut DataRequest,
+    shared_groups: &mut HashMap<Filter, SharedGroup>,
+    datalog: &DataLog,
+    obufs: &mut Slab<Outgoing>,
+) -> ConsumeStatus {
+    let group = shared_groups.get_mut(request.subscription()).unwrap();
+    let max_read_len = datalog.config.max_read_len as usize;
+    let mut slots: Vec<Slot> = group
+        .members()
+        .iter()
+        .map(|member| {
+            let outgoing = &obufs[member.id];
+            let (buffer_count, inflight_count) = outgoing.counts();
+            let capacity = if buffer_count >= MAX_CHANNEL_CAPACITY - 1 {
+                0
+            } else if member.qos > 0 {
+                outgoing.free_slots()
+            } else {
+                max_read_len
+            };
+
+            let load = buffer_count + inflight_count;
+            Slot { capacity, load }
+        })
+        .collect();
+
+    // Members with space left always include `id` unless its own buffer or
+    // inflight slots are full
+    let capacity = slots.iter().map(|s| s.capacity).sum::<usize>();
+    if capacity == 0 {
+        let outgoing = &mut obufs[id];
+        if outgoing.counts().0 >= MAX_CHANNEL_CAPACITY - 1 {
+            outgoing.push_notification(Notification::Unschedule);
+            outgoing.handle.try_send(()).ok();
+            return ConsumeStatus::BufferFull;
+        }
+
+        return ConsumeStatus::InflightFull;
+    }
+
+    // Members might have read past the cursor of this request
+    request.cursor = group.cursor;
+    let read_len = capacity.min(max_read_len) as u64;
+    let (next, publishes) = match datalog.native_readv(request.filter_idx, request.cursor, read_len)
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to read from commitlog. Error = {:?}", e);
+            return ConsumeStatus::FilterCaughtup;
+        }
+    };
+
+    let (start, mut next, mut caughtup) = match next {
+        Position::Next { start, end } => (start, end, false),
+        Position::Done { start, end } => (start, end, true),
+    };
+
+    request.read_count += publishes.len();
+
+    if publishes.is_empty() {
+        request.cursor = next;
+        group.cursor = next;
+        return ConsumeStatus::FilterCaughtup;
+    }
+
+    debug!(
+        "{:15.15}[O] {:20} cursor = {}[{}, {}) count = {}",
+        obufs[id].client_id,
+        "shared-proxy",
+        request.subscription(),
+        next.0,
+        next.1,
+        publishes.len()
+    );
+
+    // Offsets are consecutive in the commitlog. Every publish carries its own cursor,
+    // same as in `forward_device_data`. Publishes left over when members run out
+    // of space are read again in the next sweep
+    let now = now_millis();
+    let mut forwards: Vec<Vec<_>> = slots.iter().map(|_| Vec::new()).collect();
+    for (i, data) in publishes.into_iter().enumerate() {
+        let cursor = (start.0, start.1 + i as u64);
+        if data.remaining(now) == Some(0) {
+            continue;
+        }
+
+        let member = match group.pick(&mut slots) {
+            Some(member) => member,
+            None => {
+                next = cursor;
+                caughtup = false;
+                break;
+            }
+        };
+
+        let Member {
+            qos,
+            preserve_retain,
+            ..
+        } = group.members()[member];
+        forwards[member].extend(forward(data, qos, preserve_retain, cursor, now));
+    }
+
+    request.cursor = next;
+    group.cursor = next;
+
+    for (member, forwards) in group.members().iter().zip(forwards) {
+        if forwards.is_empty() {
+            continue;
+        }
+
+        let outgoing = &mut obufs[member.id];
+        outgoing.push_forwards(forwards.into_iter(), member.qos, request.filter_idx);
+        outgoing.handle.try_send(()).ok();
+    }
+
+    let outgoing = &mut obufs[id];
+    if outgoing.counts().0 >= MAX_CHANNEL_CAPACITY - 1 {
+        outgoing.push_notification(Notification::Unschedule);
+        outgoing.handle.try_send(()).ok();
+        return ConsumeStatus::BufferFull;
+    }
+
+    if caughtup {
+        ConsumeStatus::FilterCaughtup
+    } else {
+        ConsumeStatus::PartialRead
+    }
+}
+
+/// Forward of a publish read from commitlog. Expired publishes are dropped and
+/// the rest carry their properties and the time they have left before expiring
+fn forward(
+    data: PublishData,
+    qos: u8,
+    preserve_retain: bool,
+    cursor: Offset,
+    now: u64,
+) -> Option<(Forward, Option<PublishProperties>)> {
+    let remaining = data.remaining(now);
+    if remaining == Some(0) {
+        return None;
+    }
+
+    let mut publish = data.publish;
+    publish.qos = protocol::qos(qos).unwrap();
+    publish.retain |= preserve_retain && data.retain;
+    let forward = Forward {
+        cursor,
+        size: 0,
+        publish,
+    };
+
+    let mut properties = data.properties;
+    if let Some(interval) = remaining {
+        let properties = properties.get_or_insert_with(Default::default);
+        properties.message_expiry_interval = Some(interval);
+    }
+
+    Some((forward, properties))
+}
+
+/// Data requests of a persistent session to save. Requests parked in the datalog
+/// are added back and cursors resume from the oldest unacked publish on
+/// reconnection. Cursor of shared subscriptions is owned by the group
+fn saved_tracker(mut tracker: Tracker, parked: Vec<DataRequest>, outgoing: &Outgoing) -> Tracker {
+    parked
+        .into_iter()
+        .for_each(|r| tracker.register_data_request(r));
+
+    let unacked = outgoing.unacked_cursors();
+    let mut received = outgoing.received_cursors();
+    for request in tracker.data_requests.iter_mut() {
+        if request.share.is_some() {
+            continue;
+        }
+
+        if let Some(cursor) = unacked.get(&request.filter_idx) {
+            request.cursor = *cursor;
+        }
+
+        // QoS 2 publishes after the oldest unacked one which were already received
+        // are skipped when the session resumes. Their PubRels are resent
+        if let Some(cursors) = received.remove(&request.filter_idx) {
+            request.received.extend(cursors);
+        }
+
+        let cursor = request.cursor;
+        request.received.retain(|received| *received >= cursor);
+        request.received.sort_unstable();
+        request.received.dedup();
+    }
+
+    tracker
+}
+
+/// Removes connection `id` from the group of shared subscription. Groups are
+/// dropped with their last member
+fn leave_shared_group(
+    shared_groups: &mut HashMap<Filter, SharedGroup>,
+    id: ConnectionId,
+    subscription: &Filter,
+) {
+    if let Some(group) = shared_groups.get_mut(subscription) {
+        group.leave(id);
+        if group.is_empty() {
+            shared_groups.remove(subscription);
+        }
+    }
+}
+
 fn retrieve_shadow(datalog: &mut DataLog, outgoing: &mut Outgoing, shadow: ShadowRequest) {
     if let Some(reply) = datalog.shadow(&shadow.filter) {
         let publish = reply;
@@ -1157,34 +2183,734 @@ fn retrieve_metrics(id: ConnectionId, router: &mut Router, metrics: MetricsReque
             let metrics = router.scheduler.readyqueue.clone();
             MetricsReply::ReadyQueue(metrics)
         }
+        MetricsRequest::Prometheus => MetricsReply::Prometheus(prometheus_metrics(router)),
     };
 
     let connection = router.connections.get_mut(id).unwrap();
     connection.metrics.try_send(message).ok();
 }
 
+/// Reason code and reason string of the disconnect sent to clients
+/// whose publishes can't be appended
+fn disconnect_reason(e: &RouterError) -> (DisconnectReasonCode, String) {
+    let code = match e {
+        RouterError::NonUtf8Topic(_)
+        | RouterError::BadTenant(..)
+        | RouterError::ReservedTopic(_) => DisconnectReasonCode::TopicNameInvalid,
+        RouterError::NoMatchingFilters(_) => DisconnectReasonCode::ImplementationSpecificError,
+        _ => DisconnectReasonCode::UnspecifiedError,
+    };
+
+    (code, e.to_string())
+}
+
+/// Joins failures of a subscribe or unsubscribe into the reason string of its ack
+fn reason_string(failures: Vec<String>) -> Option<String> {
+    match failures.is_empty() {
+        true => None,
+        false => Some(failures.join(", ")),
+    }
+}
+
+fn retained_publish(topic: &str, data: &PublishData, now: u64, payload: bool) -> RetainedPublish {
+    let publish = &data.publish;
+    RetainedPublish {
+        topic: topic.to_owned(),
+        qos: publish.qos as u8,
+        size: publish.payload.len(),
+        expiry_interval: data.remaining(now),
+        payload: payload.then(|| String::from_utf8_lossy(&publish.payload).into_owned()),
+    }
+}
+
+fn prometheus_metrics(router: &Router) -> String {
+    let mut encoder = Encoder::new();
+    let metrics = &router.router_metrics;
+    let subscriptions: usize = router
+        .connections
+        .iter()
+        .map(|(_, connection)| connection.subscriptions.len())
+        .sum();
+
+    encoder.gauge(
+        "rumqttd_uptime_seconds",
+        "Seconds since the router started",
+        router.started.elapsed().as_secs(),
+    );
+    encoder.gauge(
+        "rumqttd_connections",
+        "Connected clients",
+        router.connections.len(),
+    );
+    encoder.gauge(
+        "rumqttd_subscriptions",
+        "Subscriptions of connected clients",
+        subscriptions,
+    );
+    encoder.counter(
+        "rumqttd_connections_refused_total",
+        "Connections refused because of max connections",
+        metrics.refused_connections,
+    );
+    encoder.counter(
+        "rumqttd_publishes_total",
+        "Publishes received from clients",
+        metrics.total_publishes,
+    );
+    encoder.counter(
+        "rumqttd_publish_failures_total",
+        "Publishes which were denied or failed to append",
+        metrics.failed_publishes,
+    );
+    encoder.gauge(
+        "rumqttd_ready_queue_length",
+        "Connections scheduled to receive data",
+        router.scheduler.readyqueue.len(),
+    );
+    encoder.histogram(
+        "rumqttd_router_loop_duration_seconds",
+        "Time taken by iterations of the router loop",
+        &router.loop_latency,
+    );
+
+    // Sorted by client id for a stable output
+    let mut connections: Vec<_> = router
+        .connections
+        .iter()
+        .map(|(id, connection)| (connection, &router.ibufs[id], &router.obufs[id]))
+        .collect();
+    connections.sort_by(|a, b| a.0.client_id.cmp(&b.0.client_id));
+
+    type ConnectionValue = fn(&Connection, &Incoming, &Outgoing) -> usize;
+    let families: [(&str, &str, &str, ConnectionValue); 7] = [
+        (
+            "rumqttd_connection_incoming_publishes_total",
+            "counter",
+            "Publishes received from the client",
+            |_, incoming, _| incoming.meter.publish_count,
+        ),
+        (
+            "rumqttd_connection_incoming_bytes_total",
+            "counter",
+            "Size of publishes received from the client in bytes",
+            |_, incoming, _| incoming.meter.total_size,
+        ),
+        (
+            "rumqttd_connection_throttled_publishes_total",
+            "counter",
+            "Publishes of the client rejected or held back for being over quota",
+            |connection, _, _| connection.meter.throttled_count(),
+        ),
+        (
+            "rumqttd_connection_outgoing_publishes_total",
+            "counter",
+            "Publishes forwarded to the client",
+            |_, _, outgoing| outgoing.meter.publish_count,
+        ),
+        (
+            "rumqttd_connection_inflight",
+            "gauge",
+            "Publishes forwarded to the client and waiting for acks",
+            |_, _, outgoing| outgoing.counts().1,
+        ),
+        (
+            "rumqttd_connection_buffered",
+            "gauge",
+            "Notifications waiting to be written to the client",
+            |_, _, outgoing| outgoing.counts().0,
+        ),
+        (
+            "rumqttd_connection_subscriptions",
+            "gauge",
+            "Subscriptions of the client",
+            |connection, _, _| connection.subscriptions.len(),
+        ),
+    ];
+
+    for (name, kind, help, value) in families {
+        encoder.family(name, kind, help);
+        for (connection, incoming, outgoing) in connections.iter() {
+            let labels = [("client_id", connection.client_id.as_str())];
+            encoder.sample(name, &labels, value(connection, incoming, outgoing));
+        }
+    }
+
+    // Commitlogs sorted by filter
+    let mut meters: Vec<_> = router.datalog.meters().into_iter().collect();
+    meters.sort_by(|a, b| a.0.cmp(b.0));
+
+    type LogValue = fn(&SubscriptionMeter) -> usize;
+    let families: [(&str, &str, &str, LogValue); 4] = [
+        (
+            "rumqttd_log_appends_total",
+            "counter",
+            "Publishes appended to the commitlog of the filter",
+            |meter| meter.count,
+        ),
+        (
+            "rumqttd_log_bytes_total",
+            "counter",
+            "Size of publishes appended to the commitlog of the filter in bytes",
+            |meter| meter.total_size,
+        ),
+        (
+            "rumqttd_log_segments",
+            "gauge",
+            "Segments of the commitlog in memory",
+            |meter| {
+                (meter
+                    .head_and_tail_id
+                    .1
+                    .saturating_sub(meter.head_and_tail_id.0)
+                    + 1) as usize
+            },
+        ),
+        (
+            "rumqttd_log_disk_segments",
+            "gauge",
+            "Segments of the commitlog on disk",
+            |meter| meter.disk_segments,
+        ),
+    ];
+
+    for (name, kind, help, value) in families {
+        encoder.family(name, kind, help);
+        for (filter, meter) in meters.iter() {
+            encoder.sample(name, &[("filter", filter.as_str())], value(meter));
+        }
+    }
+
+    let name = "rumqttd_subscription_connections";
+    encoder.family(name, "gauge", "Connections subscribed to the filter");
+    for (filter, _) in meters.iter() {
+        let count = router.subscription_map.get(*filter).map_or(0, HashSet::len);
+        encoder.sample(name, &[("filter", filter.as_str())], count);
+    }
+
+    encoder.finish()
+}
+
 fn validate_subscription(
     connection: &mut Connection,
     filter: &protocol::Filter,
 ) -> Result<(), RouterError> {
+    // Shared subscriptions are validated on the filter they share
+    let shared = shared::split(&filter.path);
+    let path = shared.map_or(filter.path.as_str(), |(_, f)| f);
+
+    // Publishes of a member can go to any member of the group
+    if shared.is_some() && filter.nolocal {
+        return Err(RouterError::SharedNoLocal(filter.path.to_owned()));
+    }
+
     // Ensure that only client devices of the tenant can
     if let Some(tenant_prefix) = &connection.tenant_prefix {
-        if !filter.path.starts_with(tenant_prefix) {
+        if !path.starts_with(tenant_prefix) {
             return Err(RouterError::InvalidFilterPrefix(filter.path.to_owned()));
         }
     }
 
-    if filter.qos == QoS::ExactlyOnce {
-        return Err(RouterError::UnsupportedQoS(filter.qos));
-    }
-
-    if filter.path.starts_with("test") || filter.path.starts_with('$') {
+    // $SYS is the only '$' prefix clients can subscribe to. Access to it
+    // is controlled with acl
+    if path.starts_with("test") || (path.starts_with('$') && !sys_filter(path)) {
         return Err(RouterError::InvalidFilterPrefix(filter.path.to_owned()));
     }
 
     Ok(())
 }
 
+/// Checks if `filter` is on broker statistics
+fn sys_filter(filter: &str) -> bool {
+    filter == "$SYS" || filter.starts_with("$SYS/")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Router;
+    use crate::link::local::{Link, LinkError, LinkRx, LinkSettings, LinkTx};
+    use crate::protocol::{
+        ConnectReturnCode, DisconnectReasonCode, Filter, LastWill, LastWillProperties, Packet,
+        PubAck, PubAckReason, PubRec, PubRecReason, Publish, QoS, RetainForwardRule, Subscribe,
+        SubscribeReasonCode, UnsubAckReason, Unsubscribe,
+    };
+    use crate::router::{Ack, Disconnection, Event, MetricsReply, MetricsRequest, Notification};
+    use crate::{AclAction, AclPermission, AclRule, ConnectionId, RouterConfig};
+    use flume::Sender;
+    use std::time::{Duration, Instant};
+
+    fn config() -> RouterConfig {
+        RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            max_connections: 10,
+            ..Default::default()
+        }
+    }
+
+    fn router(config: RouterConfig) -> Sender<(ConnectionId, Event)> {
+        let (_router, router_tx) = Router::new(0, config).spawn();
+        router_tx
+    }
+
+    /// Pushes a packet from the client to the router
+    fn send(tx: &LinkTx, router_tx: &Sender<(ConnectionId, Event)>, packet: Packet) {
+        tx.buffer().push_back(packet);
+        router_tx
+            .send((tx.connection_id, Event::DeviceData))
+            .unwrap();
+    }
+
+    /// Next notification to the client, skipping wakeups without notifications
+    fn next(rx: &mut LinkRx) -> Notification {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(notification) = rx.recv_deadline(deadline).unwrap() {
+                return notification;
+            }
+        }
+    }
+
+    fn subscribe(pkid: u16, qos: QoS, paths: &[&str]) -> Packet {
+        let filters = paths
+            .iter()
+            .map(|path| Filter {
+                path: path.to_string(),
+                qos,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+            })
+            .collect();
+
+        Packet::Subscribe(Subscribe { pkid, filters }, None)
+    }
+
+    fn publish(topic: &str, payload: &str) -> Packet {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: topic.to_owned().into(),
+            pkid: 0,
+            payload: payload.to_owned().into(),
+        };
+
+        Packet::Publish(publish, None)
+    }
+
+    /// Next publish forwarded to the client
+    fn next_publish(rx: &mut LinkRx) -> Publish {
+        loop {
+            match next(rx) {
+                Notification::Forward(forward) => return forward.publish,
+                Notification::DeviceAck(_) => continue,
+                v => panic!("{:?}", v),
+            }
+        }
+    }
+
+    /// Topic of the next publish forwarded to the client
+    fn next_topic(rx: &mut LinkRx) -> String {
+        let publish = next_publish(rx);
+        String::from_utf8(publish.topic.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn takeover_disconnects_existing_connection_and_moves_session() {
+        let router_tx = router(config());
+        let (tx, mut watcher, _) =
+            Link::new("watcher", router_tx.clone(), Default::default()).unwrap();
+        send(&tx, &router_tx, subscribe(1, QoS::AtLeastOnce, &["will/+"]));
+        assert!(matches!(next(&mut watcher), Notification::DeviceAck(_)));
+
+        // Will is delayed, so that only the end of the session publishes it right away
+        let device = |clean: bool| {
+            let settings = LinkSettings {
+                clean,
+                last_will: Some(LastWill {
+                    topic: "will/device".into(),
+                    message: "gone".into(),
+                    qos: QoS::AtMostOnce,
+                    retain: false,
+                }),
+                last_will_properties: Some(LastWillProperties {
+                    delay_interval: Some(60),
+                    payload_format_indicator: None,
+                    message_expiry_interval: None,
+                    content_type: None,
+                    response_topic: None,
+                    correlation_data: None,
+                    user_properties: vec![],
+                }),
+                ..Default::default()
+            };
+
+            let (tx, rx, connack) = Link::new("device", router_tx.clone(), settings).unwrap();
+            match connack {
+                Notification::DeviceAck(Ack::ConnAck(_, connack)) => {
+                    (tx, rx, connack.session_present)
+                }
+                v => panic!("{:?}", v),
+            }
+        };
+
+        let (tx, mut old, session_present) = device(false);
+        assert!(!session_present);
+        send(
+            &tx,
+            &router_tx,
+            subscribe(1, QoS::AtLeastOnce, &["hello/world"]),
+        );
+        assert!(matches!(next(&mut old), Notification::DeviceAck(_)));
+
+        // Session carries on with the new connection. Will isn't published
+        let (tx, mut new, session_present) = device(false);
+        assert!(session_present);
+        match next(&mut old) {
+            Notification::Disconnect(DisconnectReasonCode::SessionTakenOver) => (),
+            v => panic!("{:?}", v),
+        }
+
+        send(&tx, &router_tx, publish("will/marker", "hello"));
+        assert_eq!(next_topic(&mut watcher), "will/marker");
+
+        // Subscription of the session moved to the new connection
+        let (tx, _, _) = Link::new("publisher", router_tx.clone(), Default::default()).unwrap();
+        send(&tx, &router_tx, publish("hello/world", "hello"));
+        assert_eq!(next_topic(&mut new), "hello/world");
+
+        // Clean start ends the session. Will is published without its delay
+        let (_tx, _rx, session_present) = device(true);
+        assert!(!session_present);
+        match next(&mut new) {
+            Notification::Disconnect(DisconnectReasonCode::SessionTakenOver) => (),
+            v => panic!("{:?}", v),
+        }
+
+        assert_eq!(next_topic(&mut watcher), "will/device");
+    }
+
+    #[test]
+    fn received_qos2_publishes_are_not_sent_again_to_resumed_sessions() {
+        let router_tx = router(config());
+        let settings = || LinkSettings {
+            clean: false,
+            ..Default::default()
+        };
+
+        let (tx, mut rx, _) = Link::new("device", router_tx.clone(), settings()).unwrap();
+        send(
+            &tx,
+            &router_tx,
+            subscribe(1, QoS::ExactlyOnce, &["hello/world"]),
+        );
+        assert!(matches!(next(&mut rx), Notification::DeviceAck(_)));
+
+        let (publisher, _, _) =
+            Link::new("publisher", router_tx.clone(), Default::default()).unwrap();
+        for payload in ["1", "2", "3"] {
+            send(&publisher, &router_tx, publish("hello/world", payload));
+        }
+
+        let pkids: Vec<u16> = (0..3).map(|_| next_publish(&mut rx).pkid).collect();
+
+        // Second publish is received. Ones around it aren't acked
+        let pubrec = PubRec {
+            pkid: pkids[1],
+            reason: PubRecReason::Success,
+        };
+        send(&tx, &router_tx, Packet::PubRec(pubrec, None));
+        match next(&mut rx) {
+            Notification::DeviceAck(Ack::PubRel(pubrel)) => assert_eq!(pubrel.pkid, pkids[1]),
+            v => panic!("{:?}", v),
+        }
+
+        let disconnection = Disconnection {
+            id: "device".to_owned(),
+            execute_will: false,
+            pending: vec![],
+        };
+        let event = Event::Disconnect(disconnection);
+        router_tx.send((rx.id(), event)).unwrap();
+
+        // Session resumes from the first publish, but the second one is only released
+        let (_tx, mut rx, connack) = Link::new("device", router_tx, settings()).unwrap();
+        match connack {
+            Notification::DeviceAck(Ack::ConnAck(_, connack)) => assert!(connack.session_present),
+            v => panic!("{:?}", v),
+        }
+
+        match next(&mut rx) {
+            Notification::DeviceAck(Ack::PubRel(pubrel)) => assert_eq!(pubrel.pkid, pkids[1]),
+            v => panic!("{:?}", v),
+        }
+
+        assert_eq!(next_publish(&mut rx).payload, "1");
+        assert_eq!(next_publish(&mut rx).payload, "3");
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(!matches!(rx.recv_deadline(deadline), Ok(Some(_))));
+    }
+
+    #[test]
+    fn connections_over_limit_are_refused() {
+        let router_tx = router(RouterConfig {
+            max_connections: 2,
+            ..config()
+        });
+
+        let _device = Link::new("device-1", router_tx.clone(), Default::default()).unwrap();
+        let (_tx, rx, _) = Link::new("device-2", router_tx.clone(), Default::default()).unwrap();
+
+        // Link learns about the refusal from the ConnAck instead of waiting for one
+        for _ in 0..2 {
+            match Link::new("device-3", router_tx.clone(), Default::default()) {
+                Err(LinkError::Refused(ConnectReturnCode::ServiceUnavailable)) => (),
+                v => panic!("{:?}", v.map(|(_, _, notification)| notification)),
+            }
+        }
+
+        // Taking over a connection doesn't need a new slot
+        let (_tx, _rx, _) = Link::new("device-1", router_tx.clone(), Default::default()).unwrap();
+
+        router_tx
+            .send((rx.id(), Event::Metrics(MetricsRequest::Router)))
+            .unwrap();
+        match rx.metrics() {
+            Some(MetricsReply::Router(metrics)) => assert_eq!(metrics.refused_connections, 2),
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn failed_subscriptions_get_reason_codes_and_strings() {
+        let rule = |filter: &str, permission| AclRule {
+            client_id: None,
+            username: None,
+            filter: filter.to_owned(),
+            action: AclAction::All,
+            permission,
+        };
+
+        let router_tx = router(RouterConfig {
+            acl: Some(vec![
+                rule("secret/#", AclPermission::Deny),
+                rule("#", AclPermission::Allow),
+            ]),
+            ..config()
+        });
+
+        let (tx, mut rx, _) = Link::new("device", router_tx.clone(), Default::default()).unwrap();
+        let mut subscribe = subscribe(1, QoS::AtLeastOnce, &["secret/1", "test/1", "hello/+"]);
+        if let Packet::Subscribe(subscribe, _) = &mut subscribe {
+            let mut shared = subscribe.filters[2].clone();
+            shared.path = "$share/group/hello/+".to_owned();
+            shared.nolocal = true;
+            subscribe.filters.push(shared);
+        }
+
+        send(&tx, &router_tx, subscribe);
+        match next(&mut rx) {
+            Notification::DeviceAck(Ack::SubAckWithProperties(suback, properties)) => {
+                let codes = [
+                    SubscribeReasonCode::NotAuthorized,
+                    SubscribeReasonCode::TopicFilterInvalid,
+                    SubscribeReasonCode::QoS1,
+                    SubscribeReasonCode::TopicFilterInvalid,
+                ];
+                assert_eq!(suback.return_codes, codes);
+
+                let reason = properties.reason_string.unwrap();
+                assert!(reason.contains("secret/1"), "{}", reason);
+                assert!(reason.contains("test/1"), "{}", reason);
+                assert!(reason.contains("$share/group/hello/+"), "{}", reason);
+            }
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn unsubscribes_without_subscription_get_reason_strings() {
+        let router_tx = router(config());
+        let (tx, mut rx, _) = Link::new("device", router_tx.clone(), Default::default()).unwrap();
+        send(
+            &tx,
+            &router_tx,
+            subscribe(1, QoS::AtLeastOnce, &["hello/+"]),
+        );
+        assert!(matches!(next(&mut rx), Notification::DeviceAck(_)));
+
+        let unsubscribe = Unsubscribe {
+            pkid: 2,
+            filters: vec!["hello/+".to_owned(), "hello/world".to_owned()],
+        };
+        send(&tx, &router_tx, Packet::Unsubscribe(unsubscribe.clone()));
+        match next(&mut rx) {
+            Notification::DeviceAck(Ack::UnsubAckWithProperties(unsuback, properties)) => {
+                assert_eq!(unsuback.pkid, 2);
+                let reasons = [
+                    UnsubAckReason::Success,
+                    UnsubAckReason::NoSubscriptionExisted,
+                ];
+                assert_eq!(unsuback.reasons, reasons);
+                let reason = properties.reason_string.unwrap();
+                assert_eq!(reason, "No subscription to hello/world");
+            }
+            v => panic!("{:?}", v),
+        }
+
+        // Unsubscribe is acked once per packet even when nothing is unsubscribed
+        send(&tx, &router_tx, Packet::Unsubscribe(unsubscribe));
+        match next(&mut rx) {
+            Notification::DeviceAck(Ack::UnsubAckWithProperties(unsuback, _)) => {
+                let reasons = [UnsubAckReason::NoSubscriptionExisted; 2];
+                assert_eq!(unsuback.reasons, reasons);
+            }
+            v => panic!("{:?}", v),
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert!(!matches!(rx.recv_deadline(deadline), Ok(Some(_))));
+    }
+
+    #[test]
+    fn protocol_errors_disconnect_with_reason_strings() {
+        let router_tx = router(config());
+        let (tx, mut rx, _) = Link::new("device", router_tx.clone(), Default::default()).unwrap();
+        let puback = PubAck {
+            pkid: 5,
+            reason: PubAckReason::Success,
+        };
+
+        send(&tx, &router_tx, Packet::PubAck(puback, None));
+        match next(&mut rx) {
+            Notification::DisconnectWithProperties(code, properties) => {
+                assert_eq!(code, DisconnectReasonCode::ProtocolError);
+                let reason = properties.reason_string.unwrap();
+                assert_eq!(reason, "Unsolicited ack for pkid 5");
+            }
+            v => panic!("{:?}", v),
+        }
+
+        // Connection was removed along with the disconnect. A new connection with its
+        // client id reuses its slot instead of taking it over
+        let (_tx, _rx, connack) = Link::new("device", router_tx, Default::default()).unwrap();
+        match connack {
+            Notification::DeviceAck(Ack::ConnAck(id, _)) => assert_eq!(id, rx.id()),
+            v => panic!("{:?}", v),
+        }
+    }
+
+    #[test]
+    fn sys_subscriptions_are_not_authorized_without_allow_rule() {
+        let router_tx = router(config());
+        let (tx, mut rx, _) = Link::new("device", router_tx.clone(), Default::default()).unwrap();
+        send(
+            &tx,
+            &router_tx,
+            subscribe(1, QoS::AtLeastOnce, &["$SYS/#", "hello/+"]),
+        );
+        match next(&mut rx) {
+            Notification::DeviceAck(Ack::SubAckWithProperties(suback, properties)) => {
+                let codes = [
+                    SubscribeReasonCode::NotAuthorized,
+                    SubscribeReasonCode::QoS1,
+                ];
+                assert_eq!(suback.return_codes, codes);
+                assert!(properties.reason_string.unwrap().contains("$SYS/#"));
+            }
+            v => panic!("{:?}", v),
+        }
+
+        // Rules which cover everything don't cover $SYS
+        let rule = |username: &str, filter: &str| AclRule {
+            client_id: None,
+            username: Some(username.to_owned()),
+            filter: filter.to_owned(),
+            action: AclAction::Subscribe,
+            permission: AclPermission::Allow,
+        };
+
+        let router_tx = router(RouterConfig {
+            acl: Some(vec![rule("admin", "$SYS/#"), rule("device", "#")]),
+            ..config()
+        });
+
+        for (username, code) in [
+            ("device", SubscribeReasonCode::NotAuthorized),
+            ("admin", SubscribeReasonCode::QoS1),
+        ] {
+            let settings = LinkSettings {
+                username: Some(username.to_owned()),
+                ..Default::default()
+            };
+
+            let (tx, mut rx, _) = Link::new(username, router_tx.clone(), settings).unwrap();
+            send(
+                &tx,
+                &router_tx,
+                subscribe(1, QoS::AtLeastOnce, &["$SYS/broker/+"]),
+            );
+            match next(&mut rx) {
+                Notification::DeviceAck(Ack::SubAck(suback))
+                | Notification::DeviceAck(Ack::SubAckWithProperties(suback, _)) => {
+                    assert_eq!(suback.return_codes, [code])
+                }
+                v => panic!("{:?}", v),
+            }
+        }
+    }
+
+    #[test]
+    fn broker_statistics_are_published_under_sys() {
+        let router_tx = router(RouterConfig {
+            sys_interval_secs: 1,
+            ..config()
+        });
+
+        let settings = LinkSettings {
+            local: true,
+            ..Default::default()
+        };
+
+        let (tx, mut rx, _) = Link::new("monitor", router_tx.clone(), settings).unwrap();
+        send(
+            &tx,
+            &router_tx,
+            subscribe(1, QoS::AtLeastOnce, &["$SYS/broker/#"]),
+        );
+        assert!(matches!(
+            next(&mut rx),
+            Notification::DeviceAck(Ack::SubAck(_))
+        ));
+
+        // Statistics published before the connection are retained. Later ones count it
+        let mut topics = Vec::new();
+        loop {
+            let publish = match next(&mut rx) {
+                Notification::Forward(forward) => forward.publish,
+                Notification::DeviceAck(_) => continue,
+                v => panic!("{:?}", v),
+            };
+
+            let topic = String::from_utf8(publish.topic.to_vec()).unwrap();
+            if topic == "$SYS/broker/clients/connected" && publish.payload == "1" {
+                break;
+            }
+
+            topics.push(topic);
+        }
+
+        for name in ["uptime", "subscriptions/count", "clients/refused", "logs"] {
+            assert!(
+                topics.contains(&format!("$SYS/broker/{}", name)),
+                "{:?}",
+                topics
+            );
+        }
+    }
+}
+
 // #[cfg(test)]
 // #[allow(non_snake_case)]
 // mod test {
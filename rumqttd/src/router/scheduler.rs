@@ -199,7 +199,7 @@ impl Tracker {
     pub fn unregister_data_request(&mut self, filter: Filter) {
         let mut idxs = Vec::<usize>::new();
         for (i, data_req) in self.data_requests.iter().enumerate() {
-            if data_req.filter == filter {
+            if *data_req.subscription() == filter {
                 idxs.push(i);
             }
         }
@@ -223,7 +223,7 @@ impl Scheduler {
         let no_duplicates = tracker
             .get_data_requests()
             .iter()
-            .all(move |x| uniq.insert(x.filter_idx));
+            .all(move |x| uniq.insert((x.filter_idx, &x.share)));
 
         if !no_duplicates {
             dbg!(&tracker.data_requests);
@@ -0,0 +1,169 @@
+use crate::{ConnectionId, Offset, SharedStrategy};
+
+/// Splits shared subscription `$share/<group>/<filter>` into group and filter.
+/// Returns None for regular filters and malformed shared subscriptions
+pub fn split(subscription: &str) -> Option<(&str, &str)> {
+    let subscription = subscription.strip_prefix("$share/")?;
+    let (group, filter) = subscription.split_once('/')?;
+    if group.is_empty() || group.contains(['+', '#']) || filter.is_empty() {
+        return None;
+    }
+
+    Some((group, filter))
+}
+
+/// Space left in a member of the group in the current sweep
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    /// Number of publishes the member can take
+    pub capacity: usize,
+    /// Publishes which are buffered or inflight in the member
+    pub load: usize,
+}
+
+/// Member connection of a group and the options of its subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Member {
+    pub id: ConnectionId,
+    pub qos: u8,
+    /// Retain As Published option of the subscription
+    pub preserve_retain: bool,
+}
+
+/// Connections subscribed to the same shared subscription. Group reads the
+/// commitlog of the filter with a single cursor and every publish is
+/// delivered to only one of the members
+#[derive(Debug)]
+pub struct SharedGroup {
+    /// Next offset to read from the commitlog of the filter
+    pub cursor: Offset,
+    members: Vec<Member>,
+    /// Member which is next in line for round robin
+    next: usize,
+    strategy: SharedStrategy,
+}
+
+impl SharedGroup {
+    pub fn new(cursor: Offset, strategy: SharedStrategy) -> SharedGroup {
+        SharedGroup {
+            cursor,
+            members: Vec::new(),
+            next: 0,
+            strategy,
+        }
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Adds a member. Subscribing again updates the options of a member
+    pub fn join(&mut self, member: Member) {
+        match self.members.iter_mut().find(|m| m.id == member.id) {
+            Some(m) => *m = member,
+            None => self.members.push(member),
+        }
+    }
+
+    pub fn leave(&mut self, id: ConnectionId) {
+        self.members.retain(|member| member.id != id);
+    }
+
+    /// Picks the member for next publish among the members with capacity
+    /// left. `slots` are indexed like `members` and are updated with the pick
+    pub fn pick(&mut self, slots: &mut [Slot]) -> Option<usize> {
+        let count = slots.len();
+        let index = match self.strategy {
+            SharedStrategy::RoundRobin => {
+                let index = (0..count)
+                    .map(|i| (self.next + i) % count)
+                    .find(|i| slots[*i].capacity > 0)?;
+
+                self.next = (index + 1) % count;
+                index
+            }
+            SharedStrategy::LeastLoaded => (0..count)
+                .filter(|i| slots[*i].capacity > 0)
+                .min_by_key(|i| slots[*i].load)?,
+        };
+
+        slots[index].capacity -= 1;
+        slots[index].load += 1;
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split, Member, SharedGroup, Slot};
+    use crate::SharedStrategy;
+
+    #[test]
+    fn shared_subscriptions_are_split_into_group_and_filter() {
+        assert_eq!(split("$share/g1/hello/+"), Some(("g1", "hello/+")));
+        assert_eq!(split("$share/g1/#"), Some(("g1", "#")));
+        assert_eq!(split("hello/world"), None);
+        assert_eq!(split("$share//hello"), None);
+        assert_eq!(split("$share/g1"), None);
+        assert_eq!(split("$share/g1/"), None);
+        assert_eq!(split("$share/g+/hello"), None);
+        assert_eq!(split("$SYS/hello"), None);
+    }
+
+    #[test]
+    fn round_robin_skips_members_without_capacity() {
+        let mut group = SharedGroup::new((0, 0), SharedStrategy::RoundRobin);
+        let slot = |capacity| Slot { capacity, load: 0 };
+        let mut slots = [slot(10), slot(1), slot(10)];
+
+        let picks: Vec<_> = (0..6).map_while(|_| group.pick(&mut slots)).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 2, 0]);
+
+        let mut slots = [slot(0), slot(0), slot(0)];
+        assert_eq!(group.pick(&mut slots), None);
+    }
+
+    #[test]
+    fn least_loaded_member_is_picked() {
+        let mut group = SharedGroup::new((0, 0), SharedStrategy::LeastLoaded);
+        let mut slots = [
+            Slot {
+                capacity: 10,
+                load: 5,
+            },
+            Slot {
+                capacity: 0,
+                load: 0,
+            },
+            Slot {
+                capacity: 10,
+                load: 3,
+            },
+        ];
+
+        let picks: Vec<_> = (0..5).map_while(|_| group.pick(&mut slots)).collect();
+        assert_eq!(picks, [2, 2, 0, 2, 0]);
+    }
+
+    #[test]
+    fn joining_again_updates_subscription_options() {
+        let mut group = SharedGroup::new((0, 0), SharedStrategy::RoundRobin);
+        let member = |id, qos, preserve_retain| Member {
+            id,
+            qos,
+            preserve_retain,
+        };
+
+        group.join(member(1, 0, false));
+        group.join(member(2, 1, false));
+        group.join(member(1, 2, true));
+        assert_eq!(group.members(), [member(1, 2, true), member(2, 1, false)]);
+
+        group.leave(1);
+        assert_eq!(group.members(), [member(2, 1, false)]);
+    }
+}
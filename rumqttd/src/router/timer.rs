@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+/// Hashed timer wheel. Timers are bucketed into slots by the tick of their
+/// deadline and slots are walked as time passes. Timers which are more than a
+/// turn of the wheel away stay in their slot until the turn of their tick
+#[derive(Debug)]
+pub struct TimerWheel<T> {
+    /// Time of tick 0
+    start: Instant,
+    /// Duration of a tick
+    resolution: Duration,
+    /// Timers and their ticks by tick modulo number of slots
+    slots: Vec<Vec<(u64, T)>>,
+    /// Next tick to walk
+    current: u64,
+    /// Number of timers in the wheel
+    len: usize,
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(slots: usize, resolution: Duration) -> TimerWheel<T> {
+        TimerWheel {
+            start: Instant::now(),
+            resolution,
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            current: 0,
+            len: 0,
+        }
+    }
+
+    /// Schedules `timer` to expire at `deadline`. Timers never expire early,
+    /// they expire within a tick after their deadline
+    pub fn insert(&mut self, deadline: Instant, timer: T) {
+        let elapsed = deadline.saturating_duration_since(self.start).as_nanos();
+        let resolution = self.resolution.as_nanos();
+        let tick = (elapsed.div_ceil(resolution) as u64).max(self.current);
+
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((tick, timer));
+        self.len += 1;
+    }
+
+    /// Removes and returns timers which are due by `now`
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let elapsed = now.saturating_duration_since(self.start).as_nanos();
+        let now_tick = (elapsed / self.resolution.as_nanos()) as u64;
+
+        let mut expired = Vec::new();
+        if now_tick < self.current {
+            return expired;
+        }
+
+        // A full turn visits every slot. Ticks which weren't walked for longer
+        // than that don't have to be walked one by one
+        let turn = self.slots.len() as u64;
+        let walk = (now_tick - self.current + 1).min(turn);
+        for tick in self.current..self.current + walk {
+            if self.len == 0 {
+                break;
+            }
+
+            let slot = &mut self.slots[(tick % turn) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= now_tick {
+                    expired.push(slot.swap_remove(i).1);
+                    self.len -= 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        self.current = now_tick + 1;
+        expired
+    }
+
+    /// Time of the next tick to walk. None when there are no timers
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let ticks = u32::try_from(self.current).unwrap_or(u32::MAX);
+        Some(self.start + self.resolution * ticks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimerWheel;
+    use std::time::Duration;
+
+    #[test]
+    fn timers_expire_after_their_deadline() {
+        let mut wheel = TimerWheel::new(4, Duration::from_secs(1));
+        let start = wheel.start;
+        wheel.insert(start + Duration::from_millis(1500), "a");
+        wheel.insert(start + Duration::from_secs(3), "b");
+        wheel.insert(start + Duration::from_secs(10), "c");
+
+        assert!(wheel.expire(start + Duration::from_millis(1900)).is_empty());
+        assert_eq!(wheel.expire(start + Duration::from_secs(2)), vec!["a"]);
+        assert_eq!(wheel.expire(start + Duration::from_secs(3)), vec!["b"]);
+
+        // Same slot as tick 2 in the previous turns
+        assert!(wheel.expire(start + Duration::from_secs(6)).is_empty());
+        assert_eq!(wheel.next_deadline(), Some(start + Duration::from_secs(7)));
+
+        assert_eq!(wheel.expire(start + Duration::from_secs(60)), vec!["c"]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn timers_in_the_past_expire_on_next_walk() {
+        let mut wheel = TimerWheel::new(4, Duration::from_secs(1));
+        let start = wheel.start;
+        wheel.expire(start + Duration::from_secs(5));
+
+        wheel.insert(start + Duration::from_secs(1), "a");
+        assert_eq!(wheel.next_deadline(), Some(start + Duration::from_secs(6)));
+        assert_eq!(wheel.expire(start + Duration::from_secs(6)), vec!["a"]);
+    }
+}
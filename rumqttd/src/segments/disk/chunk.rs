@@ -0,0 +1,110 @@
+use super::index::Index;
+use super::segment::Segment;
+use crate::segments::SegmentPosition;
+use bytes::Bytes;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A segment which was moved to disk. Consists of an index file and a segment
+/// file, both named after the id of the segment in the commitlog
+pub(super) struct Chunk {
+    index: Index,
+    segment: Segment,
+}
+
+impl Chunk {
+    /// Writes data of a segment to disk. `version` is the format of the data
+    pub(super) fn create(
+        dir: &Path,
+        id: u64,
+        version: u32,
+        absolute_offset: u64,
+        data: &[Bytes],
+    ) -> io::Result<Chunk> {
+        let (index_path, segment_path) = paths(dir, id);
+        let lens: Vec<u64> = data.iter().map(|d| d.len() as u64).collect();
+        let segment = Segment::create(&segment_path, data)?;
+        let index = Index::create(&index_path, version, absolute_offset, &lens)?;
+        Ok(Chunk { index, segment })
+    }
+
+    /// Opens a chunk which was written by a previous run. Fails if its data
+    /// isn't of the format `version`
+    pub(super) fn open(dir: &Path, id: u64, version: u32) -> io::Result<Chunk> {
+        let (index_path, segment_path) = paths(dir, id);
+        let index = Index::open(&index_path, version)?;
+        let segment = Segment::open(&segment_path)?;
+
+        // Last entry of the index should end exactly at the end of segment file
+        if index.entries() > 0 {
+            let (position, len) = index.readv(index.entries() - 1, 1)?[0];
+            if position + len != segment.size() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("index of chunk {} doesn't match its segment", id),
+                ));
+            }
+        }
+
+        Ok(Chunk { index, segment })
+    }
+
+    /// Deletes files of the chunk
+    pub(super) fn remove(dir: &Path, id: u64) -> io::Result<()> {
+        let (index_path, segment_path) = paths(dir, id);
+        fs::remove_file(index_path)?;
+        fs::remove_file(segment_path)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub(super) fn absolute_offset(&self) -> u64 {
+        self.index.absolute_offset()
+    }
+
+    #[inline]
+    pub(super) fn next_offset(&self) -> u64 {
+        self.index.absolute_offset() + self.index.entries()
+    }
+
+    /// Same as `Segment::readv` of memory segments, but returns serialized data
+    pub(super) fn readv(
+        &self,
+        absolute_index: u64,
+        len: u64,
+        out: &mut Vec<Bytes>,
+    ) -> io::Result<SegmentPosition> {
+        // this substraction can never overflow as checking of offset happens at
+        // `CommitLog::readv`.
+        let idx = absolute_index - self.absolute_offset();
+        let entries = self.index.entries();
+        if idx >= entries {
+            return Ok(SegmentPosition::Done(self.next_offset()));
+        }
+
+        let limit = (idx + len).min(entries);
+        let positions = self.index.readv(idx, limit - idx)?;
+
+        // Entries are contiguous in the segment file. Read all of them at once
+        let start = positions[0].0;
+        let (last_position, last_len) = positions[positions.len() - 1];
+        let mut data = self.segment.read(start, last_position + last_len - start)?;
+        for (_, len) in positions {
+            out.push(data.split_to(len as usize));
+        }
+
+        if limit == entries {
+            Ok(SegmentPosition::Done(self.next_offset()))
+        } else {
+            Ok(SegmentPosition::Next(self.absolute_offset() + limit))
+        }
+    }
+}
+
+/// Returns paths of the index and segment files of chunk `id`
+pub(super) fn paths(dir: &Path, id: u64) -> (PathBuf, PathBuf) {
+    let index = dir.join(format!("{:020}.index", id));
+    let segment = dir.join(format!("{:020}.segment", id));
+    (index, segment)
+}
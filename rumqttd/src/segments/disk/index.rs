@@ -0,0 +1,121 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the header which holds the format version of the entries and the
+/// absolute offset of the first entry
+const HEADER_LEN: u64 = 12;
+/// Size of every entry. Each entry is (position in segment file, length)
+const ENTRY_LEN: u64 = 16;
+
+/// Index file of a disk segment. Maps relative offsets of entries to their
+/// position in the segment file
+pub(super) struct Index {
+    file: File,
+    /// Absolute offset of the first entry
+    absolute_offset: u64,
+    /// Number of entries in the index
+    entries: u64,
+}
+
+impl Index {
+    /// Creates a new index file with an entry for every given length. Overwrites
+    /// existing file with the same name
+    pub(super) fn create(
+        path: &Path,
+        version: u32,
+        absolute_offset: u64,
+        lens: &[u64],
+    ) -> io::Result<Index> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut writer = BufWriter::new(&file);
+        writer.write_all(&version.to_be_bytes())?;
+        writer.write_all(&absolute_offset.to_be_bytes())?;
+
+        let mut position: u64 = 0;
+        for len in lens {
+            writer.write_all(&position.to_be_bytes())?;
+            writer.write_all(&len.to_be_bytes())?;
+            position += len;
+        }
+
+        writer.flush()?;
+        drop(writer);
+
+        Ok(Index {
+            file,
+            absolute_offset,
+            entries: lens.len() as u64,
+        })
+    }
+
+    /// Opens an existing index file. Files of another format version, including
+    /// the ones written before versions were, are rejected
+    pub(super) fn open(path: &Path, version: u32) -> io::Result<Index> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let size = file.metadata()?.len();
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+
+        let (found, absolute_offset) = header.split_at(4);
+        let found = u32::from_be_bytes(found.try_into().unwrap());
+        if found != version {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported format version {}, expected {}", found, version),
+            ));
+        }
+
+        let entries = size.saturating_sub(HEADER_LEN) / ENTRY_LEN;
+        if size != HEADER_LEN + entries * ENTRY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid index file size {}", size),
+            ));
+        }
+
+        Ok(Index {
+            file,
+            absolute_offset: u64::from_be_bytes(absolute_offset.try_into().unwrap()),
+            entries,
+        })
+    }
+
+    #[inline]
+    pub(super) fn absolute_offset(&self) -> u64 {
+        self.absolute_offset
+    }
+
+    #[inline]
+    pub(super) fn entries(&self) -> u64 {
+        self.entries
+    }
+
+    /// Reads `len` entries starting at relative offset `idx`. Bounds are checked
+    /// by the caller
+    pub(super) fn readv(&self, idx: u64, len: u64) -> io::Result<Vec<(u64, u64)>> {
+        let mut buf = vec![0; (len * ENTRY_LEN) as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(HEADER_LEN + idx * ENTRY_LEN))?;
+        file.read_exact(&mut buf)?;
+
+        let entries = buf
+            .chunks_exact(ENTRY_LEN as usize)
+            .map(|entry| {
+                let (position, len) = entry.split_at(8);
+                (
+                    u64::from_be_bytes(position.try_into().unwrap()),
+                    u64::from_be_bytes(len.try_into().unwrap()),
+                )
+            })
+            .collect();
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,185 @@
+use super::{SegmentPosition, Storage};
+use bytes::Bytes;
+use log::{error, warn};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+mod chunk;
+mod index;
+mod segment;
+
+use chunk::Chunk;
+
+/// Holds segments of a commitlog which were moved out of memory. Chunks on
+/// disk are contiguous in their ids and end right before the first segment
+/// in memory
+pub(crate) struct DiskHandler {
+    /// Directory which holds the index and segment files
+    dir: PathBuf,
+    /// Id of the first chunk
+    head: u64,
+    /// Format version of the data in chunks
+    version: u32,
+    /// Maximum number of chunks on disk
+    max_segments: usize,
+    chunks: VecDeque<Chunk>,
+}
+
+impl DiskHandler {
+    /// Opens the directory and loads chunks written by previous runs. Chunks
+    /// which are invalid, of another format `version`, or aren't contiguous
+    /// with the latest chunk, are deleted
+    pub(crate) fn new(dir: PathBuf, max_segments: usize, version: u32) -> io::Result<DiskHandler> {
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("index") {
+                continue;
+            }
+
+            match path.file_stem().and_then(|s| s.to_str()).map(str::parse) {
+                Some(Ok(id)) => ids.push(id),
+                _ => warn!("ignoring file with invalid name {:?}", path),
+            }
+        }
+
+        // Walk back from the latest chunk and stop at the first gap
+        ids.sort_unstable();
+        let mut head = 0;
+        let mut chunks: VecDeque<Chunk> = VecDeque::new();
+        let mut broken = false;
+        for id in ids.into_iter().rev() {
+            if !broken && chunks.len() < max_segments {
+                match Chunk::open(&dir, id, version) {
+                    Ok(chunk) => {
+                        let contiguous = match chunks.front() {
+                            Some(next) => {
+                                id + 1 == head && chunk.next_offset() == next.absolute_offset()
+                            }
+                            None => true,
+                        };
+
+                        if contiguous {
+                            chunks.push_front(chunk);
+                            head = id;
+                            continue;
+                        }
+                    }
+                    Err(e) => warn!("invalid chunk {} in {:?}. Error = {:?}", id, dir, e),
+                }
+
+                broken = true;
+            }
+
+            if let Err(e) = Chunk::remove(&dir, id) {
+                warn!(
+                    "failed to remove chunk {} in {:?}. Error = {:?}",
+                    id, dir, e
+                );
+            }
+        }
+
+        Ok(DiskHandler {
+            dir,
+            head,
+            version,
+            max_segments,
+            chunks,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn head(&self) -> u64 {
+        self.head
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Id of the last chunk and the absolute offset which follows it
+    pub(crate) fn next_offset(&self) -> Option<(u64, u64)> {
+        let chunk = self.chunks.back()?;
+        Some((self.head + self.len() as u64 - 1, chunk.next_offset()))
+    }
+
+    /// Absolute offset of the first entry in chunk `id`. Bounds are checked by
+    /// the caller
+    pub(crate) fn absolute_offset(&self, id: u64) -> u64 {
+        self.chunks[(id - self.head) as usize].absolute_offset()
+    }
+
+    /// Writes a segment to disk as chunk `id`. Deletes the oldest chunk if the
+    /// limit on number of chunks is crossed
+    pub(crate) fn push(&mut self, id: u64, absolute_offset: u64, data: &[Bytes]) -> io::Result<()> {
+        let chunk = Chunk::create(&self.dir, id, self.version, absolute_offset, data)?;
+        if self.chunks.is_empty() {
+            self.head = id;
+        }
+
+        self.chunks.push_back(chunk);
+        while self.chunks.len() > self.max_segments {
+            self.remove_head();
+        }
+
+        Ok(())
+    }
+
+    /// Deletes all the chunks. Used when contiguity with memory segments breaks
+    pub(crate) fn clear(&mut self) {
+        while !self.chunks.is_empty() {
+            self.remove_head();
+        }
+    }
+
+    fn remove_head(&mut self) {
+        self.chunks.pop_front();
+        if let Err(e) = Chunk::remove(&self.dir, self.head) {
+            warn!(
+                "failed to remove chunk {} in {:?}. Error = {:?}",
+                self.head, self.dir, e
+            );
+        }
+
+        self.head += 1;
+    }
+
+    /// Reads from chunk `id`. Same semantics as `Segment::readv` of memory
+    /// segments
+    pub(crate) fn readv<T: Storage>(
+        &self,
+        id: u64,
+        absolute_index: u64,
+        len: u64,
+        out: &mut Vec<T>,
+    ) -> io::Result<SegmentPosition> {
+        let chunk = &self.chunks[(id - self.head) as usize];
+        let mut data = Vec::new();
+        let position = chunk.readv(absolute_index, len, &mut data)?;
+        let data: io::Result<Vec<T>> = data.into_iter().map(T::deserialize).collect();
+
+        // Readers skip over the rest of a corrupt chunk
+        match data {
+            Ok(data) => out.extend(data),
+            Err(e) => {
+                error!(
+                    "dropping corrupt chunk {} in {:?}. Error = {:?}",
+                    id, self.dir, e
+                );
+                return Ok(SegmentPosition::Done(chunk.next_offset()));
+            }
+        }
+
+        Ok(position)
+    }
+}
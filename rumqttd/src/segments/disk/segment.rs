@@ -0,0 +1,68 @@
+use bytes::Bytes;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Segment file of a disk segment. Entries are stored back to back, and their
+/// boundaries are only known to the corresponding index
+pub(super) struct Segment {
+    file: File,
+    /// Size of the segment file in bytes
+    size: u64,
+}
+
+impl Segment {
+    /// Creates a new segment file with the given data. Overwrites existing file
+    /// with the same name
+    pub(super) fn create(path: &Path, data: &[Bytes]) -> io::Result<Segment> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut size = 0;
+        let mut writer = BufWriter::new(&file);
+        for d in data {
+            writer.write_all(d)?;
+            size += d.len() as u64;
+        }
+
+        writer.flush()?;
+        drop(writer);
+
+        Ok(Segment { file, size })
+    }
+
+    /// Opens an existing segment file
+    pub(super) fn open(path: &Path) -> io::Result<Segment> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Segment { file, size })
+    }
+
+    #[inline]
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads `len` bytes starting from `position` in the file
+    pub(super) fn read(&self, position: u64, len: u64) -> io::Result<Bytes> {
+        if position + len > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "read of {} bytes at {} beyond segment size {}",
+                    len, position, self.size
+                ),
+            ));
+        }
+
+        let mut buf = vec![0; len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut buf)?;
+        Ok(Bytes::from(buf))
+    }
+}
@@ -1,10 +1,14 @@
-use log::warn;
+use bytes::Bytes;
+use log::{error, warn};
+use std::path::PathBuf;
 use std::usize;
 use std::{collections::VecDeque, io};
 
+mod disk;
 mod segment;
 pub mod utils;
 
+use disk::DiskHandler;
 use segment::{Segment, SegmentPosition};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -14,7 +18,17 @@ pub enum Position {
 }
 
 pub trait Storage {
+    /// Version of the serialized layout. Bump it whenever `serialize` changes,
+    /// segments on disk which were written with another layout are discarded
+    const VERSION: u32;
+
     fn size(&self) -> usize;
+    /// Bytes written to disk when the segment holding this is moved out of memory
+    fn serialize(&self) -> Bytes;
+    /// Reads back serialized bytes. Fails on corrupt or truncated data
+    fn deserialize(bytes: Bytes) -> io::Result<Self>
+    where
+        Self: Sized;
 }
 
 /// There are 3 limits which are enforced:
@@ -60,6 +74,8 @@ pub struct CommitLog<T> {
     max_mem_segments: usize,
     /// Total size of active segment, used for enforcing the contraints.
     segments: VecDeque<Segment<T>>,
+    /// Segments moved out of memory, when disk persistence is enabled.
+    disk_handler: Option<DiskHandler>,
 }
 
 impl<T> CommitLog<T>
@@ -76,7 +92,11 @@ where
     ///
     /// If disk is opened and the limit on disk size is reached, the head file will be deleted from
     /// filesystem as well.
-    pub fn new(max_segment_size: usize, max_mem_segments: usize) -> io::Result<Self> {
+    pub fn new(
+        max_segment_size: usize,
+        max_mem_segments: usize,
+        disk: Option<(PathBuf, usize)>,
+    ) -> io::Result<Self> {
         if max_segment_size < 1024 {
             panic!("given max_segment_size {} bytes < 1KB", max_segment_size);
         }
@@ -85,15 +105,35 @@ where
             panic!("atleast 1 segment needs to exist in memory else what's the point of log");
         }
 
+        let disk_handler = match disk {
+            Some((dir, max_disk_segments)) if max_disk_segments > 0 => {
+                Some(DiskHandler::new(dir, max_disk_segments, T::VERSION)?)
+            }
+            _ => None,
+        };
+
+        // Segments in memory continue from the last segment on disk
+        let (head, tail, absolute_offset) = match &disk_handler {
+            Some(disk) if !disk.is_empty() => {
+                let (id, absolute_offset) = disk.next_offset().unwrap();
+                (disk.head(), id + 1, absolute_offset)
+            }
+            _ => (0, 0, 0),
+        };
+
         let mut segments = VecDeque::with_capacity(max_mem_segments);
-        segments.push_back(Segment::with_capacity(max_segment_size));
+        segments.push_back(Segment::with_capacity_and_offset(
+            max_segment_size,
+            absolute_offset,
+        ));
 
         Ok(Self {
-            head: 0,
-            tail: 0,
+            head,
+            tail,
             max_segment_size,
             max_mem_segments,
             segments,
+            disk_handler,
         })
     }
 
@@ -113,6 +153,28 @@ where
         self.segments.len()
     }
 
+    /// Number of segments on disk
+    #[inline]
+    pub fn disk_segments_count(&self) -> usize {
+        self.disk_handler.as_ref().map_or(0, |disk| disk.len())
+    }
+
+    /// Index of the first segment in memory
+    #[inline]
+    fn memory_head(&self) -> u64 {
+        self.tail + 1 - self.segments.len() as u64
+    }
+
+    /// Absolute offset of the first entry of segment `id`. Bounds are checked
+    /// by the caller
+    fn absolute_offset(&self, id: u64) -> u64 {
+        let memory_head = self.memory_head();
+        match &self.disk_handler {
+            Some(disk) if id < memory_head => disk.absolute_offset(id),
+            _ => self.segments[(id - memory_head) as usize].absolute_offset,
+        }
+    }
+
     /// Size of data in all the segments
     #[allow(dead_code)]
     pub fn size(&self) -> u64 {
@@ -161,8 +223,24 @@ where
         if self.active_segment().size() >= self.max_segment_size as u64 {
             // If active segment is full and segments are full, apply retention policy
             if self.memory_segments_count() >= self.max_mem_segments {
-                self.segments.pop_front();
-                self.head += 1;
+                let id = self.memory_head();
+                let segment = self.segments.pop_front().unwrap();
+                self.head = match &mut self.disk_handler {
+                    Some(disk) => {
+                        let data: Vec<Bytes> = segment.data.iter().map(T::serialize).collect();
+                        match disk.push(id, segment.absolute_offset, &data) {
+                            Ok(()) => disk.head(),
+                            Err(e) => {
+                                // Segments should be contiguous. Older segments on disk are
+                                // unreachable without this one
+                                error!("failed to move segment {} to disk. Error = {:?}", id, e);
+                                disk.clear();
+                                id + 1
+                            }
+                        }
+                    }
+                    None => id + 1,
+                };
             }
 
             // Pushing a new segment into segments and updating tail automatically changes active
@@ -198,7 +276,7 @@ where
         }
 
         if cursor.0 < self.head {
-            let head_absolute_offset = self.segments.front().unwrap().absolute_offset;
+            let head_absolute_offset = self.absolute_offset(self.head);
             warn!(
                 "given index {} less than head {}, jumping to head",
                 cursor.0, head_absolute_offset
@@ -207,18 +285,45 @@ where
             start = cursor;
         }
 
-        let mut idx = (cursor.0 - self.head) as usize;
-        let mut curr_segment = &self.segments[idx];
-
-        if curr_segment.absolute_offset > cursor.1 {
+        let absolute_offset = self.absolute_offset(cursor.0);
+        if absolute_offset > cursor.1 {
             warn!(
                 "offset specified {} if less than actual {}, jumping",
-                cursor.1, curr_segment.absolute_offset
+                cursor.1, absolute_offset
             );
-            start.1 = curr_segment.absolute_offset;
-            cursor.1 = curr_segment.absolute_offset;
+            start.1 = absolute_offset;
+            cursor.1 = absolute_offset;
+        }
+
+        // Segments on disk are always older than the ones in memory. Same logic as reading
+        // from older memory segments below
+        let memory_head = self.memory_head();
+        while cursor.0 < memory_head {
+            // `unwrap` is fine as segments before memory head only exist on disk
+            let disk = self.disk_handler.as_ref().unwrap();
+            match disk.readv(cursor.0, cursor.1, len, out)? {
+                SegmentPosition::Next(offset) => {
+                    return Ok(Position::Next {
+                        start,
+                        end: (cursor.0, offset),
+                    });
+                }
+                SegmentPosition::Done(next_offset) => {
+                    if next_offset >= cursor.1 {
+                        len -= next_offset - cursor.1;
+                    }
+                    cursor = (cursor.0 + 1, next_offset);
+                }
+            }
+
+            if len == 0 {
+                return Ok(Position::Next { start, end: cursor });
+            }
         }
 
+        let mut idx = (cursor.0 - memory_head) as usize;
+        let mut curr_segment = &self.segments[idx];
+
         while cursor.0 < self.tail {
             // `Segment::readv` handles conversion from absolute index to relative
             // index and it returns the absolute offset.
@@ -301,7 +406,7 @@ mod tests {
     #[test]
     fn reading_at_invalid_cursor_returns_none() {
         // 1 as active only
-        let log: CommitLog<Bytes> = CommitLog::new(1024, 1).unwrap();
+        let log: CommitLog<Bytes> = CommitLog::new(1024, 1, None).unwrap();
         let mut out = Vec::new();
 
         assert_eq!(log.head, 0);
@@ -327,7 +432,7 @@ mod tests {
         let max_segment_size = 1024 * 100; // 100K
         let packet_size = 1024;
         // 1 as active 1 as inactive but in mem
-        let mut log: CommitLog<Bytes> = CommitLog::new(max_segment_size, 2).unwrap();
+        let mut log: CommitLog<Bytes> = CommitLog::new(max_segment_size, 2, None).unwrap();
 
         // Fill the active segment
         for i in 0..100 {
@@ -366,7 +471,7 @@ mod tests {
         let max_segment_size = 1024 * 100; // 100K
         let packet_size: u64 = 1024;
         // 1 as active only
-        let mut log = CommitLog::new(max_segment_size, 1).unwrap();
+        let mut log = CommitLog::new(max_segment_size, 1, None).unwrap();
 
         for i in 0..10 {
             log.append(random_payload(i, packet_size));
@@ -495,7 +600,7 @@ mod tests {
         let max_segment_size = 1024 * 100; // 100K
         let packet_size: u64 = 1024;
         // 1 as active, 3 as inactive but in mem
-        let mut log = CommitLog::new(max_segment_size, 4).unwrap();
+        let mut log = CommitLog::new(max_segment_size, 4, None).unwrap();
 
         // Fill active segment
         for i in 0..100 {
@@ -560,7 +665,7 @@ mod tests {
         let max_segment_size = 1024 * 10; // 10K
         let packet_size: u64 = 1024;
         // 1 as active, 4 as inactive but in mem
-        let mut log = CommitLog::new(max_segment_size, 5).unwrap();
+        let mut log = CommitLog::new(max_segment_size, 5, None).unwrap();
 
         // Fill active segment + 3 more memory segments
         for i in 0..40 {
@@ -654,7 +759,7 @@ mod tests {
         let max_segment_size = 1024 * 10; // 10K
         let packet_size: u64 = 1024;
         // 1 as active, 9 as inactive but in mem
-        let mut log = CommitLog::new(max_segment_size, 10).unwrap();
+        let mut log = CommitLog::new(max_segment_size, 10, None).unwrap();
 
         // Fill all 10 in memory segments
         for i in 0..100 {
@@ -689,4 +794,159 @@ mod tests {
             }
         );
     }
+
+    fn disk_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rumqttd-{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn retention_moves_segments_to_disk_and_reads_work() {
+        let max_segment_size = 1024 * 10; // 10K
+        let packet_size: u64 = 1024;
+        let dir = disk_dir("retention-disk");
+        // 1 as active, 1 as inactive in mem and 5 on disk
+        let mut log = CommitLog::new(max_segment_size, 2, Some((dir.clone(), 5))).unwrap();
+
+        // 10 segments. Oldest 3 should be deleted from disk
+        for i in 0..100 {
+            log.append(random_payload(i, packet_size));
+        }
+        assert_eq!(log.head, 3);
+        assert_eq!(log.tail, 9);
+        assert_eq!(log.memory_segments_count(), 2);
+        assert_eq!(log.disk_segments_count(), 5);
+        assert!(!dir.join(format!("{:020}.index", 2)).exists());
+        assert!(dir.join(format!("{:020}.segment", 3)).exists());
+
+        // Cursor before head jumps to the first segment on disk
+        let mut out = Vec::new();
+        let next = log.readv((0, 0), 5, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Next {
+                start: (3, 30),
+                end: (3, 35)
+            }
+        );
+        for (i, o) in out.drain(..).enumerate() {
+            verify(30 + i, packet_size, o);
+        }
+
+        // Read across disk segments into memory segments
+        let next = log.readv((7, 75), 20, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Next {
+                start: (7, 75),
+                end: (9, 95)
+            }
+        );
+        for (i, o) in out.drain(..).enumerate() {
+            verify(75 + i, packet_size, o);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn disk_segments_are_reloaded() {
+        let max_segment_size = 1024 * 10; // 10K
+        let packet_size: u64 = 1024;
+        let dir = disk_dir("reload-disk");
+
+        let mut log = CommitLog::new(max_segment_size, 2, Some((dir.clone(), 10))).unwrap();
+        for i in 0..50 {
+            log.append(random_payload(i, packet_size));
+        }
+        assert_eq!(log.head, 0);
+        assert_eq!(log.tail, 4);
+        drop(log);
+
+        // Segments in memory are lost. New segments continue after the ones on disk
+        let mut log: CommitLog<Bytes> =
+            CommitLog::new(max_segment_size, 2, Some((dir.clone(), 10))).unwrap();
+        assert_eq!(log.head, 0);
+        assert_eq!(log.tail, 3);
+        assert_eq!(log.next_offset(), (3, 30));
+        assert_eq!(log.append(random_payload(30, packet_size)), (3, 31));
+
+        let mut out = Vec::new();
+        let next = log.readv((2, 25), 10, &mut out).unwrap();
+        assert_eq!(
+            next,
+            Done {
+                start: (2, 25),
+                end: (3, 31)
+            }
+        );
+        for (i, o) in out.into_iter().enumerate() {
+            verify(25 + i, packet_size, o);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn disk_segments_of_other_versions_are_discarded() {
+        let dir = disk_dir("version-disk");
+        let mut log = CommitLog::new(1024 * 10, 2, Some((dir.clone(), 10))).unwrap();
+        for i in 0..50 {
+            log.append(random_payload(i, 1024));
+        }
+        drop(log);
+
+        // Index of segments written before layouts were versioned starts with the
+        // absolute offset
+        let path = dir.join(format!("{:020}.index", 1));
+        let index = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &index[4..]).unwrap();
+
+        // Older segments aren't contiguous without the rejected one
+        let log: CommitLog<Bytes> = CommitLog::new(1024 * 10, 2, Some((dir.clone(), 10))).unwrap();
+        assert_eq!(log.disk_segments_count(), 1);
+        assert_eq!(log.head, 2);
+        assert!(!path.exists());
+        assert!(!dir.join(format!("{:020}.index", 0)).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn corrupt_disk_segments_are_skipped() {
+        use crate::protocol::{Publish, QoS};
+        use crate::router::PublishData;
+
+        let dir = disk_dir("corrupt-disk");
+        let mut log = CommitLog::new(1024, 2, Some((dir.clone(), 10))).unwrap();
+        for i in 0..60 {
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: "hello/world".into(),
+                pkid: 0,
+                payload: vec![i; 100].into(),
+            };
+
+            log.append(PublishData::new(publish, None));
+        }
+        assert!(log.disk_segments_count() >= 3);
+
+        // Garbage of the same size keeps the chunk valid until its data is read
+        let path = dir.join(format!("{:020}.segment", 1));
+        let len = std::fs::metadata(&path).unwrap().len() as usize;
+        std::fs::write(&path, vec![0xFF; len]).unwrap();
+
+        let mut out = Vec::new();
+        log.readv((0, 0), 100, &mut out).unwrap();
+        let dropped = log.absolute_offset(2) - log.absolute_offset(1);
+        assert_eq!(out.len() as u64, log.next_offset().1 - dropped);
+        assert_eq!(out[0].publish.payload[0], 0);
+        let first = log.absolute_offset(1) as usize;
+        assert_eq!(out[first].publish.payload[0] as u64, first as u64 + dropped);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
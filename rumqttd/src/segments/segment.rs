@@ -32,6 +32,8 @@ where
             total_size: 0,
         }
     }
+
+    #[allow(dead_code)]
     pub(crate) fn with_capacity(capacity: usize) -> Self {
         Self {
             data: Vec::with_capacity(capacity),
@@ -1,23 +1,146 @@
-use crate::protocol::Publish;
+use crate::protocol::v5::{len_len, publish::properties};
+use crate::protocol::{Error, Publish};
+use crate::router::PublishData;
 use crate::Storage;
-use bytes::Bytes;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::error;
+use std::io;
 
 impl Storage for Bytes {
+    const VERSION: u32 = 1;
+
     fn size(&self) -> usize {
         // For bytes len returns number of bytes in the given `Bytes`
         self.len()
     }
+
+    fn serialize(&self) -> Bytes {
+        self.clone()
+    }
+
+    fn deserialize(bytes: Bytes) -> io::Result<Self> {
+        Ok(bytes)
+    }
 }
 
 impl Storage for Publish {
+    const VERSION: u32 = 1;
+
     fn size(&self) -> usize {
-        4 + self.topic.len() + self.payload.len()
+        5 + self.topic.len() + self.payload.len()
+    }
+
+    fn serialize(&self) -> Bytes {
+        Publish::serialize(self)
+    }
+
+    fn deserialize(bytes: Bytes) -> io::Result<Self> {
+        Publish::deserialize(bytes).map_err(invalid)
+    }
+}
+
+impl Storage for PublishData {
+    /// Expiry, properties, retain flag and origin before the publish
+    const VERSION: u32 = 1;
+
+    fn size(&self) -> usize {
+        let properties = match &self.properties {
+            Some(p) => {
+                let len = properties::len(p);
+                len_len(len) + len
+            }
+            None => 1,
+        };
+
+        let origin = self.origin.as_ref().map_or(0, |o| o.len());
+        8 + properties + 1 + 2 + origin + self.publish.size()
+    }
+
+    /// Expiry, properties as encoded in v5 publishes, retain flag as published,
+    /// origin and then the publish. Publishes which never expire are written
+    /// with 0 as their expiry and publishes without origin with an empty one
+    fn serialize(&self) -> Bytes {
+        let publish = self.publish.serialize();
+        let origin = self.origin.as_deref().unwrap_or_default();
+        let mut o = BytesMut::with_capacity(self.size());
+        o.put_u64(self.expiry.unwrap_or(0));
+
+        // Properties were read from a packet and fit in its length. Properties which
+        // can't be encoded anyway are dropped instead of the publish
+        let mut properties = BytesMut::new();
+        if let Some(p) = &self.properties {
+            if let Err(e) = properties::write(p, &mut properties) {
+                error!("dropping properties of stored publish. Error = {:?}", e);
+                properties.clear();
+            }
+        }
+
+        match properties.is_empty() {
+            true => o.put_u8(0),
+            false => o.extend_from_slice(&properties),
+        }
+
+        o.put_u8(self.retain as u8);
+        o.put_u16(origin.len() as u16);
+        o.extend_from_slice(origin.as_bytes());
+        o.extend_from_slice(&publish);
+        o.freeze()
+    }
+
+    fn deserialize(mut bytes: Bytes) -> io::Result<Self> {
+        if bytes.len() < 8 {
+            return Err(invalid(Error::InsufficientBytes(8 - bytes.len())));
+        }
+
+        let expiry = match bytes.get_u64() {
+            0 => None,
+            expiry => Some(expiry),
+        };
+
+        let properties = properties::read(&mut bytes).map_err(invalid)?;
+        if bytes.len() < 3 {
+            return Err(invalid(Error::InsufficientBytes(3 - bytes.len())));
+        }
+
+        let retain = bytes.get_u8() != 0;
+        let origin_len = bytes.get_u16() as usize;
+        if bytes.len() < origin_len {
+            return Err(invalid(Error::InsufficientBytes(origin_len - bytes.len())));
+        }
+
+        let origin = match origin_len {
+            0 => None,
+            len => Some(String::from_utf8_lossy(&bytes.split_to(len)).into()),
+        };
+
+        let publish = Publish::deserialize(bytes).map_err(invalid)?;
+        Ok(PublishData {
+            publish,
+            properties,
+            expiry,
+            origin,
+            retain,
+        })
     }
 }
 
 impl Storage for Vec<u8> {
+    const VERSION: u32 = 1;
+
     fn size(&self) -> usize {
         // For bytes len returns number of bytes in the given `Bytes`
         self.len()
     }
+
+    fn serialize(&self) -> Bytes {
+        Bytes::from(self.clone())
+    }
+
+    fn deserialize(bytes: Bytes) -> io::Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+fn invalid(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
 }
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::{fs, io};
+
+use crate::protocol::ConnectReturnCode;
+
+/// Reason to reject a connection. Sent back to the client in `ConnAck`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    BadUserNamePassword,
+    NotAuthorized,
+}
+
+impl AuthError {
+    pub(crate) fn code(&self) -> ConnectReturnCode {
+        match self {
+            AuthError::BadUserNamePassword => ConnectReturnCode::BadUserNamePassword,
+            AuthError::NotAuthorized => ConnectReturnCode::NotAuthorized,
+        }
+    }
+}
+
+/// Authenticates new connections before they are registered with the router.
+/// Implemented for closures with the same signature as `authenticate`
+pub trait AuthHandler: Send + Sync {
+    fn authenticate(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+        addr: SocketAddr,
+    ) -> Result<(), AuthError>;
+}
+
+impl<F> AuthHandler for F
+where
+    F: Fn(&str, Option<&str>, Option<&str>, SocketAddr) -> Result<(), AuthError> + Send + Sync,
+{
+    fn authenticate(
+        &self,
+        client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+        addr: SocketAddr,
+    ) -> Result<(), AuthError> {
+        self(client_id, username, password, addr)
+    }
+}
+
+/// Static credentials. Loaded from a file with a `username:password` pair per
+/// line. Empty lines and lines starting with '#' are ignored
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    users: HashMap<String, String>,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> io::Result<Credentials> {
+        let file = fs::read_to_string(path)?;
+        Credentials::parse(&file)
+    }
+
+    fn parse(file: &str) -> io::Result<Credentials> {
+        let mut users = HashMap::new();
+        for (i, line) in file.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(':') {
+                Some((username, password)) => {
+                    users.insert(username.to_owned(), password.to_owned());
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid credentials in line {}", i + 1),
+                    ))
+                }
+            }
+        }
+
+        Ok(Credentials { users })
+    }
+}
+
+impl AuthHandler for Credentials {
+    fn authenticate(
+        &self,
+        _client_id: &str,
+        username: Option<&str>,
+        password: Option<&str>,
+        _addr: SocketAddr,
+    ) -> Result<(), AuthError> {
+        let username = username.ok_or(AuthError::NotAuthorized)?;
+        match (self.users.get(username), password) {
+            (Some(p), Some(password)) if constant_time_eq(p, password) => Ok(()),
+            _ => Err(AuthError::BadUserNamePassword),
+        }
+    }
+}
+
+/// Compares secrets in time which only depends on the length of `expected`
+pub(crate) fn constant_time_eq(expected: &str, given: &str) -> bool {
+    let given = given.as_bytes();
+    let mut diff = expected.len() ^ given.len();
+    for (i, byte) in expected.bytes().enumerate() {
+        diff |= (byte ^ given.get(i).copied().unwrap_or(0)) as usize;
+    }
+
+    diff == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuthError, AuthHandler, Credentials};
+
+    #[test]
+    fn static_credentials_are_verified() {
+        let file = "# users\nalice:secret\n\nbob:pass:word\n";
+        let credentials = Credentials::parse(file).unwrap();
+        let addr = "127.0.0.1:1883".parse().unwrap();
+
+        let auth = |username, password| credentials.authenticate("c", username, password, addr);
+        assert_eq!(auth(Some("alice"), Some("secret")), Ok(()));
+        assert_eq!(auth(Some("bob"), Some("pass:word")), Ok(()));
+        assert_eq!(
+            auth(Some("alice"), Some("wrong")),
+            Err(AuthError::BadUserNamePassword)
+        );
+        assert_eq!(
+            auth(Some("carol"), Some("secret")),
+            Err(AuthError::BadUserNamePassword)
+        );
+        assert_eq!(
+            auth(Some("alice"), Some("secret2")),
+            Err(AuthError::BadUserNamePassword)
+        );
+        assert_eq!(
+            auth(Some("alice"), Some("secre")),
+            Err(AuthError::BadUserNamePassword)
+        );
+        assert_eq!(
+            auth(Some("alice"), None),
+            Err(AuthError::BadUserNamePassword)
+        );
+        assert_eq!(auth(None, None), Err(AuthError::NotAuthorized));
+
+        assert!(Credentials::parse("alice").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-// use crate::link::bridge;
+use crate::link::bridge;
 use crate::link::console::ConsoleLink;
 use crate::link::network::{Network, N};
 use crate::link::remote::{self, RemoteLink};
@@ -11,23 +11,32 @@ use crate::protocol::ws::Ws;
 use crate::protocol::Protocol;
 #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
 use crate::server::tls::{self, TLSAcceptor};
+use crate::server::{AuthHandler, Credentials};
 use crate::ConnectionSettings;
-use flume::{RecvError, SendError, Sender};
+use flume::{Receiver, RecvError, SendError, Sender};
 use log::*;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::error;
+use std::net::SocketAddr;
 use std::sync::Arc;
 #[cfg(feature = "websockets")]
 use websocket_codec::MessageCodec;
 
+use std::io;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use std::{io, thread};
 
-use crate::link::console;
-use crate::link::local::{self, Link, LinkRx, LinkTx};
+use crate::link::console::{self, Accepts};
+use crate::link::local::{self, Link, LinkRx, LinkSettings, LinkTx};
 use crate::router::{Disconnection, Event, Router};
 use crate::{Config, ConnectionId, ServerSettings};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time::error::Elapsed;
-use tokio::{task, time};
+use tokio::{select, task, time};
+
+/// Time servers wait for their connections to close during shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, thiserror::Error)]
 #[error("Acceptor error")]
@@ -49,9 +58,91 @@ pub enum Error {
     Remote(#[from] remote::Error),
 }
 
+/// Loads a new config for reloads
+type ConfigLoader = Box<dyn Fn() -> Result<Config, Box<dyn error::Error + Send + Sync>> + Send>;
+
 pub struct Broker {
     config: Arc<Config>,
     router_tx: Sender<(ConnectionId, Event)>,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
+    handle: BrokerHandle,
+    config_loader: Option<ConfigLoader>,
+    reload_rx: Receiver<()>,
+    /// Running servers by kind (v4, v5, ws) and name. Dropping the
+    /// sender stops the server
+    servers: HashMap<(&'static str, String), Sender<ServerUpdate>>,
+    accepts: Accepts,
+}
+
+/// Handle to reload or shut down a running broker from other threads
+#[derive(Clone)]
+pub struct BrokerHandle {
+    router_tx: Sender<(ConnectionId, Event)>,
+    /// Dropping the sender signals servers, bridges and console to stop
+    shutdown_tx: Arc<Mutex<Option<Sender<()>>>>,
+    shutdown_rx: Receiver<()>,
+    reload_tx: Sender<()>,
+    /// Router, server, bridge and console threads to join on shutdown
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl BrokerHandle {
+    fn new(
+        router_tx: Sender<(ConnectionId, Event)>,
+        router: JoinHandle<()>,
+        reload_tx: Sender<()>,
+    ) -> BrokerHandle {
+        let (shutdown_tx, shutdown_rx) = flume::bounded(1);
+        BrokerHandle {
+            router_tx,
+            shutdown_tx: Arc::new(Mutex::new(Some(shutdown_tx))),
+            shutdown_rx,
+            reload_tx,
+            threads: Arc::new(Mutex::new(vec![router])),
+        }
+    }
+
+    /// Asks the running broker to reload its config with the loader set by
+    /// `Broker::set_config_loader`. Listeners, their connection settings
+    /// and TLS config, credentials and acl are updated without dropping
+    /// existing connections. Connection settings and credentials apply to
+    /// new connections, existing ones keep the settings they were accepted
+    /// with. Acl applies to all the connections right away
+    pub fn reload(&self) {
+        // Full channel means a reload is already pending
+        self.reload_tx.try_send(()).ok();
+    }
+
+    fn spawned(&self, thread: JoinHandle<()>) {
+        self.threads.lock().push(thread);
+    }
+
+    /// Stops accepting new connections, disconnects existing ones with
+    /// `ServerShuttingDown` after flushing their pending acks and waits for
+    /// all the broker threads to stop. Persistent sessions are saved to
+    /// `session_dir` when it is configured
+    pub fn shutdown(&self) {
+        if self.shutdown_tx.lock().take().is_none() {
+            return;
+        }
+
+        info!("{:15.15}[I] {:20}", "", "shutdown");
+        self.router_tx.send((0, Event::Shutdown)).ok();
+
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().drain(..).collect();
+        let current = thread::current().id();
+        for handle in threads {
+            if handle.thread().id() == current {
+                continue;
+            }
+
+            let name = handle.thread().name().unwrap_or_default().to_owned();
+
+            if handle.join().is_err() {
+                error!("{:15.15}[E] {:20} thread = {}", "", "shutdown-panic", name);
+            }
+        }
+    }
 }
 
 impl Broker {
@@ -70,13 +161,33 @@ impl Broker {
                 // Broker::setup_remote_cluster(&mut router, node_id, &mut cluster);
 
                 // Start router first and then cluster in the background
-                let router_tx = router.spawn();
+                let (router_thread, router_tx) = router.spawn();
+                let (reload_tx, reload_rx) = flume::bounded(1);
                 // cluster.spawn();
-                Broker { config, router_tx }
+                Broker {
+                    config,
+                    router_tx: router_tx.clone(),
+                    auth_handler: None,
+                    handle: BrokerHandle::new(router_tx, router_thread, reload_tx),
+                    config_loader: None,
+                    reload_rx,
+                    servers: HashMap::new(),
+                    accepts: Accepts::default(),
+                }
             }
             None => {
-                let router_tx = router.spawn();
-                Broker { config, router_tx }
+                let (router_thread, router_tx) = router.spawn();
+                let (reload_tx, reload_rx) = flume::bounded(1);
+                Broker {
+                    config,
+                    router_tx: router_tx.clone(),
+                    auth_handler: None,
+                    handle: BrokerHandle::new(router_tx, router_thread, reload_tx),
+                    config_loader: None,
+                    reload_rx,
+                    servers: HashMap::new(),
+                    accepts: Accepts::default(),
+                }
             }
         }
     }
@@ -120,114 +231,244 @@ impl Broker {
     //     }
     // }
 
+    /// Sets a handler to authenticate remote connections with. Takes precedence
+    /// over credentials file in the config
+    pub fn set_auth_handler<A: AuthHandler + 'static>(&mut self, auth_handler: A) {
+        self.auth_handler = Some(Arc::new(auth_handler));
+    }
+
+    /// Sets the loader of new configs for `BrokerHandle::reload`. Reloads
+    /// are ignored without a loader
+    pub fn set_config_loader<F>(&mut self, loader: F)
+    where
+        F: Fn() -> Result<Config, Box<dyn error::Error + Send + Sync>> + Send + 'static,
+    {
+        self.config_loader = Some(Box::new(loader));
+    }
+
+    /// Handle to reload or shut down the broker. `start` returns after shutdown
+    pub fn handle(&self) -> BrokerHandle {
+        self.handle.clone()
+    }
+
     pub fn link(&self, client_id: &str) -> Result<(LinkTx, LinkRx), local::LinkError> {
         // Register this connection with the router. Router replies with ack which if ok will
         // start the link. Router can sometimes reject the connection (ex max connection limit)
-        let (link_tx, link_rx, _ack) =
-            Link::new(None, client_id, self.router_tx.clone(), true, None, false)?;
+        let settings = LinkSettings {
+            local: true,
+            ..Default::default()
+        };
+
+        let (link_tx, link_rx, _ack) = Link::new(client_id, self.router_tx.clone(), settings)?;
         Ok((link_tx, link_rx))
     }
 
     pub fn start(&mut self) -> Result<(), Error> {
-        // spawn bridge in a separate thread
-        // if let Some(bridge_config) = self.config.bridge.clone() {
-        //     let router_tx = self.router_tx.clone();
-        //     thread::Builder::new()
-        //         .name("bridge-thread".to_string())
-        //         .spawn(move || {
-        //             let mut runtime = tokio::runtime::Builder::new_current_thread();
-        //             let runtime = runtime.enable_all().build().unwrap();
-        //             let router_tx = router_tx.clone();
-
-        //             runtime.block_on(async move {
-        //                 if let Err(e) = bridge::bridge_launch(bridge_config, router_tx).await {
-        //                     error!("bridge: {:?}", e);
-        //                 };
-        //             });
-        //         })?;
-        // }
+        let config = self.config.clone();
+        let auth_handler = self.auth_handler(&config)?;
 
         // spawn servers in a separate thread
-        for (_, config) in self.config.v4.clone() {
-            let server_thread = thread::Builder::new().name(config.name.clone());
-            let server = Server::new(config, self.router_tx.clone(), V4);
-            server_thread.spawn(move || {
+        self.update_servers("v4", &config.v4, V4, false, &auth_handler)?;
+        self.update_servers("v5", &config.v5, V5, false, &auth_handler)?;
+
+        #[cfg(feature = "websockets")]
+        self.update_servers(
+            "ws",
+            &config.ws,
+            Ws {
+                codec: MessageCodec::server(),
+            },
+            true,
+            &auth_handler,
+        )?;
+
+        // for (_, config) in self.config.shadows.clone() {
+        //     let server_thread = thread::Builder::new().name(config.name.clone());
+        //     let server = Server::new(config, self.router_tx.clone());
+        //     server_thread.spawn(move || {
+        //         let mut runtime = tokio::runtime::Builder::new_current_thread();
+        //         let runtime = runtime.enable_all().build().unwrap();
+
+        //         runtime.block_on(async {
+        //             if let Err(e) = server.start(true).await {
+        //                 error!("Accept loop error: {:?}", e.to_string());
+        //             }
+        //         });
+        //     })?;
+        // }
+
+        // spawn bridges in a separate thread
+        for (_, config) in self.config.bridge.clone() {
+            let bridge_thread = thread::Builder::new().name(config.name.clone());
+            let router_tx = self.router_tx.clone();
+            let shutdown = self.handle.shutdown_rx.clone();
+            let thread = bridge_thread.spawn(move || {
                 let mut runtime = tokio::runtime::Builder::new_current_thread();
                 let runtime = runtime.enable_all().build().unwrap();
 
                 runtime.block_on(async {
-                    if let Err(e) = server.start(false).await {
-                        error!("{:15.15}[I] Remote link error = {:?}", "", e);
+                    let name = config.name.clone();
+                    if let Err(e) = bridge::start(config, router_tx, shutdown).await {
+                        error!("{:15.15}[E] Bridge error = {:?}", name, e);
                     }
                 });
             })?;
+            self.handle.spawned(thread);
         }
 
-        for (_, config) in self.config.v5.clone() {
-            let server_thread = thread::Builder::new().name(config.name.clone());
-            let server = Server::new(config, self.router_tx.clone(), V5);
-            server_thread.spawn(move || {
-                let mut runtime = tokio::runtime::Builder::new_current_thread();
-                let runtime = runtime.enable_all().build().unwrap();
+        let console_link = ConsoleLink::new(
+            self.config.console.clone(),
+            self.router_tx.clone(),
+            self.handle.reload_tx.clone(),
+            self.accepts.clone(),
+        );
 
-                runtime.block_on(async {
-                    if let Err(e) = server.start(false).await {
-                        error!("{:15.15}[I] Remote link error = {:?}", "", e);
-                    }
-                });
-            })?;
+        let console_link = Arc::new(console_link);
+        let shutdown = self.handle.shutdown_rx.clone();
+        let console_thread = thread::Builder::new().name("console".to_owned());
+        let thread = console_thread.spawn(move || console::start(console_link, shutdown))?;
+        self.handle.spawned(thread);
+
+        // Serve reloads until shutdown like the console used to block.
+        // Shutdown receiver errors out when `BrokerHandle::shutdown` drops
+        // the sender
+        loop {
+            let reload = flume::Selector::new()
+                .recv(&self.handle.shutdown_rx, |_| false)
+                .recv(&self.reload_rx, |o| o.is_ok())
+                .wait();
+
+            if !reload {
+                return Ok(());
+            }
+
+            if let Err(e) = self.reload() {
+                error!("{:15.15}[E] {:20} {}", "", "reload-error", e);
+            }
         }
+    }
+
+    /// Applies new config from the config loader. Other settings than
+    /// listeners, credentials and acl need a restart
+    fn reload(&mut self) -> Result<(), Box<dyn error::Error + Send + Sync>> {
+        let loader = match &self.config_loader {
+            Some(loader) => loader,
+            None => {
+                warn!("{:15.15}[W] {:20} no config loader", "", "reload");
+                return Ok(());
+            }
+        };
+
+        let config = Arc::new(loader()?);
+        let auth_handler = self.auth_handler(&config)?;
+        info!("{:15.15}[I] {:20}", "", "reload");
+
+        self.update_servers("v4", &config.v4, V4, false, &auth_handler)?;
+        self.update_servers("v5", &config.v5, V5, false, &auth_handler)?;
 
         #[cfg(feature = "websockets")]
-        for (_, config) in self.config.ws.clone() {
+        self.update_servers(
+            "ws",
+            &config.ws,
+            Ws {
+                codec: MessageCodec::server(),
+            },
+            true,
+            &auth_handler,
+        )?;
+
+        let acl = Event::Acl(config.router.acl.clone());
+        self.router_tx
+            .send((0, acl))
+            .map_err(|_| "router is gone")?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Handler set on the broker, else credentials from the config
+    fn auth_handler(&self, config: &Config) -> io::Result<Option<Arc<dyn AuthHandler>>> {
+        let auth_handler = match (&self.auth_handler, &config.credentials) {
+            (Some(auth_handler), _) => Some(auth_handler.clone()),
+            (None, Some(path)) => {
+                let credentials: Arc<dyn AuthHandler> = Arc::new(Credentials::load(path)?);
+                Some(credentials)
+            }
+            (None, None) => None,
+        };
+
+        Ok(auth_handler)
+    }
+
+    /// Starts servers of `kind` which aren't running, updates running ones
+    /// and stops the ones which aren't in `servers` anymore. Servers which
+    /// stopped on their own (e.g. bind errors) are started again
+    fn update_servers<P: Protocol + Clone + Send + 'static>(
+        &mut self,
+        kind: &'static str,
+        servers: &HashMap<String, ServerSettings>,
+        protocol: P,
+        shadow: bool,
+        auth_handler: &Option<Arc<dyn AuthHandler>>,
+    ) -> io::Result<()> {
+        self.servers
+            .retain(|(k, name), _| *k != kind || servers.contains_key(name));
+
+        for (name, config) in servers {
+            let key = (kind, name.clone());
+            let update = ServerUpdate {
+                config: config.clone(),
+                auth_handler: auth_handler.clone(),
+            };
+
+            let update = match self.servers.get(&key) {
+                Some(tx) => match tx.send(update) {
+                    Ok(()) => continue,
+                    Err(e) => e.into_inner(),
+                },
+                None => update,
+            };
+
             let server_thread = thread::Builder::new().name(config.name.clone());
             let server = Server::new(
-                config,
+                update.config,
                 self.router_tx.clone(),
-                Ws {
-                    codec: MessageCodec::server(),
-                },
+                protocol.clone(),
+                update.auth_handler,
+                self.accepts.clone(),
             );
-            server_thread.spawn(move || {
+            let shutdown = self.handle.shutdown_rx.clone();
+            let (updates_tx, updates) = flume::unbounded();
+            let thread = server_thread.spawn(move || {
                 let mut runtime = tokio::runtime::Builder::new_current_thread();
                 let runtime = runtime.enable_all().build().unwrap();
 
                 runtime.block_on(async {
-                    if let Err(e) = server.start(true).await {
+                    if let Err(e) = server.start(shadow, shutdown, updates).await {
                         error!("{:15.15}[I] Remote link error = {:?}", "", e);
                     }
                 });
             })?;
-        }
 
-        // for (_, config) in self.config.shadows.clone() {
-        //     let server_thread = thread::Builder::new().name(config.name.clone());
-        //     let server = Server::new(config, self.router_tx.clone());
-        //     server_thread.spawn(move || {
-        //         let mut runtime = tokio::runtime::Builder::new_current_thread();
-        //         let runtime = runtime.enable_all().build().unwrap();
-
-        //         runtime.block_on(async {
-        //             if let Err(e) = server.start(true).await {
-        //                 error!("Accept loop error: {:?}", e.to_string());
-        //             }
-        //         });
-        //     })?;
-        // }
-
-        let console_link = ConsoleLink::new(self.config.console.clone(), self.router_tx.clone());
-
-        let console_link = Arc::new(console_link);
-        console::start(console_link);
+            self.handle.spawned(thread);
+            self.servers.insert(key, updates_tx);
+        }
 
         Ok(())
     }
 }
 
+/// New settings and auth handler for a running server
+struct ServerUpdate {
+    config: ServerSettings,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
+}
+
 struct Server<P> {
     config: ServerSettings,
     router_tx: Sender<(ConnectionId, Event)>,
     protocol: P,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
+    accepts: Accepts,
 }
 
 impl<P: Protocol + Clone + Send + 'static> Server<P> {
@@ -235,11 +476,15 @@ impl<P: Protocol + Clone + Send + 'static> Server<P> {
         config: ServerSettings,
         router_tx: Sender<(ConnectionId, Event)>,
         protocol: P,
+        auth_handler: Option<Arc<dyn AuthHandler>>,
+        accepts: Accepts,
     ) -> Server<P> {
         Server {
             config,
             router_tx,
             protocol,
+            auth_handler,
+            accepts,
         }
     }
 
@@ -257,19 +502,67 @@ impl<P: Protocol + Clone + Send + 'static> Server<P> {
         Ok((Box::new(stream), None))
     }
 
-    async fn start(&self, shadow: bool) -> Result<(), Error> {
-        let listener = TcpListener::bind(&self.config.listen).await?;
-        let delay = Duration::from_millis(self.config.next_connection_delay_ms);
+    /// Accepts connections until shutdown or until the sender of `updates`
+    /// is dropped. Connections of a server which is stopped by dropping the
+    /// sender are left alone until shutdown
+    async fn start(
+        mut self,
+        shadow: bool,
+        shutdown: Receiver<()>,
+        updates: Receiver<ServerUpdate>,
+    ) -> Result<(), Error> {
+        let mut listener = TcpListener::bind(&self.config.listen).await?;
+        let mut delay = Duration::from_millis(self.config.next_connection_delay_ms);
         let mut count: usize = 0;
 
-        let config = Arc::new(self.config.connections.clone());
+        // Every connection task holds a clone of the sender. Receiver errors
+        // out after all of them are done
+        let (tasks_tx, tasks_rx) = flume::bounded::<()>(1);
+
+        let mut config = Arc::new(self.config.connections.clone());
         info!(
             "{:15.15}[>] waiting for remote connections > {}",
             self.config.name, self.config.listen
         );
-        loop {
+        let removed = loop {
             // Await new network connection.
-            let (stream, addr) = match listener.accept().await {
+            let accept = select! {
+                accept = listener.accept() => accept,
+                update = updates.recv_async() => {
+                    let mut update = match update {
+                        Ok(update) => update,
+                        Err(_) => break true,
+                    };
+
+                    // Keep the old listener when new address can't be bound
+                    if update.config.listen != self.config.listen {
+                        match TcpListener::bind(&update.config.listen).await {
+                            Ok(l) => listener = l,
+                            Err(e) => {
+                                error!(
+                                    "{:15.15}[E] {:20} listen = {}, error = {:?}",
+                                    self.config.name, "rebind-error", update.config.listen, e
+                                );
+                                update.config.listen = self.config.listen;
+                            }
+                        }
+                    }
+
+                    // Connection settings apply to new connections
+                    self.config = update.config;
+                    self.auth_handler = update.auth_handler;
+                    config = Arc::new(self.config.connections.clone());
+                    delay = Duration::from_millis(self.config.next_connection_delay_ms);
+                    info!(
+                        "{:15.15}[I] {:20} listen = {}",
+                        self.config.name, "reload", self.config.listen
+                    );
+                    continue;
+                }
+                _ = shutdown.recv_async() => break false,
+            };
+
+            let (stream, addr) = match accept {
                 Ok((s, r)) => (s, r),
                 Err(e) => {
                     error!("Unable to accept socket. Error = {:?}", e);
@@ -293,16 +586,59 @@ impl<P: Protocol + Clone + Send + 'static> Server<P> {
             let config = config.clone();
             let router_tx = self.router_tx.clone();
             count += 1;
+            *self
+                .accepts
+                .lock()
+                .entry(self.config.name.clone())
+                .or_default() += 1;
 
             let protocol = self.protocol.clone();
+            let auth_handler = self.auth_handler.clone();
+            let shutdown = shutdown.clone();
+            let tasks_tx = tasks_tx.clone();
             match shadow {
                 #[cfg(feature = "websockets")]
-                true => task::spawn(shadow_connection(config, router_tx, network)),
-                _ => task::spawn(remote(config, tenant_id, router_tx, network, protocol)),
+                true => task::spawn(async move {
+                    shadow_connection(config, router_tx, network, addr, auth_handler).await;
+                    drop(tasks_tx);
+                }),
+                _ => task::spawn(async move {
+                    remote(
+                        config,
+                        tenant_id,
+                        router_tx,
+                        network,
+                        protocol,
+                        addr,
+                        auth_handler,
+                        shutdown,
+                    )
+                    .await;
+                    drop(tasks_tx);
+                }),
             };
 
             time::sleep(delay).await;
+        };
+
+        drop(listener);
+        drop(tasks_tx);
+        info!("{:15.15}[I] {:20}", self.config.name, "stop-accept");
+
+        // Existing connections of a removed server live on until shutdown
+        if removed {
+            select! {
+                _ = tasks_rx.recv_async() => return Ok(()),
+                _ = shutdown.recv_async() => (),
+            }
         }
+
+        // Router disconnects existing connections during shutdown. Runtime
+        // drops the connections which are still around after the timeout
+        time::timeout(SHUTDOWN_TIMEOUT, tasks_rx.recv_async())
+            .await
+            .ok();
+        Ok(())
     }
 }
 
@@ -311,16 +647,34 @@ impl<P: Protocol + Clone + Send + 'static> Server<P> {
 /// waiting for mqtt connect packet. Also this honours connection wait time as per config to prevent
 /// denial of service attacks (rogue clients which only does network connection without sending
 /// mqtt connection packet to make make the server reach its concurrent connection limit)
+#[allow(clippy::too_many_arguments)]
 async fn remote<P: Protocol>(
     config: Arc<ConnectionSettings>,
     tenant_id: Option<String>,
     router_tx: Sender<(ConnectionId, Event)>,
     stream: Box<dyn N>,
     protocol: P,
+    addr: SocketAddr,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
+    shutdown: Receiver<()>,
 ) {
-    let network = Network::new(stream, config.max_payload_size, 100, protocol);
-    // Start the link
-    let mut link = match RemoteLink::new(config, router_tx.clone(), tenant_id, network).await {
+    // Packets are read in batches of up to inflight limit of the connection
+    let batch = config.max_inflight_count as usize;
+    let network = Network::new(stream, config.max_payload_size, batch, protocol);
+    // Start the link. Don't wait for connect packet once broker is shutting down
+    let link = select! {
+        link = RemoteLink::new(
+            config,
+            router_tx.clone(),
+            tenant_id,
+            network,
+            addr,
+            auth_handler,
+        ) => link,
+        _ = shutdown.recv_async() => return,
+    };
+
+    let mut link = match link {
         Ok(l) => l,
         Err(e) => {
             error!("{:15.15}[E] Remote link error = {:?}", "", e);
@@ -341,6 +695,18 @@ async fn remote<P: Protocol>(
             info!("{:15.15}[E] {:20} {:?}", client_id, "router-drop", e);
             return;
         }
+        // Router has already removed the connection
+        Err(remote::Error::Disconnected(reason)) => {
+            info!(
+                "{:15.15}[I] {:20} {:?}",
+                client_id, "router-disconnect", reason
+            );
+            return;
+        }
+        // Client was sent a disconnect packet for its protocol error
+        Err(e @ remote::Error::InvalidTopicAlias(_)) => {
+            error!("{:15.15}[E] Disconnected!! {:?}", client_id, e);
+        }
         // Any other error
         Err(e) => {
             error!("{:15.15}[E] Disconnected!! {:?}", client_id, e);
@@ -364,9 +730,12 @@ async fn shadow_connection(
     config: Arc<ConnectionSettings>,
     router_tx: Sender<(ConnectionId, Event)>,
     stream: Box<dyn N>,
+    addr: SocketAddr,
+    auth_handler: Option<Arc<dyn AuthHandler>>,
 ) {
     // Start the link
-    let mut link = match ShadowLink::new(config, router_tx.clone(), stream).await {
+    let link = ShadowLink::new(config, router_tx.clone(), stream, addr, auth_handler);
+    let mut link = match link.await {
         Ok(l) => l,
         Err(e) => {
             error!("{:15.15}[E] Remote link error = {:?}", "", e);
@@ -399,3 +768,257 @@ async fn shadow_connection(
     let message = (connection_id, disconnect);
     router_tx.send(message).ok();
 }
+
+#[cfg(test)]
+mod test {
+    use super::{remote, Broker};
+    use crate::link::local::Link;
+    use crate::protocol::v5::V5;
+    use crate::router::{Notification, Router};
+    use crate::{AclAction, AclPermission, AclRule, Config, ConsoleSettings};
+    use crate::{ConnectionSettings, RouterConfig, ServerSettings};
+    use parking_lot::Mutex;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    fn connection_settings() -> ConnectionSettings {
+        ConnectionSettings {
+            connection_timeout_ms: 1000,
+            throttle_delay_ms: 0,
+            max_payload_size: 1024,
+            max_inflight_count: 10,
+            max_inflight_size: 1024,
+            dynamic_filters: false,
+            topic_alias_max: 0,
+            server_keep_alive: None,
+            rate_limit: None,
+        }
+    }
+
+    fn config(servers: &[(&str, SocketAddr)]) -> Config {
+        let v4 = servers
+            .iter()
+            .map(|(name, listen)| {
+                let settings = ServerSettings {
+                    name: name.to_string(),
+                    listen: *listen,
+                    tls: None,
+                    next_connection_delay_ms: 0,
+                    connections: connection_settings(),
+                };
+
+                (name.to_string(), settings)
+            })
+            .collect();
+
+        Config {
+            router: RouterConfig {
+                max_segment_size: 1024,
+                max_segment_count: 10,
+                max_read_len: 1024,
+                max_connections: 10,
+                ..Default::default()
+            },
+            v4,
+            console: ConsoleSettings {
+                listen: "127.0.0.1:0".to_owned(),
+                admin_token: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Connects client `id` to `addr` with MQTT 3.1.1 and returns the stream
+    /// along with the code of ConnAck. None when nothing listens on `addr`
+    fn connect(addr: SocketAddr, id: u8, login: Option<(&str, &str)>) -> Option<(TcpStream, u8)> {
+        let mut stream = TcpStream::connect(addr).ok()?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut payload = vec![0, 1, id];
+        let mut flags = 0x02;
+        if let Some((username, password)) = login {
+            flags |= 0xC0;
+            for field in [username, password] {
+                payload.extend([0, field.len() as u8]);
+                payload.extend(field.as_bytes());
+            }
+        }
+
+        let mut packet = vec![0x10, 10 + payload.len() as u8, 0, 4];
+        packet.extend(b"MQTT");
+        packet.extend([4, flags, 0, 60]);
+        packet.extend(payload);
+        stream.write_all(&packet).unwrap();
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack).unwrap();
+        Some((stream, connack[3]))
+    }
+
+    /// Waits until `addr` accepts connections or stops accepting them
+    fn wait_for(addr: SocketAddr, listening: bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TcpStream::connect(addr).is_ok() != listening {
+            assert!(
+                Instant::now() < deadline,
+                "{} listening = {}",
+                addr,
+                !listening
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Subscribes to `filter` and returns the return code of SubAck
+    fn subscribe(stream: &mut TcpStream, filter: &str) -> u8 {
+        let mut packet = vec![0x82, 5 + filter.len() as u8, 0, 1, 0, filter.len() as u8];
+        packet.extend(filter.as_bytes());
+        packet.push(0);
+        stream.write_all(&packet).unwrap();
+
+        let mut suback = [0; 5];
+        stream.read_exact(&mut suback).unwrap();
+        assert_eq!(suback[0], 0x90);
+        suback[4]
+    }
+
+    #[test]
+    fn reload_updates_listeners_credentials_and_acl() {
+        let old = free_addr();
+        let new = free_addr();
+        let next = Arc::new(Mutex::new(config(&[("old", old)])));
+
+        let mut broker = Broker::new(next.lock().clone());
+        let loader = next.clone();
+        broker.set_config_loader(move || Ok(loader.lock().clone()));
+        let handle = broker.handle();
+        let broker = thread::spawn(move || broker.start().unwrap());
+
+        wait_for(old, true);
+        let (mut existing, code) = connect(old, b'1', None).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(subscribe(&mut existing, "secret/1"), 0);
+
+        // Old listener is replaced with a new one which needs credentials
+        let credentials = std::env::temp_dir().join("rumqttd-reload-credentials");
+        std::fs::write(&credentials, "user:pass\n").unwrap();
+        let mut config = config(&[("new", new)]);
+        config.credentials = Some(credentials);
+        let rule = |filter: &str, permission| AclRule {
+            client_id: None,
+            username: None,
+            filter: filter.to_owned(),
+            action: AclAction::Subscribe,
+            permission,
+        };
+
+        config.router.acl = Some(vec![
+            rule("secret/#", AclPermission::Deny),
+            rule("#", AclPermission::Allow),
+        ]);
+
+        *next.lock() = config;
+        handle.reload();
+        wait_for(new, true);
+        wait_for(old, false);
+
+        assert_eq!(connect(new, b'2', None).unwrap().1, 5);
+        assert_eq!(connect(new, b'2', Some(("user", "wrong"))).unwrap().1, 4);
+        assert_eq!(connect(new, b'2', Some(("user", "pass"))).unwrap().1, 0);
+
+        // Existing connection of the removed listener lives on. Acl applies to it
+        assert_eq!(subscribe(&mut existing, "secret/2"), 0x80);
+        assert_eq!(subscribe(&mut existing, "public/1"), 0);
+
+        handle.shutdown();
+        broker.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_connections_are_dropped_after_keep_alive() {
+        let config = RouterConfig {
+            max_segment_size: 1024,
+            max_segment_count: 10,
+            max_read_len: 1024,
+            max_connections: 10,
+            ..Default::default()
+        };
+
+        let (_router, router_tx) = Router::new(0, config).spawn();
+        let (mut tx, mut watcher, _) =
+            Link::new("watcher", router_tx.clone(), Default::default()).unwrap();
+        tx.subscribe("will/+").unwrap();
+
+        let settings = Arc::new(ConnectionSettings {
+            connection_timeout_ms: 1000,
+            throttle_delay_ms: 0,
+            max_payload_size: 1024,
+            max_inflight_count: 10,
+            max_inflight_size: 1024,
+            dynamic_filters: false,
+            topic_alias_max: 0,
+            server_keep_alive: Some(1),
+            rate_limit: None,
+        });
+
+        // MQTT 5 clean start connect of client `a` with a keep alive of 60 seconds
+        // and a will on `will/a`. Nothing is sent after it
+        let connect = [
+            0x10, 29, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x06, 0, 60, 0, 0, 1, b'a', 0, 0, 6, b'w',
+            b'i', b'l', b'l', b'/', b'a', 0, 4, b'g', b'o', b'n', b'e',
+        ];
+
+        let (mut client, server) = duplex(1024);
+        client.write_all(&connect).await.unwrap();
+
+        let start = Instant::now();
+        let (_shutdown_tx, shutdown_rx) = flume::bounded(1);
+        let addr = "127.0.0.1:1883".parse().unwrap();
+        let link = remote(
+            settings,
+            None,
+            router_tx,
+            Box::new(server),
+            V5,
+            addr,
+            None,
+            shutdown_rx,
+        );
+        let link = tokio::spawn(link);
+
+        // ConnAck assigns keep alive of the server. Connection is closed after 1.5 times of it
+        let mut read = Vec::new();
+        client.read_to_end(&mut read).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(read, [0x20, 6, 0, 0, 3, 0x13, 0, 1]);
+        assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+        link.await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match watcher.recv_deadline(deadline).unwrap() {
+                Some(Notification::Forward(forward)) => {
+                    assert_eq!(forward.publish.topic, "will/a");
+                    assert_eq!(forward.publish.payload, "gone");
+                    break;
+                }
+                Some(Notification::DeviceAck(_)) | None => continue,
+                v => panic!("{:?}", v),
+            }
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use tokio::io::{AsyncRead, AsyncWrite};
 
+mod auth;
 mod broker;
 #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
 mod tls;
 
-pub use broker::Broker;
+pub(crate) use auth::constant_time_eq;
+pub use auth::{AuthError, AuthHandler, Credentials};
+pub use broker::{Broker, BrokerHandle};
 
 pub trait IO: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> IO for T {}